@@ -59,6 +59,7 @@ class FieldElementsData:
 @dataclasses.dataclass(frozen=True)
 class JobData(Task):
     cairo_pie_compressed: FieldElementsData
+    deadline: int
 
     def load_task(self) -> "CairoPieTask":
         return CairoPieTask(
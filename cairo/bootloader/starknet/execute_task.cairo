@@ -1,7 +1,7 @@
 from builtin_selection.inner_select_builtins import inner_select_builtins
 from builtin_selection.select_input_builtins import select_input_builtins
 from builtin_selection.validate_builtins import validate_builtins
-from common.builtin_poseidon.poseidon import PoseidonBuiltin, poseidon_hash_many
+from common.builtin_poseidon.poseidon import PoseidonBuiltin, poseidon_hash, poseidon_hash_many
 from common.cairo_builtins import HashBuiltin, EcOpBuiltin
 from common.hash_chain import hash_chain
 from common.bool import TRUE
@@ -113,19 +113,26 @@ func execute_task{builtin_ptrs: BuiltinData*, self_range_check_ptr}(
             use_poseidon=bool(ids.use_poseidon)), 'Computed hash does not match input.'
     %}
 
+    local deadline: felt;
     local public_key: felt;
     local signature_r: felt;
     local signature_s: felt;
     %{
+        ids.deadline = simple_bootloader_input.job.job_data.deadline
         ids.public_key = simple_bootloader_input.job.public_key
         ids.signature_r = simple_bootloader_input.job.signature_r
         ids.signature_s = simple_bootloader_input.job.signature_s
     %}
 
+    // The delegator signs the program hash together with the job deadline.
+    with poseidon_ptr {
+        let (message) = poseidon_hash(x=hash, y=deadline);
+    }
+
     let ec_op_ptr = cast(input_builtin_ptrs.ec_op, EcOpBuiltin*);
     with ec_op_ptr {
         let (res) = check_ecdsa_signature(
-            message=hash, public_key=public_key, signature_r=signature_r, signature_s=signature_s
+            message=message, public_key=public_key, signature_r=signature_r, signature_s=signature_s
         );
         assert res = TRUE;
     }
@@ -0,0 +1,147 @@
+use crate::layout::Layout;
+use cairo_vm::vm::runners::cairo_pie::CairoPie;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use starknet_crypto::FieldElement;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/*
+    Executor Capabilities
+    This object is periodically gossiped by every executor on the networking topic.
+    It describes what the executor can handle: the layouts it runs the bootloader in, its step and memory limits,
+    the prover backend and version, the hash of the bootloader program and how many jobs are already queued.
+    Delegators keep the latest record of every executor and only accept bids from executors that support the job.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutorCapabilities {
+    pub identity: PeerId,
+    pub layouts: Vec<Layout>,
+    pub max_steps: usize,
+    pub max_memory_cells: usize,
+    pub prover_backend: String,
+    pub prover_version: String,
+    pub bootloader_program_hash: FieldElement,
+    pub queue_depth: u64,
+}
+
+pub const CAPABILITY_ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Records not refreshed within a few advertisement intervals belong to executors that went away.
+pub const CAPABILITY_TTL: Duration = Duration::from_secs(90);
+
+impl ExecutorCapabilities {
+    pub fn supports(&self, requirements: &JobRequirements) -> bool {
+        requirements.steps <= self.max_steps
+            && requirements.memory_cells <= self.max_memory_cells
+            && self.layouts.iter().any(|layout| {
+                requirements.builtins.iter().all(|builtin| layout.builtins().contains(&&**builtin))
+            })
+    }
+}
+
+/*
+    Job Requirements
+    What a job needs from an executor, derived from its Cairo PIE.
+    Sent along with the bid solicitation so executors that cannot handle the job do not bid on it.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobRequirements {
+    pub steps: usize,
+    pub memory_cells: usize,
+    pub builtins: Vec<String>,
+}
+
+impl From<&CairoPie> for JobRequirements {
+    fn from(pie: &CairoPie) -> Self {
+        Self {
+            steps: pie.execution_resources.n_steps,
+            memory_cells: pie.memory.0.len(),
+            builtins: pie
+                .metadata
+                .program
+                .builtins
+                .iter()
+                .map(|builtin| builtin.to_str().to_string())
+                .collect(),
+        }
+    }
+}
+
+// Latest capability record of every executor seen on the network.
+#[derive(Debug, Default)]
+pub struct CapabilityStore {
+    records: HashMap<PeerId, (ExecutorCapabilities, Instant)>,
+}
+
+impl CapabilityStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, capabilities: ExecutorCapabilities) {
+        self.records.retain(|_, (_, seen)| seen.elapsed() < CAPABILITY_TTL);
+        self.records.insert(capabilities.identity, (capabilities, Instant::now()));
+    }
+
+    // Forgets an executor that left the network, its bids are no longer accepted.
+    pub fn remove(&mut self, identity: &PeerId) {
+        self.records.remove(identity);
+    }
+
+    pub fn get(&self, identity: &PeerId) -> Option<&ExecutorCapabilities> {
+        self.records
+            .get(identity)
+            .filter(|(_, seen)| seen.elapsed() < CAPABILITY_TTL)
+            .map(|(capabilities, _)| capabilities)
+    }
+
+    pub fn supports(&self, identity: &PeerId, requirements: &JobRequirements) -> bool {
+        self.get(identity).is_some_and(|capabilities| capabilities.supports(requirements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(identity: PeerId) -> ExecutorCapabilities {
+        ExecutorCapabilities {
+            identity,
+            layouts: vec![Layout::RecursiveWithPoseidon],
+            max_steps: 1000,
+            max_memory_cells: 1000,
+            prover_backend: "stone".to_string(),
+            prover_version: "0.1.0".to_string(),
+            bootloader_program_hash: FieldElement::ONE,
+            queue_depth: 0,
+        }
+    }
+
+    fn requirements(builtins: &[&str]) -> JobRequirements {
+        JobRequirements {
+            steps: 100,
+            memory_cells: 100,
+            builtins: builtins.iter().map(|builtin| builtin.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn supported_requirements() {
+        let capabilities = capabilities(PeerId::random());
+        assert!(capabilities.supports(&requirements(&["output", "poseidon"])));
+        assert!(!capabilities.supports(&requirements(&["output", "ecdsa"])));
+        assert!(!capabilities.supports(&JobRequirements { steps: 1001, ..requirements(&[]) }));
+    }
+
+    #[test]
+    fn unknown_executor_is_unsupported() {
+        let mut store = CapabilityStore::new();
+        let identity = PeerId::random();
+        assert!(!store.supports(&identity, &requirements(&["output"])));
+        store.insert(capabilities(identity));
+        assert!(store.supports(&identity, &requirements(&["output"])));
+    }
+}
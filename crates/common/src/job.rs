@@ -1,21 +1,29 @@
 use crate::hash;
-use cairo_vm::vm::runners::cairo_pie::CairoPie;
+use cairo_vm::vm::runners::{
+    cairo_pie::{
+        CairoPie, CairoPieAdditionalData, CairoPieMemory, CairoPieMetadata, CairoPieVersion,
+    },
+    cairo_runner::ExecutionResources,
+};
 use libp2p::{kad, PeerId};
 use serde::{Deserialize, Serialize};
 use starknet::signers::{SigningKey, VerifyingKey};
-use starknet_crypto::{poseidon_hash_many, FieldElement, Signature};
+use starknet_crypto::{poseidon_hash, poseidon_hash_many, FieldElement, Signature};
 use std::{
+    collections::BTreeMap,
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
-    io::Write,
+    io::{Cursor, Read},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tempfile::NamedTempFile;
+use thiserror::Error;
+use zip::ZipArchive;
 
 /*
     Job Object
     This object represents a task requested by a delegator.
     It contains metadata that allows the executor to decide if the task is attractive enough to run.
-    It includes a pie object that holds the task bytecode itself.
+    It includes a pie object that holds the task bytecode itself, and a deadline by which the delegator expects the proof.
     Additionally, the object holds the signature and public key of the delegator, enabling the executor to prove to the Registry that the task was intended by the delegator.
     The Job object also includes the target registry where the delegator expects this proof to be verified.
 */
@@ -28,64 +36,212 @@ pub struct Job {
 }
 
 impl Job {
-    pub fn try_from_job_data(job_data: JobData, signing_key: &SigningKey) -> Self {
-        let message_hash: FieldElement = job_data.compute_program_hash_chain();
-        let signature = signing_key.sign(&message_hash).unwrap();
+    pub fn try_from_job_data(
+        job_data: JobData,
+        signing_key: &SigningKey,
+    ) -> Result<Self, JobError> {
+        let pie = JobData::decompress_cairo_pie(&job_data.cairo_pie_compressed, MAX_PIE_SIZE)?;
+        Self::sign(job_data, &pie, signing_key)
+    }
+
+    // Signs job data whose PIE the caller already decompressed.
+    pub fn sign(
+        job_data: JobData,
+        pie: &CairoPie,
+        signing_key: &SigningKey,
+    ) -> Result<Self, JobError> {
+        let message_hash: FieldElement = job_data.message_hash(pie)?;
+        let signature =
+            signing_key.sign(&message_hash).map_err(|e| JobError::Signature(e.to_string()))?;
         let public_key = signing_key.verifying_key().scalar();
-        Self { job_data, public_key, signature_r: signature.r, signature_s: signature.s }
+        Ok(Self { job_data, public_key, signature_r: signature.r, signature_s: signature.s })
+    }
+
+    pub fn verify_signature(&self) -> Result<bool, JobError> {
+        let pie = JobData::decompress_cairo_pie(&self.job_data.cairo_pie_compressed, MAX_PIE_SIZE)?;
+        self.verify_signature_with(&pie)
     }
 
-    pub fn verify_signature(&self) -> bool {
-        let message_hash: FieldElement = self.job_data.compute_program_hash_chain();
+    // Verifies the signature against the PIE of the job the caller already decompressed.
+    pub fn verify_signature_with(&self, pie: &CairoPie) -> Result<bool, JobError> {
+        let message_hash: FieldElement = self.job_data.message_hash(pie)?;
         VerifyingKey::from_scalar(self.public_key)
             .verify(&message_hash, &Signature { r: self.signature_r, s: self.signature_s })
-            .unwrap()
+            .map_err(|e| JobError::Signature(e.to_string()))
     }
 }
 
+pub const DEFAULT_JOB_TTL: Duration = Duration::from_secs(600);
+
+// Longest a delegator may give executors to prove a job.
+pub const MAX_JOB_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Bytes a memory cell takes in a Cairo PIE, its address and its value.
+pub const MEMORY_CELL_SIZE: u64 = 40;
+
+// Most bytes a Cairo PIE may inflate to when the caller has no tighter limit, like for PIEs it built itself.
+pub const MAX_PIE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+// Entries of a zipped Cairo PIE.
+const PIE_ENTRIES: [&str; 5] = [
+    "version.json",
+    "metadata.json",
+    "memory.bin",
+    "execution_resources.json",
+    "additional_data.json",
+];
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct JobData {
     #[serde(with = "chunk_felt_array")]
     pub cairo_pie_compressed: Vec<u8>,
+    pub deadline: u64, // Unix timestamp in seconds by which the proof is expected, signed together with the program hash
 }
 
 impl JobData {
-    pub fn new(cairo_pie_compressed: Vec<u8>) -> Self {
-        Self { cairo_pie_compressed }
+    pub fn new(cairo_pie_compressed: Vec<u8>, deadline: u64) -> Self {
+        Self { cairo_pie_compressed, deadline }
     }
 
-    fn decompress_cairo_pie(cairo_pie_compressed: &[u8]) -> CairoPie {
-        let mut file = NamedTempFile::new().unwrap();
-        file.write_all(cairo_pie_compressed).unwrap();
-        CairoPie::read_zip_file(file.path()).unwrap()
+    pub fn with_ttl(cairo_pie_compressed: Vec<u8>, ttl: Duration) -> Self {
+        Self::new(cairo_pie_compressed, unix_now().saturating_add(ttl.as_secs()))
     }
 
-    pub fn compute_program_hash_chain(&self) -> FieldElement {
-        let pie = Self::decompress_cairo_pie(&self.cairo_pie_compressed);
+    // Time left until the deadline, None once it has passed.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline.checked_sub(unix_now()).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    // Deadlines come from remote peers, the ones beyond the maximum ttl are cut to it.
+    pub fn expires_at(&self) -> Instant {
+        Instant::now() + self.time_left().unwrap_or_default().min(MAX_JOB_TTL)
+    }
+
+    // Reads the zipped PIE straight from memory, so no temporary files are needed.
+    pub fn decompress_cairo_pie(
+        cairo_pie_compressed: &[u8],
+        max_size: u64,
+    ) -> Result<CairoPie, JobError> {
+        Self::decompress_cairo_pie_with_hints(cairo_pie_compressed, max_size).map(|(pie, _)| pie)
+    }
+
+    // Decompresses the PIE together with the code of the hints left in its program,
+    // stripped programs of well-formed PIEs carry none.
+    // PIEs come from remote peers, the sizes the archive declares are checked against the limit before
+    // anything is inflated and reads stop at the limit whatever the archive claims.
+    pub fn decompress_cairo_pie_with_hints(
+        cairo_pie_compressed: &[u8],
+        max_size: u64,
+    ) -> Result<(CairoPie, Vec<String>), JobError> {
+        let mut zip = ZipArchive::new(Cursor::new(cairo_pie_compressed))?;
+        let mut size = 0u64;
+        for name in PIE_ENTRIES {
+            size = size.saturating_add(zip.by_name(name)?.size());
+        }
+        if size > max_size {
+            return Err(JobError::PieTooLarge { size, limit: max_size });
+        }
+
+        let mut remaining = max_size;
+        let version: CairoPieVersion =
+            serde_json::from_slice(&pie_entry(&mut zip, "version.json", &mut remaining)?)?;
+        let metadata = pie_entry(&mut zip, "metadata.json", &mut remaining)?;
+        let hints: HintsMetadata = serde_json::from_slice(&metadata)?;
+        let metadata: CairoPieMetadata = serde_json::from_slice(&metadata)?;
+        let memory = pie_entry(&mut zip, "memory.bin", &mut remaining)?;
+        let memory = CairoPieMemory::from_bytes(&memory).ok_or(JobError::InvalidMemory)?;
+        let execution_resources: ExecutionResources = serde_json::from_slice(&pie_entry(
+            &mut zip,
+            "execution_resources.json",
+            &mut remaining,
+        )?)?;
+        let additional_data: CairoPieAdditionalData =
+            serde_json::from_slice(&pie_entry(&mut zip, "additional_data.json", &mut remaining)?)?;
+
+        let hints = hints.program.hints.into_values().flatten().map(|hint| hint.code).collect();
+        Ok((CairoPie { metadata, memory, execution_resources, additional_data, version }, hints))
+    }
+
+    pub fn compute_program_hash_chain(&self) -> Result<FieldElement, JobError> {
+        Self::program_hash_chain(&Self::decompress_cairo_pie(
+            &self.cairo_pie_compressed,
+            MAX_PIE_SIZE,
+        )?)
+    }
+
+    pub fn program_hash_chain(pie: &CairoPie) -> Result<FieldElement, JobError> {
         let mut felts: Vec<FieldElement> = vec![];
         felts.push(FieldElement::ZERO);
         felts.push(FieldElement::from(pie.metadata.program.main));
         felts.push(FieldElement::from(pie.metadata.program.builtins.len()));
-        felts.extend(
-            pie.metadata.program.builtins.iter().map(|builtin| {
-                FieldElement::from_byte_slice_be(builtin.to_str().as_bytes()).unwrap()
-            }),
-        );
-        felts.extend(
-            pie.metadata
-                .program
-                .data
-                .into_iter()
-                .map(|data| data.get_int().unwrap())
-                .map(|f| FieldElement::from_bytes_be(&f.to_bytes_be()).unwrap()),
-        );
-        poseidon_hash_many(&felts)
+        for builtin in pie.metadata.program.builtins.iter() {
+            felts.push(
+                FieldElement::from_byte_slice_be(builtin.to_str().as_bytes())
+                    .map_err(|_| JobError::InvalidBuiltinName(builtin.to_str().to_string()))?,
+            );
+        }
+        for data in pie.metadata.program.data.iter() {
+            let data = data.get_int().ok_or(JobError::NonIntegerProgramData)?;
+            felts.push(
+                FieldElement::from_bytes_be(&data.to_bytes_be())
+                    .map_err(|_| JobError::NonIntegerProgramData)?,
+            );
+        }
+        Ok(poseidon_hash_many(&felts))
+    }
+
+    // The message signed by the delegator, binding the program to the deadline.
+    pub fn compute_message_hash(&self) -> Result<FieldElement, JobError> {
+        Ok(poseidon_hash(self.compute_program_hash_chain()?, FieldElement::from(self.deadline)))
     }
+
+    // Same message for a PIE the caller already decompressed.
+    pub fn message_hash(&self, pie: &CairoPie) -> Result<FieldElement, JobError> {
+        Ok(poseidon_hash(Self::program_hash_chain(pie)?, FieldElement::from(self.deadline)))
+    }
+}
+
+// Reads an entry of a zipped PIE out of the bytes left to inflate.
+fn pie_entry(
+    zip: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+    remaining: &mut u64,
+) -> Result<Vec<u8>, JobError> {
+    let mut data = vec![];
+    zip.by_name(name)?.take(remaining.saturating_add(1)).read_to_end(&mut data)?;
+    let size = data.len() as u64;
+    if size > *remaining {
+        return Err(JobError::EntryTooLarge { name: name.to_string(), size, limit: *remaining });
+    }
+    *remaining -= size;
+    Ok(data)
+}
+
+// Hints of a full program in the metadata of a PIE, cairo-vm drops them when it decodes the stripped program.
+#[derive(Deserialize)]
+struct HintsMetadata {
+    program: HintsProgram,
+}
+
+#[derive(Deserialize)]
+struct HintsProgram {
+    #[serde(default)]
+    hints: BTreeMap<String, Vec<HintCode>>,
+}
+
+#[derive(Deserialize)]
+struct HintCode {
+    code: String,
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
 }
 
 impl Hash for JobData {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.cairo_pie_compressed.hash(state);
+        self.deadline.hash(state);
     }
 }
 
@@ -108,6 +264,43 @@ pub struct JobBid {
     pub price: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRejection {
+    pub identity: PeerId,
+    pub job_key: kad::RecordKey,
+    pub reason: String,
+}
+
+#[derive(Error, Debug)]
+pub enum JobError {
+    #[error("cairo pie archive error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("cairo pie decoding error: {0}")]
+    Serde(#[from] serde_json::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("cairo pie inflates to {size} bytes, the limit is {limit} bytes")]
+    PieTooLarge { size: u64, limit: u64 },
+
+    #[error("cairo pie entry {name} inflates to more than the {limit} bytes left")]
+    EntryTooLarge { name: String, size: u64, limit: u64 },
+
+    #[error("cairo pie memory is malformed")]
+    InvalidMemory,
+
+    #[error("program data is not an integer")]
+    NonIntegerProgramData,
+
+    #[error("builtin name {0} does not fit in a field element")]
+    InvalidBuiltinName(String),
+
+    #[error("signature error: {0}")]
+    Signature(String),
+}
+
 mod chunk_felt_array {
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
     use starknet_crypto::FieldElement;
@@ -149,10 +342,14 @@ mod chunk_felt_array {
         v
     }
 
+    // Felts are what the bootloader reads, binary formats like the wire codec keep the plain bytes.
     pub fn serialize<S>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(value);
+        }
         FieldElementsData {
             data_len: value.len(),
             data: from_data_vec_to_vec_field_elements(value),
@@ -164,6 +361,9 @@ mod chunk_felt_array {
     where
         D: Deserializer<'de>,
     {
+        if !deserializer.is_human_readable() {
+            return Vec::<u8>::deserialize(deserializer);
+        }
         let field_elements_data = FieldElementsData::deserialize(deserializer)?;
         Ok(from_field_elements_vec_to_data_vec(
             &field_elements_data.data,
@@ -186,3 +386,70 @@ mod chunk_felt_array {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, path::PathBuf};
+
+    fn cairo_pie_compressed() -> Vec<u8> {
+        let ws_root = PathBuf::from(
+            env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR env not present"),
+        )
+        .join("../../");
+        fs::read(ws_root.join("crates/tests/cairo/fibonacci_pie.zip")).unwrap()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let job = Job::try_from_job_data(
+            JobData::with_ttl(cairo_pie_compressed(), DEFAULT_JOB_TTL),
+            &SigningKey::from_random(),
+        )
+        .unwrap();
+        assert!(job.verify_signature().unwrap());
+    }
+
+    #[test]
+    fn deadline_is_signed() {
+        let mut job = Job::try_from_job_data(
+            JobData::with_ttl(cairo_pie_compressed(), DEFAULT_JOB_TTL),
+            &SigningKey::from_random(),
+        )
+        .unwrap();
+        job.job_data.deadline += 1;
+        assert!(!job.verify_signature().unwrap());
+    }
+
+    #[test]
+    fn expired_job_data() {
+        let job_data = JobData::new(vec![], unix_now() - 1);
+        assert_eq!(job_data.time_left(), None);
+    }
+
+    #[test]
+    fn distant_deadline_does_not_overflow() {
+        let job_data = JobData::with_ttl(vec![], Duration::MAX);
+        assert_eq!(job_data.deadline, u64::MAX);
+        assert!(job_data.expires_at() <= Instant::now() + MAX_JOB_TTL);
+    }
+
+    #[test]
+    fn pie_inflating_beyond_the_limit() {
+        let pie = cairo_pie_compressed();
+        assert!(matches!(
+            JobData::decompress_cairo_pie(&pie, 1024),
+            Err(JobError::PieTooLarge { limit: 1024, .. })
+        ));
+        JobData::decompress_cairo_pie(&pie, MAX_PIE_SIZE).unwrap();
+    }
+
+    #[test]
+    fn malformed_cairo_pie() {
+        let job_data = JobData::new(vec![1, 2, 3], unix_now());
+        assert!(matches!(
+            Job::try_from_job_data(job_data, &SigningKey::from_random()),
+            Err(JobError::Zip(_))
+        ));
+    }
+}
@@ -1,60 +1,58 @@
-use crate::hash;
+use crate::{hash, workspace::Workspace};
 use libp2p::kad;
 use std::{
     fmt::Display,
     hash::{DefaultHasher, Hash, Hasher},
-    mem::ManuallyDrop,
+    path::PathBuf,
 };
-use tempfile::NamedTempFile;
 
 /*
     Job Trace Object
     This object represents the output from the Cairo run process in proof mode.
     It includes objects such as public input, private input, trace, and memory.
+    The files live in the workspace of the job, which the trace owns until the job is proven.
 */
 
 #[derive(Debug)]
 pub struct JobTrace {
     pub job_key: kad::RecordKey,
-    pub air_public_input: NamedTempFile, // Temporary file containing the public input
-    pub air_private_input: NamedTempFile, // Temporary file containing the private input; memory and trace files must exist for this to be valid
-    pub memory: ManuallyDrop<NamedTempFile>, // Temporary file containing memory data (required for air_private_input validity)
-    pub trace: ManuallyDrop<NamedTempFile>, // Temporary file containing trace data (required for air_private_input validity)
+    pub workspace: Workspace,
 }
 
 impl JobTrace {
-    pub fn new(
-        job_key: kad::RecordKey,
-        air_public_input: NamedTempFile,
-        air_private_input: NamedTempFile,
-        memory: NamedTempFile,
-        trace: NamedTempFile,
-    ) -> Self {
-        Self {
-            job_key,
-            air_public_input,
-            air_private_input,
-            memory: ManuallyDrop::new(memory),
-            trace: ManuallyDrop::new(trace),
-        }
+    pub const AIR_PUBLIC_INPUT: &'static str = "air_public_input.json";
+    pub const AIR_PRIVATE_INPUT: &'static str = "air_private_input.json";
+    pub const MEMORY: &'static str = "memory.bin";
+    pub const TRACE: &'static str = "trace.bin";
+
+    pub fn new(job_key: kad::RecordKey, workspace: Workspace) -> Self {
+        Self { job_key, workspace }
+    }
+
+    pub fn air_public_input(&self) -> PathBuf {
+        self.workspace.file(Self::AIR_PUBLIC_INPUT)
+    }
+
+    // Refers to the memory and trace files, they must exist for it to be valid.
+    pub fn air_private_input(&self) -> PathBuf {
+        self.workspace.file(Self::AIR_PRIVATE_INPUT)
+    }
+
+    pub fn memory(&self) -> PathBuf {
+        self.workspace.file(Self::MEMORY)
     }
-}
 
-impl Drop for JobTrace {
-    fn drop(&mut self) {
-        unsafe {
-            ManuallyDrop::drop(&mut self.memory);
-            ManuallyDrop::drop(&mut self.trace);
-        }
+    pub fn trace(&self) -> PathBuf {
+        self.workspace.file(Self::TRACE)
     }
 }
 
 impl Hash for JobTrace {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.air_public_input.path().hash(state);
-        self.air_private_input.path().hash(state);
-        self.memory.path().hash(state);
-        self.trace.path().hash(state);
+        self.air_public_input().hash(state);
+        self.air_private_input().hash(state);
+        self.memory().hash(state);
+        self.trace().hash(state);
     }
 }
 
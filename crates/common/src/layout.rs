@@ -1,8 +1,24 @@
+use serde::{Deserialize, Serialize};
 use strum::IntoStaticStr;
 
-#[derive(Debug, PartialEq, IntoStaticStr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoStaticStr, Serialize, Deserialize)]
 #[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
 pub enum Layout {
     RecursiveWithPoseidon,
     Starknet,
 }
+
+impl Layout {
+    // Builtins a task can use when it is run by the bootloader in this layout.
+    pub fn builtins(&self) -> &'static [&'static str] {
+        match self {
+            Layout::RecursiveWithPoseidon => {
+                &["output", "pedersen", "range_check", "bitwise", "poseidon"]
+            }
+            Layout::Starknet => {
+                &["output", "pedersen", "range_check", "ecdsa", "bitwise", "ec_op", "poseidon"]
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+pub mod capability;
 pub mod graceful_shutdown;
 pub mod job;
 pub mod job_trace;
@@ -5,3 +6,5 @@ pub mod job_witness;
 pub mod layout;
 pub mod macros;
 pub mod process;
+pub mod registry;
+pub mod workspace;
@@ -1,6 +1,22 @@
 use futures::{Future, FutureExt};
-use std::pin::Pin;
-use tokio::sync::mpsc;
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::pending,
+    io,
+    pin::Pin,
+    process::{ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tokio::{
+    io::AsyncReadExt,
+    process::{ChildStderr, Command},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tracing::{debug, warn};
 
 pub struct Process<'future, PR> {
     future: Pin<Box<dyn Future<Output = PR> + Send + 'future>>,
@@ -33,3 +49,418 @@ impl<'future, PR> Future for Process<'future, PR> {
         self.future.poll_unpin(cx)
     }
 }
+
+/*
+    Process Limits
+    Caps on a child process spawned for a job, so a malicious or huge job cannot hang or exhaust the executor.
+    The timeout is enforced by the supervisor of the child, which sends on the terminate channel of the child once
+    the timeout passes, so a timed out child is ended the same way as an aborted one.
+    Memory and CPU time are capped through rlimits set in the child right before it executes. The supervisor reaps
+    the child itself to learn what it used, a failure is blamed on the CPU time limit only when the child used that much
+    CPU time, and on the memory limit only when the child reported a failed allocation.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessLimits {
+    pub timeout: Option<Duration>,
+    // Address space of the child in bytes.
+    pub max_memory: Option<u64>,
+    pub max_cpu_time: Option<Duration>,
+}
+
+// What children write to stderr when an allocation fails, Python raises a MemoryError and native code aborts.
+const ALLOCATION_FAILURES: &[&str] =
+    &["MemoryError", "std::bad_alloc", "Cannot allocate memory", "out of memory"];
+
+impl ProcessLimits {
+    // Sets the memory and CPU time rlimits of the child spawned by the command.
+    pub fn apply(&self, command: &mut Command) {
+        #[cfg(unix)]
+        {
+            // The child gets SIGXCPU once its CPU time is up and SIGKILL a second later.
+            let limits = [
+                (libc::RLIMIT_AS, self.max_memory.map(|max_memory| (max_memory, max_memory))),
+                (
+                    libc::RLIMIT_CPU,
+                    self.max_cpu_time
+                        .map(|cpu_time| cpu_time.as_secs().max(1))
+                        .map(|secs| (secs, secs + 1)),
+                ),
+            ];
+            if limits.iter().all(|(_, limit)| limit.is_none()) {
+                return;
+            }
+            // SAFETY: setrlimit is async-signal-safe and the closure allocates nothing.
+            unsafe {
+                command.pre_exec(move || {
+                    for (resource, limit) in limits {
+                        let Some((soft, hard)) = limit else { continue };
+                        let rlimit = libc::rlimit {
+                            rlim_cur: soft as libc::rlim_t,
+                            rlim_max: hard as libc::rlim_t,
+                        };
+                        if libc::setrlimit(resource, &rlimit) != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = command;
+    }
+
+    // Resolves once the timeout of the child is up, never when it has none.
+    pub async fn deadline(&self) {
+        match self.timeout {
+            Some(timeout) => sleep(timeout).await,
+            None => pending().await,
+        }
+    }
+
+    // Limit a failed child ran into, judging by the resources it used and what it wrote to stderr.
+    // Meaningless for children killed on a timeout or an abort.
+    pub fn exceeded(&self, usage: &ResourceUsage, stderr: &str) -> Option<String> {
+        if let Some(cpu_time) = self.max_cpu_time {
+            let secs = cpu_time.as_secs().max(1);
+            if usage.cpu_time.as_secs() >= secs {
+                return Some(format!("cpu time limit of {secs}s"));
+            }
+        }
+        match self.max_memory {
+            Some(max_memory)
+                if ALLOCATION_FAILURES.iter().any(|failure| stderr.contains(failure)) =>
+            {
+                Some(format!("memory limit of {max_memory} bytes"))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Resources a child used until it exited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub cpu_time: Duration,
+    // Peak resident set size in bytes.
+    pub max_rss: u64,
+}
+
+/*
+    Child Failure
+    What is known about a child process that did not exit successfully: the command line it was spawned with,
+    its exit code or the signal that ended it and the tail of what it wrote to stderr.
+    Errors of the compiler, runner and prover carry it so operators can tell a failed Cairo assertion from a prover OOM.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChildFailure {
+    pub command: String,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub stderr: String,
+}
+
+// Bytes kept from the end of the stderr of a child.
+pub const STDERR_TAIL_BYTES: usize = 4096;
+
+const STDERR_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+impl ChildFailure {
+    fn new(command: String, status: ExitStatus, stderr: String) -> Self {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&status);
+        #[cfg(not(unix))]
+        let signal = None;
+        Self { command, code: status.code(), signal, stderr }
+    }
+}
+
+impl fmt::Display for ChildFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), _) => write!(f, "`{}` exited with code {code}", self.command)?,
+            (None, Some(signal)) => write!(f, "`{}` was killed by signal {signal}", self.command)?,
+            (None, None) => write!(f, "`{}` did not exit", self.command)?,
+        }
+        match self.stderr.trim() {
+            "" => Ok(()),
+            stderr => write!(f, ", stderr: {stderr}"),
+        }
+    }
+}
+
+// Spawns the command and waits for its child, which is killed on abort or once its timeout is up.
+// The timeout is sent through the terminate channel of the child, like an abort.
+pub async fn supervise_child(
+    mut command: Command,
+    limits: &ProcessLimits,
+    terminate_tx: mpsc::Sender<()>,
+    terminate_rx: &mut mpsc::Receiver<()>,
+) -> Result<(), ChildError> {
+    let command_line = command_line(&command);
+    limits.apply(&mut command);
+    // Spawned through std and reaped by the supervisor, so the resources the child used can be read.
+    let mut child = command.as_std_mut().stderr(Stdio::piped()).spawn()?;
+    let stderr = child.stderr.take().map(ChildStderr::from_std).transpose()?;
+    let stderr = tokio::spawn(read_tail(stderr));
+    let mut child = RunningChild::spawn(child);
+
+    let limits = *limits;
+    let started = Instant::now();
+    let timeout_terminate = {
+        let command_line = command_line.to_owned();
+        async move {
+            limits.deadline().await;
+            warn!("`{command_line}` timed out");
+            let _ = terminate_tx.send(()).await;
+            pending::<()>().await
+        }
+    };
+    tokio::pin!(timeout_terminate);
+    let mut terminated = false;
+    let (status, usage) = loop {
+        tokio::select! {
+            exit = &mut child.exit => break exit.map_err(io::Error::other)??,
+            Some(()) = terminate_rx.recv() => {
+                terminated = true;
+                child.kill();
+            }
+            _ = &mut timeout_terminate => {}
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+
+    let timed_out =
+        terminated && limits.timeout.is_some_and(|timeout| started.elapsed() >= timeout);
+    if terminated && !timed_out {
+        return Err(ChildError::Aborted);
+    }
+    // Grandchildren may hold on to stderr after the child is gone, they are not waited for long.
+    let stderr = timeout(STDERR_GRACE_PERIOD, stderr).await.ok().and_then(Result::ok);
+    let failure = ChildFailure::new(command_line, status, stderr.unwrap_or_default());
+    debug!("{failure}, used {usage:?}");
+    Err(if timed_out {
+        ChildError::Timeout(limits.timeout.unwrap_or_default(), failure)
+    } else if let Some(limit) = limits.exceeded(&usage, &failure.stderr) {
+        ChildError::ResourceExceeded(limit, failure)
+    } else {
+        ChildError::Failed(failure)
+    })
+}
+
+// Child reaped on the blocking pool, killed when its supervisor goes away before it exited.
+struct RunningChild {
+    pid: u32,
+    exit: JoinHandle<io::Result<(ExitStatus, ResourceUsage)>>,
+}
+
+impl RunningChild {
+    fn spawn(child: std::process::Child) -> Self {
+        Self { pid: child.id(), exit: tokio::task::spawn_blocking(move || wait(child)) }
+    }
+
+    fn kill(&self) {
+        // The pid stays with the child until it is reaped, which is what ends the exit task.
+        if !self.exit.is_finished() {
+            kill(self.pid);
+        }
+    }
+}
+
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+// Waits for the child to exit and reaps it, together with the resources it used.
+#[cfg(unix)]
+fn wait(child: std::process::Child) -> io::Result<(ExitStatus, ResourceUsage)> {
+    use std::os::unix::process::ExitStatusExt;
+    let pid = child.id() as libc::pid_t;
+    let mut status = 0;
+    // SAFETY: rusage is plain data, all zeroes is a valid value.
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: the child is ours and not reaped yet, the pointers are valid for the call.
+    while unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } != pid {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    let duration =
+        |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    let usage = ResourceUsage {
+        cpu_time: duration(rusage.ru_utime) + duration(rusage.ru_stime),
+        // Linux reports the peak resident set size in kilobytes.
+        max_rss: rusage.ru_maxrss as u64 * 1024,
+    };
+    Ok((ExitStatus::from_raw(status), usage))
+}
+
+#[cfg(not(unix))]
+fn wait(mut child: std::process::Child) -> io::Result<(ExitStatus, ResourceUsage)> {
+    Ok((child.wait()?, ResourceUsage::default()))
+}
+
+#[cfg(unix)]
+fn kill(pid: u32) {
+    // SAFETY: sending a signal has no memory safety requirements.
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(_pid: u32) {}
+
+// Program and arguments of the command, as they would be typed in a shell.
+fn command_line(command: &Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Reads the stream to its end and returns the last STDERR_TAIL_BYTES of it.
+async fn read_tail(stream: Option<ChildStderr>) -> String {
+    let Some(mut stream) = stream else {
+        return String::new();
+    };
+    let (mut tail, mut buffer) = (VecDeque::new(), [0; 1024]);
+    while let Ok(read @ 1..) = stream.read(&mut buffer).await {
+        tail.extend(&buffer[..read]);
+        tail.drain(..tail.len().saturating_sub(STDERR_TAIL_BYTES));
+    }
+    String::from_utf8_lossy(tail.make_contiguous()).into_owned()
+}
+
+#[derive(Error, Debug)]
+pub enum ChildError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("aborted")]
+    Aborted,
+
+    #[error("timed out after {0:?}: {1}")]
+    Timeout(Duration, ChildFailure),
+
+    #[error("exceeded its {0}: {1}")]
+    ResourceExceeded(String, ChildFailure),
+
+    #[error("{0}")]
+    Failed(ChildFailure),
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    async fn supervise(script: &str, limits: ProcessLimits) -> Result<(), ChildError> {
+        let (terminate_tx, mut terminate_rx) = mpsc::channel(1);
+        supervise_child(shell(script), &limits, terminate_tx, &mut terminate_rx).await
+    }
+
+    #[tokio::test]
+    async fn failed_child_reports_exit_and_stderr() {
+        let Err(ChildError::Failed(failure)) =
+            supervise("echo boom >&2; exit 3", ProcessLimits::default()).await
+        else {
+            panic!("child did not fail");
+        };
+        assert_eq!(failure.command, "sh -c echo boom >&2; exit 3");
+        assert_eq!((failure.code, failure.signal), (Some(3), None));
+        assert_eq!(failure.stderr, "boom\n");
+        assert_eq!(
+            failure.to_string(),
+            "`sh -c echo boom >&2; exit 3` exited with code 3, stderr: boom"
+        );
+    }
+
+    #[tokio::test]
+    async fn stderr_keeps_its_tail() {
+        let script = format!(
+            "head -c {} /dev/zero | tr '\\0' a >&2; echo end >&2; exit 1",
+            STDERR_TAIL_BYTES * 2
+        );
+        let Err(ChildError::Failed(failure)) = supervise(&script, ProcessLimits::default()).await
+        else {
+            panic!("child did not fail");
+        };
+        assert_eq!(failure.stderr.len(), STDERR_TAIL_BYTES);
+        assert!(failure.stderr.ends_with("aend\n"));
+    }
+
+    #[tokio::test]
+    async fn slow_child_times_out() {
+        let limits =
+            ProcessLimits { timeout: Some(Duration::from_millis(100)), ..Default::default() };
+        let result = supervise("sleep 10", limits).await;
+        assert!(matches!(
+            result,
+            Err(ChildError::Timeout(_, ChildFailure { signal: Some(libc::SIGKILL), .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn cpu_time_limit_ends_busy_child() {
+        let limits =
+            ProcessLimits { max_cpu_time: Some(Duration::from_secs(1)), ..Default::default() };
+        let Err(ChildError::ResourceExceeded(limit, _)) =
+            supervise("while :; do :; done", limits).await
+        else {
+            panic!("child was not stopped by its limit");
+        };
+        assert_eq!(limit, "cpu time limit of 1s");
+    }
+
+    #[tokio::test]
+    async fn aborted_child_is_killed() {
+        let (terminate_tx, mut terminate_rx) = mpsc::channel(1);
+        terminate_tx.send(()).await.unwrap();
+        let limits = ProcessLimits::default();
+        let result =
+            supervise_child(shell("sleep 10"), &limits, terminate_tx, &mut terminate_rx).await;
+        assert!(matches!(result, Err(ChildError::Aborted)));
+    }
+
+    #[tokio::test]
+    async fn killed_child_is_not_blamed_on_cpu_time() {
+        let limits =
+            ProcessLimits { max_cpu_time: Some(Duration::from_secs(10)), ..Default::default() };
+        let result = supervise("kill -9 $$", limits).await;
+        assert!(matches!(
+            result,
+            Err(ChildError::Failed(ChildFailure { signal: Some(libc::SIGKILL), .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn failed_allocation_exceeds_memory_limit() {
+        let limits = ProcessLimits { max_memory: Some(1 << 30), ..Default::default() };
+        let Err(ChildError::ResourceExceeded(limit, failure)) =
+            supervise("echo MemoryError >&2; exit 1", limits).await
+        else {
+            panic!("child was not stopped by its limit");
+        };
+        assert_eq!(limit, "memory limit of 1073741824 bytes");
+        assert_eq!(failure.code, Some(1));
+    }
+
+    #[test]
+    fn failure_without_limits_exceeds_nothing() {
+        let usage = ResourceUsage { cpu_time: Duration::from_secs(60), max_rss: u64::MAX };
+        assert_eq!(ProcessLimits::default().exceeded(&usage, "MemoryError"), None);
+    }
+}
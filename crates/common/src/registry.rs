@@ -0,0 +1,368 @@
+use crate::job_witness::JobWitness;
+use async_stream::stream;
+use futures::{future::BoxFuture, Stream};
+use starknet::{
+    accounts::{Account, Call, ExecutionEncoding, SingleOwnerAccount},
+    core::types::{BlockId, BlockTag, EventFilter, FieldElement, FunctionCall},
+    macros::selector,
+    providers::{jsonrpc::HttpTransport, JsonRpcClient, Provider, ProviderError, Url},
+    signers::{LocalWallet, SigningKey},
+};
+use std::{collections::BTreeMap, pin::Pin, process::Stdio, sync::RwLock, time::Duration};
+use thiserror::Error;
+use tokio::{io::AsyncWriteExt, process::Command, time::sleep};
+
+/*
+    Registry Client
+    This object talks to the ZetinaRegistry contract deployed on Starknet.
+    It reads delegator balances, submits finished `JobWitness` objects through `verify_job_witness`
+    so that executors can claim their rewards, and watches `JobWitnessVerified` events.
+    The RPC endpoint is part of the configuration, so it can point at a public node, a devnet or a local stand-in.
+    Submitting proofs requires an account; read-only clients can be created without one.
+*/
+
+const EVENTS_CHUNK_SIZE: u64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct RegistryConfig {
+    pub rpc_url: Url,
+    pub registry_address: FieldElement,
+}
+
+pub struct RegistryClient {
+    config: RegistryConfig,
+    provider: JsonRpcClient<HttpTransport>,
+    account: Option<SingleOwnerAccount<JsonRpcClient<HttpTransport>, LocalWallet>>,
+}
+
+impl RegistryClient {
+    pub fn new(config: RegistryConfig) -> Self {
+        let provider = JsonRpcClient::new(HttpTransport::new(config.rpc_url.to_owned()));
+        Self { config, provider, account: None }
+    }
+
+    pub async fn with_account(
+        mut self,
+        account_address: FieldElement,
+        signing_key: SigningKey,
+    ) -> Result<Self, RegistryError> {
+        let chain_id = self.provider.chain_id().await?;
+        let mut account = SingleOwnerAccount::new(
+            JsonRpcClient::new(HttpTransport::new(self.config.rpc_url.to_owned())),
+            LocalWallet::from(signing_key),
+            account_address,
+            chain_id,
+            ExecutionEncoding::New,
+        );
+        account.set_block_id(BlockId::Tag(BlockTag::Pending));
+        self.account = Some(account);
+        Ok(self)
+    }
+
+    pub fn registry_address(&self) -> FieldElement {
+        self.config.registry_address
+    }
+
+    pub async fn balance(&self, account: FieldElement) -> Result<u128, RegistryError> {
+        let result = self
+            .provider
+            .call(
+                FunctionCall {
+                    contract_address: self.config.registry_address,
+                    entry_point_selector: selector!("balance"),
+                    calldata: vec![account],
+                },
+                BlockId::Tag(BlockTag::Latest),
+            )
+            .await?;
+
+        match result.as_slice() {
+            [low, high] => Ok(u256_to_u128(*low, *high)),
+            _ => Err(RegistryError::UnexpectedResponse(format!("{result:?}"))),
+        }
+    }
+
+    pub async fn verify_job_witness(
+        &self,
+        job_witness: &JobWitness,
+    ) -> Result<FieldElement, RegistryError> {
+        let account = self.account.as_ref().ok_or(RegistryError::AccountNotConfigured)?;
+        let calldata = proof_calldata(&job_witness.proof).await?;
+
+        let result = account
+            .execute_v1(vec![Call {
+                to: self.config.registry_address,
+                selector: selector!("verify_job_witness"),
+                calldata,
+            }])
+            .send()
+            .await
+            .map_err(|e| RegistryError::Account(e.to_string()))?;
+
+        Ok(result.transaction_hash)
+    }
+
+    pub fn job_witness_verified_events(
+        &self,
+        from_block: u64,
+        poll_interval: Duration,
+    ) -> Pin<Box<dyn Stream<Item = Result<JobWitnessVerified, RegistryError>> + Send + '_>> {
+        let stream = stream! {
+            let mut from_block = from_block;
+            loop {
+                let head = match self.provider.block_number().await {
+                    Ok(head) => head,
+                    Err(err) => {
+                        yield Err(err.into());
+                        sleep(poll_interval).await;
+                        continue;
+                    }
+                };
+
+                if head >= from_block {
+                    let filter = EventFilter {
+                        from_block: Some(BlockId::Number(from_block)),
+                        to_block: Some(BlockId::Number(head)),
+                        address: Some(self.config.registry_address),
+                        keys: Some(vec![vec![selector!("JobWitnessVerified")]]),
+                    };
+
+                    let mut continuation_token = None;
+                    loop {
+                        match self.provider.get_events(filter.to_owned(), continuation_token, EVENTS_CHUNK_SIZE).await {
+                            Ok(page) => {
+                                for event in page.events {
+                                    yield JobWitnessVerified::try_from_keys_and_data(&event.keys, &event.data);
+                                }
+                                match page.continuation_token {
+                                    Some(token) => continuation_token = Some(token),
+                                    None => break,
+                                }
+                            }
+                            Err(err) => {
+                                yield Err(err.into());
+                                break;
+                            }
+                        }
+                    }
+
+                    from_block = head + 1;
+                }
+
+                sleep(poll_interval).await;
+            }
+        };
+        Box::pin(stream)
+    }
+}
+
+/*
+    Job Witness Verified Event
+    Emitted by the registry once a `JobWitness` was verified and the reward was transferred from the delegator to the executor.
+    The executor and delegator are event keys, the reward and the number of steps are u256 values in the event data.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobWitnessVerified {
+    pub reward: u128,
+    pub num_of_steps: u128,
+    pub executor: FieldElement,
+    pub delegator: FieldElement,
+}
+
+impl JobWitnessVerified {
+    pub fn try_from_keys_and_data(
+        keys: &[FieldElement],
+        data: &[FieldElement],
+    ) -> Result<Self, RegistryError> {
+        match (keys, data) {
+            (
+                [_selector, executor, delegator],
+                [reward_low, reward_high, num_of_steps_low, num_of_steps_high],
+            ) => Ok(Self {
+                reward: u256_to_u128(*reward_low, *reward_high),
+                num_of_steps: u256_to_u128(*num_of_steps_low, *num_of_steps_high),
+                executor: *executor,
+                delegator: *delegator,
+            }),
+            _ => Err(RegistryError::UnexpectedResponse(format!("keys {keys:?}, data {data:?}"))),
+        }
+    }
+}
+
+/*
+    Balance Oracle
+    Answers how much a delegator has deposited in the registry, keyed by the delegator public key from the `Job`.
+    The `RegistryClient` answers from the chain, the `InMemoryBalanceOracle` from a local table and is meant for tests and local setups.
+*/
+pub trait BalanceOracle: Send + Sync {
+    fn balance(&self, account: FieldElement) -> BoxFuture<'_, Result<u128, RegistryError>>;
+}
+
+impl BalanceOracle for RegistryClient {
+    fn balance(&self, account: FieldElement) -> BoxFuture<'_, Result<u128, RegistryError>> {
+        Box::pin(RegistryClient::balance(self, account))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InMemoryBalanceOracle {
+    balances: RwLock<BTreeMap<FieldElement, u128>>,
+}
+
+impl InMemoryBalanceOracle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_balance(&self, account: FieldElement, amount: u128) {
+        self.balances.write().unwrap().insert(account, amount);
+    }
+}
+
+impl BalanceOracle for InMemoryBalanceOracle {
+    fn balance(&self, account: FieldElement) -> BoxFuture<'_, Result<u128, RegistryError>> {
+        let balance = self.balances.read().unwrap().get(&account).copied().unwrap_or_default();
+        Box::pin(async move { Ok(balance) })
+    }
+}
+
+// Serializes the proof produced by the prover into `StarkProofWithSerde` calldata using the `proof_serializer` tool
+// from the integrity verifier, installed in the runtime image.
+pub async fn proof_calldata(proof: &[u8]) -> Result<Vec<FieldElement>, RegistryError> {
+    let mut task = Command::new("proof_serializer")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    if let Some(mut stdin) = task.stdin.take() {
+        stdin.write_all(proof).await?;
+    }
+
+    let output = task.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(RegistryError::ProofSerialization(output.status.to_string()));
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(|felt| {
+            FieldElement::from_dec_str(felt)
+                .map_err(|e| RegistryError::ProofSerialization(e.to_string()))
+        })
+        .collect()
+}
+
+// Values above u128::MAX are far beyond any realistic token amount, so they saturate.
+fn u256_to_u128(low: FieldElement, high: FieldElement) -> u128 {
+    if high != FieldElement::ZERO {
+        return u128::MAX;
+    }
+    let bytes = low.to_bytes_be();
+    let (upper, lower) = bytes.split_at(16);
+    if upper.iter().any(|byte| *byte != 0) {
+        return u128::MAX;
+    }
+    u128::from_be_bytes(lower.try_into().unwrap())
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("provider error: {0}")]
+    Provider(#[from] ProviderError),
+
+    #[error("account error: {0}")]
+    Account(String),
+
+    #[error("no account configured for submitting transactions")]
+    AccountNotConfigured,
+
+    #[error("unexpected response: {0}")]
+    UnexpectedResponse(String),
+
+    #[error("proof serialization error: {0}")]
+    ProofSerialization(String),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    // Minimal JSON-RPC stand-in answering `starknet_call` with a fixed u256 balance.
+    async fn rpc_stand_in(Json(request): Json<Value>) -> Json<Value> {
+        let result = match request["method"].as_str() {
+            Some("starknet_call") => json!(["0x2a", "0x0"]),
+            Some("starknet_chainId") => json!("0x534e5f5345504f4c4941"),
+            _ => Value::Null,
+        };
+        Json(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+    }
+
+    async fn spawn_stand_in() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, Router::new().route("/", post(rpc_stand_in))).await.unwrap();
+        });
+        Url::parse(&format!("http://{addr}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn balance_from_stand_in() {
+        let client = RegistryClient::new(RegistryConfig {
+            rpc_url: spawn_stand_in().await,
+            registry_address: FieldElement::ONE,
+        });
+        assert_eq!(client.balance(FieldElement::TWO).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn verify_without_account() {
+        let client = RegistryClient::new(RegistryConfig {
+            rpc_url: spawn_stand_in().await,
+            registry_address: FieldElement::ONE,
+        });
+        let job_witness = JobWitness { job_key: libp2p::kad::RecordKey::new(&[0]), proof: vec![] };
+        assert!(matches!(
+            client.verify_job_witness(&job_witness).await,
+            Err(RegistryError::AccountNotConfigured)
+        ));
+    }
+
+    #[tokio::test]
+    async fn in_memory_balance_oracle() {
+        let oracle = InMemoryBalanceOracle::new();
+        oracle.set_balance(FieldElement::ONE, 10);
+        assert_eq!(BalanceOracle::balance(&oracle, FieldElement::ONE).await.unwrap(), 10);
+        assert_eq!(BalanceOracle::balance(&oracle, FieldElement::TWO).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn decode_job_witness_verified() {
+        let event = JobWitnessVerified::try_from_keys_and_data(
+            &[selector!("JobWitnessVerified"), FieldElement::ONE, FieldElement::TWO],
+            &[
+                FieldElement::from(100_u64),
+                FieldElement::ZERO,
+                FieldElement::THREE,
+                FieldElement::ZERO,
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            event,
+            JobWitnessVerified {
+                reward: 100,
+                num_of_steps: 3,
+                executor: FieldElement::ONE,
+                delegator: FieldElement::TWO,
+            }
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    env, fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tempfile::TempDir;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/*
+    Job Workspace
+    Directory holding every file of a job while it is compiled, run and proven, in place of temporary files scattered
+    over the system temp dir. Workspaces are created under a configurable root once it is checked to have enough free
+    disk space, and removed with everything in them when dropped, unless the operator keeps them for debugging.
+    The stage that completes the job marks its workspace as succeeded, a workspace dropped before that belongs to a failed job.
+*/
+
+// Which workspaces are kept on disk once dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Retention {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "on-failure" => Ok(Self::OnFailure),
+            "always" => Ok(Self::Always),
+            _ => Err(format!("unknown retention {s}, expected never, on-failure or always")),
+        }
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Never => write!(f, "never"),
+            Self::OnFailure => write!(f, "on-failure"),
+            Self::Always => write!(f, "always"),
+        }
+    }
+}
+
+// Free disk space a workspace needs by default, traces of large jobs take gigabytes.
+pub const DEFAULT_MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceConfig {
+    pub root: PathBuf,
+    pub min_free_bytes: u64,
+    pub retention: Retention,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        Self {
+            root: env::temp_dir().join("zetina"),
+            min_free_bytes: DEFAULT_MIN_FREE_BYTES,
+            retention: Retention::default(),
+        }
+    }
+}
+
+impl WorkspaceConfig {
+    // Creates a fresh workspace named after the job.
+    pub fn create(&self, name: &str) -> Result<Workspace, WorkspaceError> {
+        fs::create_dir_all(&self.root)?;
+        let free_bytes = fs2::available_space(&self.root)?;
+        if free_bytes < self.min_free_bytes {
+            return Err(WorkspaceError::InsufficientSpace {
+                root: self.root.to_owned(),
+                free_bytes,
+                min_free_bytes: self.min_free_bytes,
+            });
+        }
+        let dir = tempfile::Builder::new().prefix(&format!("{name}-")).tempdir_in(&self.root)?;
+        Ok(Workspace { dir: Some(dir), retention: self.retention, succeeded: false })
+    }
+}
+
+#[derive(Debug)]
+pub struct Workspace {
+    dir: Option<TempDir>,
+    retention: Retention,
+    succeeded: bool,
+}
+
+impl Workspace {
+    pub fn path(&self) -> &Path {
+        self.dir.as_ref().map(TempDir::path).unwrap_or(Path::new(""))
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.path().join(name)
+    }
+
+    // Marks the job of the workspace as done, it is removed unless every workspace is kept.
+    pub fn succeed(&mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let Some(dir) = self.dir.take() else { return };
+        let keep = match self.retention {
+            Retention::Never => false,
+            Retention::OnFailure => !self.succeeded,
+            Retention::Always => true,
+        };
+        if keep {
+            info!("Keeping workspace {}", dir.into_path().display());
+        } else if let Err(err) = dir.close() {
+            warn!("Failed to remove workspace: {err}");
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WorkspaceError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("{free_bytes} bytes free under {root:?}, workspaces need {min_free_bytes}")]
+    InsufficientSpace { root: PathBuf, free_bytes: u64, min_free_bytes: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(root: &Path, retention: Retention) -> WorkspaceConfig {
+        WorkspaceConfig { root: root.to_owned(), min_free_bytes: 0, retention }
+    }
+
+    fn leftovers(root: &Path) -> usize {
+        fs::read_dir(root).unwrap().count()
+    }
+
+    #[test]
+    fn workspace_is_removed_when_dropped() {
+        let root = TempDir::new().unwrap();
+        let workspace = config(root.path(), Retention::Never).create("job").unwrap();
+        fs::write(workspace.file("trace"), [1, 2, 3]).unwrap();
+        assert!(workspace.path().starts_with(root.path()));
+        drop(workspace);
+        assert_eq!(leftovers(root.path()), 0);
+    }
+
+    #[test]
+    fn failed_workspace_is_kept_on_failure() {
+        let root = TempDir::new().unwrap();
+        let config = config(root.path(), Retention::OnFailure);
+        let mut succeeded = config.create("succeeded").unwrap();
+        succeeded.succeed();
+        drop(succeeded);
+        drop(config.create("failed").unwrap());
+        assert_eq!(leftovers(root.path()), 1);
+    }
+
+    #[test]
+    fn full_disk_is_refused() {
+        let root = TempDir::new().unwrap();
+        let config =
+            WorkspaceConfig { min_free_bytes: u64::MAX, ..config(root.path(), Retention::Never) };
+        assert!(matches!(config.create("job"), Err(WorkspaceError::InsufficientSpace { .. })));
+    }
+
+    #[test]
+    fn retention_roundtrip() {
+        for retention in [Retention::Never, Retention::OnFailure, Retention::Always] {
+            assert_eq!(retention.to_string().parse::<Retention>(), Ok(retention));
+        }
+        assert!("sometimes".parse::<Retention>().is_err());
+    }
+}
@@ -3,23 +3,32 @@ use async_process::Stdio;
 use futures::Future;
 use starknet::signers::SigningKey;
 use std::path::PathBuf;
-use std::{io::Read, pin::Pin};
-use tempfile::NamedTempFile;
-use tokio::{process::Command, select, sync::mpsc};
+use std::{fs, pin::Pin};
+use tokio::{process::Command, sync::mpsc};
 use tracing::debug;
-use zetina_common::job::JobData;
+use zetina_common::job::{JobData, DEFAULT_JOB_TTL};
 use zetina_common::layout::Layout;
-use zetina_common::{job::Job, process::Process};
+use zetina_common::{
+    job::Job,
+    process::{supervise_child, Process, ProcessLimits},
+    workspace::WorkspaceConfig,
+};
 
 pub mod tests;
 
 pub struct CairoCompiler<'identity> {
     signing_key: &'identity SigningKey,
+    workspaces: WorkspaceConfig,
 }
 
 impl<'identity> CairoCompiler<'identity> {
     pub fn new(signing_key: &'identity SigningKey) -> Self {
-        Self { signing_key }
+        Self { signing_key, workspaces: WorkspaceConfig::default() }
+    }
+
+    pub fn with_workspaces(mut self, workspaces: WorkspaceConfig) -> Self {
+        self.workspaces = workspaces;
+        self
     }
 }
 
@@ -30,78 +39,53 @@ impl<'identity> CompilerController for CairoCompiler<'identity> {
         program_input_path: PathBuf,
     ) -> Result<Process<Result<Job, CompilerControllerError>>, CompilerControllerError> {
         let (terminate_tx, mut terminate_rx) = mpsc::channel::<()>(10);
+        let timeout_tx = terminate_tx.to_owned();
         let future: Pin<
             Box<dyn Future<Output = Result<Job, CompilerControllerError>> + Send + '_>,
         > = Box::pin(async move {
             let layout: &str = Layout::RecursiveWithPoseidon.into();
+            let limits = ProcessLimits::default();
 
-            let output = NamedTempFile::new()?;
+            let name = program_path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("program");
+            let mut workspace = self.workspaces.create(name)?;
+            let output = workspace.file("compiled.json");
 
-            let mut task = Command::new("cairo-compile")
+            let mut command = Command::new("cairo-compile");
+            command
                 .arg(program_path.as_path())
                 .arg("--output")
-                .arg(output.path())
+                .arg(&output)
                 .arg("--proof_mode")
-                .stdout(Stdio::null())
-                .spawn()?;
+                .stdout(Stdio::null());
 
             debug!("program {:?} is compiling... ", program_path);
+            supervise_child(command, &limits, timeout_tx.to_owned(), &mut terminate_rx).await?;
 
-            loop {
-                select! {
-                    output = task.wait() => {
-                        debug!("{:?}", output);
-                        if !output?.success() {
-                            return Err(CompilerControllerError::TaskTerminated);
-                        }
-                        let output = task.wait_with_output().await?;
-                        debug!("{:?}", output);
-                        break;
-                    }
-                    Some(()) = terminate_rx.recv() => {
-                        task.start_kill()?;
-                    }
-                }
-            }
+            let cairo_pie = workspace.file("cairo_pie.zip");
 
-            let mut cairo_pie = NamedTempFile::new()?;
-
-            let mut task = Command::new("cairo-run")
+            let mut command = Command::new("cairo-run");
+            command
                 .arg("--program")
-                .arg(output.path())
+                .arg(&output)
                 .arg("--layout")
                 .arg(layout)
                 .arg("--program_input")
                 .arg(program_input_path)
                 .arg("--cairo_pie_output")
-                .arg(cairo_pie.path())
+                .arg(&cairo_pie)
                 .arg("--print_output")
-                .stdout(Stdio::null())
-                .spawn()?;
+                .stdout(Stdio::null());
 
             debug!("program {:?} is generating PIE... ", program_path);
+            supervise_child(command, &limits, timeout_tx.to_owned(), &mut terminate_rx).await?;
 
-            loop {
-                select! {
-                    output = task.wait() => {
-                        debug!("{:?}", output);
-                        if !output?.success() {
-                            return Err(CompilerControllerError::TaskTerminated);
-                        }
-                        let output = task.wait_with_output().await?;
-                        debug!("{:?}", output);
-                        break;
-                    }
-                    Some(()) = terminate_rx.recv() => {
-                        task.start_kill()?;
-                    }
-                }
-            }
-
-            let mut cairo_pie_compressed = Vec::new();
-            cairo_pie.read_to_end(&mut cairo_pie_compressed)?;
+            let cairo_pie_compressed = fs::read(&cairo_pie)?;
+            workspace.succeed();
 
-            Ok(Job::try_from_job_data(JobData::new(cairo_pie_compressed), self.signing_key))
+            Ok(Job::try_from_job_data(
+                JobData::with_ttl(cairo_pie_compressed, DEFAULT_JOB_TTL),
+                self.signing_key,
+            )?)
         });
 
         Ok(Process::new(future, terminate_tx))
@@ -1,19 +1,50 @@
+use std::{io, time::Duration};
 use thiserror::Error;
+use zetina_common::job::JobError;
+use zetina_common::process::{ChildError, ChildFailure};
+use zetina_common::workspace::WorkspaceError;
 
 #[derive(Error, Debug)]
 pub enum CompilerControllerError {
     #[error("task not found")]
     TaskNotFound,
 
-    #[error("task not found")]
+    #[error("task aborted")]
     TaskTerminated,
 
-    #[error("io")]
-    Io(#[from] std::io::Error),
+    #[error("compilation timed out after {0:?}: {1}")]
+    Timeout(Duration, ChildFailure),
+
+    #[error("compilation exceeded its {0}: {1}")]
+    ResourceExceeded(String, ChildFailure),
+
+    #[error("compilation failed: {0}")]
+    Failed(ChildFailure),
+
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
 
-    #[error("serde")]
+    #[error("workspace error: {0}")]
+    Workspace(#[from] WorkspaceError),
+
+    #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
 
-    #[error("proof parsing error")]
+    #[error("proof parsing error: {0}")]
     ProofParseError(String),
+
+    #[error("job error: {0}")]
+    Job(#[from] JobError),
+}
+
+impl From<ChildError> for CompilerControllerError {
+    fn from(err: ChildError) -> Self {
+        match err {
+            ChildError::Io(err) => Self::Io(err),
+            ChildError::Aborted => Self::TaskTerminated,
+            ChildError::Timeout(timeout, failure) => Self::Timeout(timeout, failure),
+            ChildError::ResourceExceeded(limit, failure) => Self::ResourceExceeded(limit, failure),
+            ChildError::Failed(failure) => Self::Failed(failure),
+        }
+    }
 }
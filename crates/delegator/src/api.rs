@@ -12,7 +12,10 @@ use std::hash::{DefaultHasher, Hash, Hasher};
 use std::{io, time::Duration};
 use tokio::sync::{broadcast, mpsc};
 use tokio_stream::Stream;
-use zetina_common::{hash, job::JobData};
+use zetina_common::{
+    hash,
+    job::{JobData, DEFAULT_JOB_TTL, MAX_JOB_TTL, MAX_PIE_SIZE},
+};
 
 use crate::delegator::DelegatorEvent;
 
@@ -35,6 +38,7 @@ pub async fn health_check_handler() -> impl IntoResponse {
 #[derive(Debug, Deserialize)]
 pub struct DelegateRequest {
     pie: Vec<u8>,
+    ttl: Option<u64>, // Seconds within which the proof is needed
 }
 
 #[derive(Debug, Serialize)]
@@ -46,7 +50,18 @@ pub async fn deletage_handler(
     State(state): State<ServerState>,
     Json(input): Json<DelegateRequest>,
 ) -> Result<Json<DelegateResponse>, StatusCode> {
-    let job_data = JobData::new(input.pie);
+    let ttl = input.ttl.map(Duration::from_secs).unwrap_or(DEFAULT_JOB_TTL);
+    if ttl > MAX_JOB_TTL {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Decompressing a large PIE takes a while, keep it off the async workers.
+    let pie = tokio::task::spawn_blocking(move || {
+        JobData::decompress_cairo_pie(&input.pie, MAX_PIE_SIZE).map(|_| input.pie)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let job_data = JobData::with_ttl(pie, ttl);
     let job_data_hash = kad::RecordKey::new(&hash!(job_data).to_be_bytes());
     state.delegate_tx.send(job_data).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(Json(DelegateResponse { job_key: hex::encode(job_data_hash) }))
@@ -64,6 +79,10 @@ pub enum JobEventsResponse {
     BidReceived(String),
     Delegated(String),
     Finished(Vec<u8>),
+    Rejected(String),
+    Returned(String),
+    Expired,
+    Failed(String),
 }
 
 pub async fn job_events_handler(
@@ -86,6 +105,10 @@ pub async fn job_events_handler(
                                     DelegatorEvent::BidReceived(peer_id) => { JobEventsResponse::BidReceived(peer_id.to_base58()) },
                                     DelegatorEvent::Delegated(peer_id) => { JobEventsResponse::Delegated(peer_id.to_base58()) },
                                     DelegatorEvent::Finished(data) => { JobEventsResponse::Finished(data) },
+                                    DelegatorEvent::Rejected(peer_id, reason) => { JobEventsResponse::Rejected(format!("{}: {}", peer_id.to_base58(), reason)) },
+                                    DelegatorEvent::Returned(peer_id, reason) => { JobEventsResponse::Returned(format!("{}: {}", peer_id.to_base58(), reason)) },
+                                    DelegatorEvent::Expired => { JobEventsResponse::Expired },
+                                    DelegatorEvent::Failed(reason) => { JobEventsResponse::Failed(reason) },
                                 }
                             )
                             .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()));
@@ -1,23 +1,28 @@
-use crate::bid_queue::{BidControllerError, BidQueue};
+use crate::job_state::{JobAction, JobInput, JobState, BIDDING_WINDOW};
+use futures::future::BoxFuture;
 use futures::stream::FuturesUnordered;
-use futures::Stream;
-use libp2p::{gossipsub, kad, PeerId};
+use libp2p::{kad, PeerId};
 use starknet::signers::SigningKey;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{HashMap, VecDeque};
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::pin::Pin;
+use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::{broadcast, mpsc};
+use tokio::time::sleep;
 use tokio::{sync::mpsc::Sender, task::JoinHandle};
 use tokio_stream::StreamExt;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+use zetina_common::capability::{CapabilityStore, JobRequirements};
 use zetina_common::graceful_shutdown::shutdown_signal;
 use zetina_common::hash;
-use zetina_common::job::{Job, JobBid, JobData};
+use zetina_common::job::{Job, JobBid, JobData, JobError, MAX_PIE_SIZE};
 use zetina_common::job_witness::JobWitness;
-use zetina_common::process::Process;
 use zetina_peer::{
-    DelegationMessage, GossipsubMessage, KademliaMessage, MarketMessage, PeerBehaviourEvent, Topic,
+    blob::{BlobClient, BlobError, BlobHash, MAX_BLOB_SIZE},
+    event::{PeerEvent, PeerEvents},
+    query::{KademliaClient, QueryError},
+    wire::{self, WireError, WireMessage},
+    DelegationMessage, GossipsubMessage, MarketMessage, NetworkingMessage, Topic, MAX_RECORD_SIZE,
 };
 
 pub struct Delegator {
@@ -26,121 +31,107 @@ pub struct Delegator {
 
 impl Delegator {
     pub fn new(
-        mut swarm_events: Pin<Box<dyn Stream<Item = PeerBehaviourEvent> + Send>>,
+        mut swarm_events: PeerEvents,
         gossipsub_tx: Sender<GossipsubMessage>,
-        kademlia_tx: Sender<KademliaMessage>,
+        kademlia: KademliaClient,
+        blobs: BlobClient,
         mut delegate_rx: mpsc::Receiver<JobData>,
         events_tx: broadcast::Sender<(kad::RecordKey, DelegatorEvent)>,
         signing_key: SigningKey,
     ) -> Self {
         Self {
             handle: Some(tokio::spawn(async move {
-                let mut job_bid_scheduler = FuturesUnordered::<
-                    Process<
-                        Result<(kad::RecordKey, BTreeMap<u64, Vec<PeerId>>), BidControllerError>,
-                    >,
-                >::new();
-                let mut job_hash_store =
-                    HashMap::<kad::RecordKey, mpsc::Sender<(u64, PeerId)>>::new();
-                let mut proof_hash_store = HashMap::<kad::RecordKey, kad::RecordKey>::new();
+                let mut driver = Driver {
+                    gossipsub_tx,
+                    kademlia,
+                    blobs,
+                    events_tx,
+                    jobs: HashMap::new(),
+                    deadlines: HashMap::new(),
+                    scheduler: FuturesUnordered::new(),
+                };
+                let mut capability_store = CapabilityStore::new();
+                let mut pending_bids = PendingBids::default();
+                // Jobs being signed on the blocking pool, so a large PIE does not hold up the bids of other jobs.
+                let mut signing =
+                    FuturesUnordered::<JoinHandle<Result<(Job, JobRequirements), JobError>>>::new();
 
                 loop {
                     tokio::select! {
                         Some(job_data) = delegate_rx.recv() => {
-                            let job = Job::try_from_job_data(job_data, &signing_key);
-                            let job_key = kad::RecordKey::new(&hash!(job).to_be_bytes());
-                            kademlia_tx.send(KademliaMessage::PUT(
-                                (job_key, serde_json::to_vec(&job)?)
-                            )).await?;
+                            let signing_key = signing_key.to_owned();
+                            signing.push(tokio::task::spawn_blocking(move || {
+                                let pie = JobData::decompress_cairo_pie(&job_data.cairo_pie_compressed, MAX_PIE_SIZE)?;
+                                let requirements = JobRequirements::from(&pie);
+                                Ok((Job::sign(job_data, &pie, &signing_key)?, requirements))
+                            }));
+                        },
+                        Some(signed) = signing.next() => {
+                            match signed {
+                                Ok(Ok((job, requirements))) => driver.submit(job, requirements),
+                                Ok(Err(err)) => error!("Failed to create job: {err}"),
+                                Err(err) => error!("Failed to create job: {err}"),
+                            }
                         },
                         Some(event) = swarm_events.next() => {
                             match event {
-                                PeerBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. }) => {
-                                    if message.topic == Topic::Market.into() {
-                                        match serde_json::from_slice::<MarketMessage>(&message.data)? {
-                                            MarketMessage::JobBid(job_bid) => {
-                                                if let Some(bid_tx) =  job_hash_store.get_mut(&job_bid.job_key) {
-                                                    info!("Received job bid: {} price: {} from: {}", hex::encode(&job_bid.job_key), job_bid.price, job_bid.identity);
-                                                    bid_tx.send((job_bid.price, job_bid.identity)).await?;
-                                                    events_tx.send((job_bid.job_key, DelegatorEvent::BidReceived(job_bid.identity)))?;
-                                                }
+                                PeerEvent::BidReceived { source, bid } => {
+                                    let bidding = driver.jobs.get(&bid.job_key).and_then(JobState::requirements).is_some();
+                                    if source != bid.identity || !bidding {
+                                        continue;
+                                    }
+                                    if capability_store.get(&bid.identity).is_none() {
+                                        // The bid waits for the capabilities of an executor that joined after its last advertisement.
+                                        if pending_bids.push(bid.to_owned()) {
+                                            let request = NetworkingMessage::CapabilitiesRequest(bid.identity);
+                                            if let Err(err) = driver.gossip(Topic::Networking, &request).await {
+                                                error!("Failed to request capabilities of {}: {err}", bid.identity);
                                             }
-                                            _ => {}
                                         }
+                                        continue;
                                     }
-                                    if message.topic == Topic::Delegation.into() {
-                                        match serde_json::from_slice::<DelegationMessage>(&message.data)? {
-                                            DelegationMessage::Finished(proof_key, job_key) => {
-                                                if job_hash_store.remove(&job_key).is_some() {
-                                                    info!("Received finished job: {} proof key: {}", hex::encode(&job_key), hex::encode(&proof_key));
-                                                    proof_hash_store.insert(proof_key.to_owned(), job_key);
-                                                    kademlia_tx.send(KademliaMessage::GET(proof_key)).await?;
-                                                }
-                                            }
-                                            _ => {}
+                                    driver.bid(&capability_store, bid).await;
+                                }
+                                PeerEvent::Capabilities { source, capabilities } => {
+                                    if source == capabilities.identity {
+                                        let identity = capabilities.identity;
+                                        capability_store.insert(capabilities);
+                                        for bid in pending_bids.take(&identity) {
+                                            driver.bid(&capability_store, bid).await;
                                         }
                                     }
-                                },
-                                PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, ..}) => {
-                                    match result {
-                                        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
-                                            gossipsub_tx.send(GossipsubMessage {
-                                                topic: Topic::Market.into(),
-                                                data: serde_json::to_vec(&MarketMessage::JobBidPropagation(key.to_owned()))?
-                                            }).await?;
-                                            info!("Propagated job: {} for bidding", hex::encode(&key));
-                                            let (process, bid_tx) = BidQueue::run(key.to_owned());
-                                            job_bid_scheduler.push(process);
-                                            job_hash_store.insert(key.to_owned(), bid_tx);
-                                            events_tx.send((key, DelegatorEvent::Propagated))?;
-                                        },
-                                        kad::QueryResult::GetRecord(Ok(
-                                            kad::GetRecordOk::FoundRecord(kad::PeerRecord {
-                                                record: kad::Record { key, value, .. },
-                                                ..
-                                            })
-                                        )) => {
-                                            if let Some ((proof_key, job_key)) = proof_hash_store.remove_entry(&key) {
-                                                info!("job {} proof with key: {} returned in DHT", hex::encode(&job_key), hex::encode(&proof_key));
-                                                let job_witness: JobWitness = serde_json::from_slice(&value)?;
-                                                events_tx.send((job_key, DelegatorEvent::Finished(job_witness.proof)))?;
-                                            }
-                                        },
-                                        _ => {}
+                                }
+                                PeerEvent::JobFinished { source, job_key, proof_key } => {
+                                    if driver.jobs.contains_key(&job_key) {
+                                        info!("Received finished job: {} proof key: {} from: {}", hex::encode(&job_key), hex::encode(&proof_key), source);
+                                        driver.feed(job_key, JobInput::ProofAnnounced { identity: source, proof_key }).await;
                                     }
                                 }
-                                _ => {}
-                            }
-                        }
-                        Some(Ok((job_key, mut bids))) = job_bid_scheduler.next() => {
-                            if let Some((price, identities)) = bids.pop_first() {
-                                if identities.is_empty() {
-                                    warn!("Job {} did not receive any bids", hex::encode(&job_key));
-                                } else {
-                                    for identity in identities {
-                                        let result = Box::pin(async {
-                                            gossipsub_tx.send(GossipsubMessage {
-                                                topic: Topic::Delegation.into(),
-                                                data: serde_json::to_vec(&DelegationMessage::Delegate(JobBid {
-                                                    identity,
-                                                    job_key: job_key.clone(),
-                                                    price,
-                                                }))?,
-                                            }).await?;
-
-                                            events_tx.send((job_key.clone(), DelegatorEvent::Delegated(identity)))?;
-                                            info!("Job {} delegated to best bidder: {}", hex::encode(&job_key), &identity);
-                                            Ok::<(), Error>(())
-                                        }).await;
-
-                                        match result {
-                                            Ok(_) => break,  // Break after successful delegation
-                                            Err(err) => error!(?err, "Failed to delegate job {}", hex::encode(&job_key)),
-                                        }
+                                PeerEvent::Departed { source, identity } => {
+                                    if source == identity {
+                                        info!("Executor {identity} left the network");
+                                        capability_store.remove(&identity);
+                                        pending_bids.take(&identity);
+                                    }
+                                }
+                                PeerEvent::JobReturned { source, rejection } => {
+                                    if source == rejection.identity && driver.jobs.contains_key(&rejection.job_key) {
+                                        warn!("Job {} returned by {}: {}", hex::encode(&rejection.job_key), rejection.identity, rejection.reason);
+                                        driver.feed(rejection.job_key, JobInput::Returned { identity: rejection.identity, reason: rejection.reason }).await;
                                     }
                                 }
+                                PeerEvent::JobRejected { source, rejection } => {
+                                    if source == rejection.identity && driver.jobs.contains_key(&rejection.job_key) {
+                                        warn!("Job {} rejected by {}: {}", hex::encode(&rejection.job_key), rejection.identity, rejection.reason);
+                                        driver.feed(rejection.job_key, JobInput::Rejected { identity: rejection.identity, reason: rejection.reason }).await;
+                                    }
+                                }
+                                _ => {}
                             }
                         }
+                        Some((job_key, input)) = driver.scheduler.next() => {
+                            driver.feed(job_key, input).await;
+                        }
                         _ = shutdown_signal() => {
                             break
                         }
@@ -158,39 +149,269 @@ impl Drop for Delegator {
         let handle = self.handle.take();
         tokio::spawn(async move {
             if let Some(handle) = handle {
-                handle.await.unwrap().unwrap();
+                match handle.await {
+                    Ok(Err(err)) => error!("Delegator stopped: {err}"),
+                    Err(err) if err.is_panic() => error!("Delegator panicked: {err}"),
+                    _ => {}
+                }
             }
         });
     }
 }
 
-#[derive(Debug, Clone)]
+/*
+    Job Driver
+    Feeds inputs into the state machines of the jobs in flight and performs the actions of every transition.
+    Network work runs in the scheduler and comes back as an input for its job, an action that fails
+    fails its own job only, so one bad job never takes the delegator down.
+*/
+struct Driver {
+    gossipsub_tx: Sender<GossipsubMessage>,
+    kademlia: KademliaClient,
+    blobs: BlobClient,
+    events_tx: broadcast::Sender<(kad::RecordKey, DelegatorEvent)>,
+    jobs: HashMap<kad::RecordKey, JobState>,
+    // When the jobs in flight expire, their job and proof blobs are kept until then.
+    deadlines: HashMap<kad::RecordKey, Instant>,
+    scheduler: FuturesUnordered<BoxFuture<'static, (kad::RecordKey, JobInput)>>,
+}
+
+impl Driver {
+    fn submit(&mut self, job: Job, requirements: JobRequirements) {
+        let job_key = kad::RecordKey::new(&hash!(job).to_be_bytes());
+        if self.jobs.contains_key(&job_key) {
+            warn!("Job {} is already in flight", hex::encode(&job_key));
+            return;
+        }
+        self.jobs.insert(job_key.to_owned(), JobState::Submitted { requirements });
+        self.deadlines.insert(job_key.to_owned(), job.job_data.expires_at());
+
+        let time_left = job.job_data.time_left().unwrap_or_default();
+        let (kademlia, blobs) = (self.kademlia.to_owned(), self.blobs.to_owned());
+        let key = job_key.to_owned();
+        self.scheduler.push(Box::pin(async move {
+            let result = publish_job(&kademlia, &blobs, key.to_owned(), &job).await;
+            (key, JobInput::Published(result.map_err(|err| err.to_string())))
+        }));
+        self.scheduler.push(Box::pin(async move {
+            sleep(time_left).await;
+            (job_key, JobInput::Expired)
+        }));
+    }
+
+    async fn feed(&mut self, job_key: kad::RecordKey, input: JobInput) {
+        let mut inputs = VecDeque::from([input]);
+        while let Some(input) = inputs.pop_front() {
+            let Some(state) = self.jobs.get(&job_key) else {
+                debug!("Ignoring input for unknown job {}", hex::encode(&job_key));
+                return;
+            };
+            let Some((state, actions)) = state.next(input) else {
+                debug!("Ignoring input for job {} in state {state:?}", hex::encode(&job_key));
+                continue;
+            };
+            if state.is_terminal() {
+                self.jobs.remove(&job_key);
+                self.deadlines.remove(&job_key);
+            } else {
+                self.jobs.insert(job_key.to_owned(), state);
+            }
+
+            for action in actions {
+                match self.perform(&job_key, action).await {
+                    Ok(Some(input)) => inputs.push_back(input),
+                    Ok(None) => {}
+                    Err(err) => {
+                        error!("Job {} failed: {err}", hex::encode(&job_key));
+                        inputs.push_back(JobInput::Failed(err.to_string()));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // Performs an action of the job, returns the input it immediately leads to.
+    async fn perform(
+        &mut self,
+        job_key: &kad::RecordKey,
+        action: JobAction,
+    ) -> Result<Option<JobInput>, Error> {
+        match action {
+            JobAction::Announce(requirements) => {
+                let message = MarketMessage::JobBidPropagation(job_key.to_owned(), requirements);
+                self.gossip(Topic::Market, &message).await?;
+                info!("Propagated job: {} for bidding", hex::encode(job_key));
+            }
+            JobAction::OpenBidding => {
+                let job_key = job_key.to_owned();
+                self.scheduler.push(Box::pin(async move {
+                    sleep(BIDDING_WINDOW).await;
+                    (job_key, JobInput::BiddingClosed)
+                }));
+            }
+            JobAction::Delegate { executor, price } => {
+                let bid = JobBid { identity: executor, job_key: job_key.to_owned(), price };
+                self.gossip(Topic::Delegation, &DelegationMessage::Delegate(bid)).await?;
+                info!("Job {} delegated to best bidder: {}", hex::encode(job_key), executor);
+                return Ok(Some(JobInput::DelegationSent));
+            }
+            JobAction::FetchProof(proof_key) => {
+                let (kademlia, blobs) = (self.kademlia.to_owned(), self.blobs.to_owned());
+                let job_key = job_key.to_owned();
+                let expires = self.deadlines.get(&job_key).copied().unwrap_or_else(Instant::now);
+                self.scheduler.push(Box::pin(async move {
+                    let result =
+                        fetch_proof(&kademlia, &blobs, &job_key, proof_key.to_owned(), expires)
+                            .await;
+                    match &result {
+                        Ok(_) => info!(
+                            "job {} proof with key: {} returned in DHT",
+                            hex::encode(&job_key),
+                            hex::encode(&proof_key)
+                        ),
+                        Err(err) => {
+                            error!("Failed to fetch proof {}: {err}", hex::encode(&proof_key))
+                        }
+                    }
+                    let result = result.map(|job_witness| job_witness.proof);
+                    (job_key, JobInput::ProofFetched(result.map_err(|err| err.to_string())))
+                }));
+            }
+            JobAction::Emit(event) => {
+                // Nobody listening to the job events is not a failure of the job.
+                if self.events_tx.send((job_key.to_owned(), event)).is_err() {
+                    debug!("No subscriber for events of job {}", hex::encode(job_key));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Accepts the bid of an executor whose capabilities are known if it can handle the job.
+    async fn bid(&mut self, capability_store: &CapabilityStore, bid: JobBid) {
+        let Some(requirements) = self.jobs.get(&bid.job_key).and_then(JobState::requirements)
+        else {
+            return;
+        };
+        if !capability_store.supports(&bid.identity, requirements) {
+            info!(
+                "Ignoring bid on job {} from {}: executor cannot handle the job",
+                hex::encode(&bid.job_key),
+                bid.identity
+            );
+            return;
+        }
+        info!(
+            "Received job bid: {} price: {} from: {}",
+            hex::encode(&bid.job_key),
+            bid.price,
+            bid.identity
+        );
+        self.feed(bid.job_key, JobInput::Bid { identity: bid.identity, price: bid.price }).await;
+    }
+
+    async fn gossip<T: WireMessage>(&self, topic: Topic, message: &T) -> Result<(), Error> {
+        let data = wire::encode(message)?;
+        self.gossipsub_tx.send(GossipsubMessage { topic: topic.into(), data }).await?;
+        Ok(())
+    }
+}
+
+// Executors whose bids wait for their capabilities and bids held for each, both bounded against floods of bids.
+const MAX_PENDING_EXECUTORS: usize = 64;
+const MAX_PENDING_BIDS: usize = 16;
+
+#[derive(Default)]
+struct PendingBids {
+    bids: HashMap<PeerId, Vec<JobBid>>,
+}
+
+impl PendingBids {
+    // Holds the bid, returns whether it is the first of its executor, whose capabilities are then requested.
+    fn push(&mut self, bid: JobBid) -> bool {
+        if !self.bids.contains_key(&bid.identity) && self.bids.len() >= MAX_PENDING_EXECUTORS {
+            return false;
+        }
+        let bids = self.bids.entry(bid.identity).or_default();
+        if bids.len() < MAX_PENDING_BIDS {
+            bids.push(bid);
+        }
+        bids.len() == 1
+    }
+
+    fn take(&mut self, identity: &PeerId) -> Vec<JobBid> {
+        self.bids.remove(identity).unwrap_or_default()
+    }
+}
+
+// Stores the job as a blob and publishes its root under the job key, both are kept until the job expires.
+async fn publish_job(
+    kademlia: &KademliaClient,
+    blobs: &BlobClient,
+    job_key: kad::RecordKey,
+    job: &Job,
+) -> Result<(), Error> {
+    let root = blobs.put(wire::encode(job)?, job.job_data.expires_at()).await?;
+    info!("Stored job {} as blob {root}", hex::encode(&job_key));
+    kademlia.put_record(job_key, wire::encode(&root)?, Some(job.job_data.expires_at())).await?;
+    Ok(())
+}
+
+// Resolves the proof record to its blob root and fetches the job witness it points to.
+async fn fetch_proof(
+    kademlia: &KademliaClient,
+    blobs: &BlobClient,
+    job_key: &kad::RecordKey,
+    proof_key: kad::RecordKey,
+    expires: Instant,
+) -> Result<JobWitness, Error> {
+    let root = wire::decode::<BlobHash>(
+        &kademlia.get_record(proof_key.to_owned()).await?,
+        MAX_RECORD_SIZE,
+    )?;
+    let job_witness = wire::decode::<JobWitness>(&blobs.get(root, expires).await?, MAX_BLOB_SIZE)?;
+    // The record may be overwritten by any peer, only a proof of this job hashing to its key is accepted.
+    if kad::RecordKey::new(&hash!(job_witness).to_be_bytes()) != proof_key
+        || job_witness.job_key != *job_key
+    {
+        return Err(Error::KeyMismatch(hex::encode(proof_key)));
+    }
+    Ok(job_witness)
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum DelegatorEvent {
     Propagated,
     BidReceived(PeerId),
     Delegated(PeerId),
     Finished(Vec<u8>),
+    Rejected(PeerId, String),
+    Returned(PeerId, String),
+    Expired,
+    Failed(String),
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("mpsc_send_error GossipsubMessage")]
+    #[error("gossipsub message send error: {0}")]
     MpscSendErrorGossipsubMessage(#[from] mpsc::error::SendError<GossipsubMessage>),
 
-    #[error("mpsc_send_error KademliaMessage")]
-    MpscSendErrorKademliaMessage(#[from] mpsc::error::SendError<KademliaMessage>),
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("serde error: {0}")]
+    Serde(#[from] serde_json::Error),
 
-    #[error("mpsc_send_error DelegatorEvent")]
-    BreadcastSendErrorDelegatorEvent(
-        #[from] broadcast::error::SendError<(kad::RecordKey, DelegatorEvent)>,
-    ),
+    #[error("wire error: {0}")]
+    Wire(#[from] WireError),
 
-    #[error("mpsc_send_error JobBid")]
-    MpscSendErrorJobBid(#[from] mpsc::error::SendError<(u64, PeerId)>),
+    #[error("blob error: {0}")]
+    Blob(#[from] BlobError),
 
-    #[error("io")]
-    Io(#[from] std::io::Error),
+    #[error("query error: {0}")]
+    Query(#[from] QueryError),
 
-    #[error("serde")]
-    Serde(#[from] serde_json::Error),
+    #[error("fetched content does not match key {0}")]
+    KeyMismatch(String),
 }
@@ -0,0 +1,390 @@
+use crate::delegator::DelegatorEvent;
+use libp2p::{kad, PeerId};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use zetina_common::capability::JobRequirements;
+
+/*
+    Delegator Job State
+    Lifecycle of a job submitted by this delegator: Submitted → Propagated → Bidding → Delegated → Proving → Finished/Failed.
+    A job handed back by a departing executor goes back to Propagated and is bid on again.
+    Transitions are pure, the current state and an input give the next state and the actions the driver performs,
+    inputs that do not apply to the current state like late bids or repeated messages give no transition at all.
+    Rejections and expiry end the job as failed, every job reports exactly one terminal event.
+*/
+
+// How long bids are collected once the job is propagated.
+pub const BIDDING_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobState {
+    // Job is being stored in the DHT.
+    Submitted { requirements: JobRequirements },
+    // Job is announced to executors, no bid arrived yet.
+    Propagated { requirements: JobRequirements },
+    // Bids by price, collected until the bidding window closes.
+    Bidding { requirements: JobRequirements, bids: BTreeMap<u64, Vec<PeerId>> },
+    // Job is being handed to the best bidder.
+    Delegated { requirements: JobRequirements, executor: PeerId, price: u64 },
+    // Executor works on the job, the proof key is set once it announced the proof.
+    Proving { requirements: JobRequirements, executor: PeerId, proof_key: Option<kad::RecordKey> },
+    Finished,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobInput {
+    Published(Result<(), String>),
+    Bid { identity: PeerId, price: u64 },
+    BiddingClosed,
+    DelegationSent,
+    ProofAnnounced { identity: PeerId, proof_key: kad::RecordKey },
+    ProofFetched(Result<Vec<u8>, String>),
+    Rejected { identity: PeerId, reason: String },
+    Returned { identity: PeerId, reason: String },
+    Expired,
+    // An action of the job failed in the driver.
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobAction {
+    Announce(JobRequirements),
+    OpenBidding,
+    Delegate { executor: PeerId, price: u64 },
+    FetchProof(kad::RecordKey),
+    Emit(DelegatorEvent),
+}
+
+impl JobState {
+    pub fn next(&self, input: JobInput) -> Option<(JobState, Vec<JobAction>)> {
+        use JobAction::*;
+        use JobInput as Input;
+
+        let next = match (self, input) {
+            (Self::Submitted { requirements }, Input::Published(Ok(()))) => (
+                Self::Propagated { requirements: requirements.to_owned() },
+                vec![
+                    Announce(requirements.to_owned()),
+                    OpenBidding,
+                    Emit(DelegatorEvent::Propagated),
+                ],
+            ),
+            (Self::Submitted { .. }, Input::Published(Err(reason))) => {
+                failed(format!("failed to publish job: {reason}"))
+            }
+            (Self::Propagated { requirements }, Input::Bid { identity, price }) => (
+                Self::Bidding {
+                    requirements: requirements.to_owned(),
+                    bids: BTreeMap::from([(price, vec![identity])]),
+                },
+                vec![Emit(DelegatorEvent::BidReceived(identity))],
+            ),
+            (Self::Bidding { requirements, bids }, Input::Bid { identity, price }) => {
+                if bids.values().flatten().any(|bidder| *bidder == identity) {
+                    return None;
+                }
+                let mut bids = bids.to_owned();
+                bids.entry(price).or_default().push(identity);
+                (
+                    Self::Bidding { requirements: requirements.to_owned(), bids },
+                    vec![Emit(DelegatorEvent::BidReceived(identity))],
+                )
+            }
+            (Self::Propagated { .. }, Input::BiddingClosed) => {
+                failed("no executor bid on the job".to_string())
+            }
+            (Self::Bidding { requirements, bids }, Input::BiddingClosed) => {
+                let (price, bidders) = bids.first_key_value()?;
+                let executor = *bidders.first()?;
+                (
+                    Self::Delegated {
+                        requirements: requirements.to_owned(),
+                        executor,
+                        price: *price,
+                    },
+                    vec![Delegate { executor, price: *price }],
+                )
+            }
+            (Self::Delegated { requirements, executor, .. }, Input::DelegationSent) => (
+                Self::Proving {
+                    requirements: requirements.to_owned(),
+                    executor: *executor,
+                    proof_key: None,
+                },
+                vec![Emit(DelegatorEvent::Delegated(*executor))],
+            ),
+            (
+                Self::Proving { requirements, executor, proof_key: None },
+                Input::ProofAnnounced { identity, proof_key },
+            ) if *executor == identity => (
+                Self::Proving {
+                    requirements: requirements.to_owned(),
+                    executor: *executor,
+                    proof_key: Some(proof_key.to_owned()),
+                },
+                vec![FetchProof(proof_key)],
+            ),
+            (Self::Proving { proof_key: Some(_), .. }, Input::ProofFetched(Ok(proof))) => {
+                (Self::Finished, vec![Emit(DelegatorEvent::Finished(proof))])
+            }
+            (Self::Proving { proof_key: Some(_), .. }, Input::ProofFetched(Err(reason))) => {
+                failed(format!("failed to fetch proof: {reason}"))
+            }
+            (
+                Self::Delegated { executor, .. } | Self::Proving { executor, proof_key: None, .. },
+                Input::Rejected { identity, reason },
+            ) if *executor == identity => (
+                Self::Failed(reason.to_owned()),
+                vec![Emit(DelegatorEvent::Rejected(identity, reason))],
+            ),
+            (
+                Self::Delegated { requirements, executor, .. }
+                | Self::Proving { requirements, executor, proof_key: None },
+                Input::Returned { identity, reason },
+            ) if *executor == identity => (
+                Self::Propagated { requirements: requirements.to_owned() },
+                vec![
+                    Announce(requirements.to_owned()),
+                    OpenBidding,
+                    Emit(DelegatorEvent::Returned(identity, reason)),
+                ],
+            ),
+            // Once the proof is announced the job no longer depends on its deadline.
+            (Self::Proving { proof_key: Some(_), .. }, Input::Expired) => return None,
+            (state, Input::Expired) if !state.is_terminal() => {
+                (Self::Failed("job expired".to_string()), vec![Emit(DelegatorEvent::Expired)])
+            }
+            (state, Input::Failed(reason)) if !state.is_terminal() => failed(reason),
+            _ => return None,
+        };
+        Some(next)
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Finished | Self::Failed(_))
+    }
+
+    // Requirements executors have to meet to bid, while the job accepts bids.
+    pub fn requirements(&self) -> Option<&JobRequirements> {
+        match self {
+            Self::Propagated { requirements } | Self::Bidding { requirements, .. } => {
+                Some(requirements)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn failed(reason: String) -> (JobState, Vec<JobAction>) {
+    (JobState::Failed(reason.to_owned()), vec![JobAction::Emit(DelegatorEvent::Failed(reason))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirements() -> JobRequirements {
+        JobRequirements { steps: 10, memory_cells: 10, builtins: vec!["output".to_string()] }
+    }
+
+    fn key(byte: u8) -> kad::RecordKey {
+        kad::RecordKey::new(&[byte])
+    }
+
+    fn bidding(bids: &[(u64, PeerId)]) -> JobState {
+        let mut map = BTreeMap::<u64, Vec<PeerId>>::new();
+        for (price, identity) in bids {
+            map.entry(*price).or_default().push(*identity);
+        }
+        JobState::Bidding { requirements: requirements(), bids: map }
+    }
+
+    fn delegated(executor: PeerId, price: u64) -> JobState {
+        JobState::Delegated { requirements: requirements(), executor, price }
+    }
+
+    fn proving(executor: PeerId, proof_key: Option<kad::RecordKey>) -> JobState {
+        JobState::Proving { requirements: requirements(), executor, proof_key }
+    }
+
+    #[test]
+    fn published_job_is_propagated() {
+        let state = JobState::Submitted { requirements: requirements() };
+        let (state, actions) = state.next(JobInput::Published(Ok(()))).unwrap();
+        assert_eq!(state, JobState::Propagated { requirements: requirements() });
+        assert_eq!(
+            actions,
+            vec![
+                JobAction::Announce(requirements()),
+                JobAction::OpenBidding,
+                JobAction::Emit(DelegatorEvent::Propagated)
+            ]
+        );
+    }
+
+    #[test]
+    fn unpublished_job_fails() {
+        let state = JobState::Submitted { requirements: requirements() };
+        let (state, actions) = state.next(JobInput::Published(Err("timeout".to_string()))).unwrap();
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Emit(DelegatorEvent::Failed(_))]));
+    }
+
+    #[test]
+    fn first_bid_starts_bidding() {
+        let identity = PeerId::random();
+        let state = JobState::Propagated { requirements: requirements() };
+        let (state, actions) = state.next(JobInput::Bid { identity, price: 3 }).unwrap();
+        assert_eq!(state, bidding(&[(3, identity)]));
+        assert_eq!(actions, vec![JobAction::Emit(DelegatorEvent::BidReceived(identity))]);
+    }
+
+    #[test]
+    fn bids_are_collected_once_per_bidder() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let state = bidding(&[(3, first)]);
+        let (state, _) = state.next(JobInput::Bid { identity: second, price: 3 }).unwrap();
+        assert_eq!(state, bidding(&[(3, first), (3, second)]));
+        assert_eq!(state.next(JobInput::Bid { identity: first, price: 1 }), None);
+    }
+
+    #[test]
+    fn closed_bidding_delegates_to_cheapest_bidder() {
+        let (expensive, cheap) = (PeerId::random(), PeerId::random());
+        let state = bidding(&[(5, expensive), (2, cheap)]);
+        let (state, actions) = state.next(JobInput::BiddingClosed).unwrap();
+        assert_eq!(state, delegated(cheap, 2));
+        assert_eq!(actions, vec![JobAction::Delegate { executor: cheap, price: 2 }]);
+    }
+
+    #[test]
+    fn closed_bidding_without_bids_fails() {
+        let state = JobState::Propagated { requirements: requirements() };
+        let (state, actions) = state.next(JobInput::BiddingClosed).unwrap();
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Emit(DelegatorEvent::Failed(_))]));
+    }
+
+    #[test]
+    fn late_bids_are_ignored() {
+        let identity = PeerId::random();
+        let state = delegated(PeerId::random(), 1);
+        assert_eq!(state.next(JobInput::Bid { identity, price: 0 }), None);
+        assert_eq!(state.requirements(), None);
+    }
+
+    #[test]
+    fn sent_delegation_starts_proving() {
+        let executor = PeerId::random();
+        let state = delegated(executor, 1);
+        let (state, actions) = state.next(JobInput::DelegationSent).unwrap();
+        assert_eq!(state, proving(executor, None));
+        assert_eq!(actions, vec![JobAction::Emit(DelegatorEvent::Delegated(executor))]);
+    }
+
+    #[test]
+    fn announced_proof_is_fetched_once() {
+        let executor = PeerId::random();
+        let announced = JobInput::ProofAnnounced { identity: executor, proof_key: key(1) };
+        let (state, actions) = proving(executor, None).next(announced.to_owned()).unwrap();
+        assert_eq!(state, proving(executor, Some(key(1))));
+        assert_eq!(actions, vec![JobAction::FetchProof(key(1))]);
+        assert_eq!(state.next(announced), None);
+    }
+
+    #[test]
+    fn proof_announced_by_other_peer_is_ignored() {
+        let input = JobInput::ProofAnnounced { identity: PeerId::random(), proof_key: key(1) };
+        assert_eq!(proving(PeerId::random(), None).next(input.to_owned()), None);
+        assert_eq!(delegated(PeerId::random(), 1).next(input), None);
+    }
+
+    #[test]
+    fn fetched_proof_finishes_job() {
+        let state = proving(PeerId::random(), Some(key(1)));
+        let (state, actions) = state.next(JobInput::ProofFetched(Ok(vec![7]))).unwrap();
+        assert_eq!(state, JobState::Finished);
+        assert_eq!(actions, vec![JobAction::Emit(DelegatorEvent::Finished(vec![7]))]);
+        assert_eq!(state.next(JobInput::ProofFetched(Ok(vec![7]))), None);
+    }
+
+    #[test]
+    fn unfetched_proof_fails_job() {
+        let state = proving(PeerId::random(), Some(key(1)));
+        let (state, _) = state.next(JobInput::ProofFetched(Err("not found".to_string()))).unwrap();
+        assert!(matches!(state, JobState::Failed(_)));
+    }
+
+    #[test]
+    fn rejection_by_executor_fails_job() {
+        let executor = PeerId::random();
+        let reason = "invalid job".to_string();
+        let (state, actions) = proving(executor, None)
+            .next(JobInput::Rejected { identity: executor, reason: reason.to_owned() })
+            .unwrap();
+        assert_eq!(state, JobState::Failed(reason.to_owned()));
+        assert_eq!(actions, vec![JobAction::Emit(DelegatorEvent::Rejected(executor, reason))]);
+    }
+
+    #[test]
+    fn returned_job_is_propagated_again() {
+        let executor = PeerId::random();
+        let input = JobInput::Returned { identity: executor, reason: "shutting down".to_string() };
+        let (state, actions) = delegated(executor, 1).next(input).unwrap();
+        assert_eq!(state, JobState::Propagated { requirements: requirements() });
+        assert!(matches!(
+            actions[..],
+            [
+                JobAction::Announce(_),
+                JobAction::OpenBidding,
+                JobAction::Emit(DelegatorEvent::Returned(peer, _))
+            ] if peer == executor
+        ));
+    }
+
+    #[test]
+    fn rejection_by_other_peer_is_ignored() {
+        let input = JobInput::Rejected { identity: PeerId::random(), reason: String::new() };
+        assert_eq!(proving(PeerId::random(), None).next(input), None);
+    }
+
+    #[test]
+    fn expiry_fails_unfinished_job() {
+        let states = [
+            JobState::Submitted { requirements: requirements() },
+            JobState::Propagated { requirements: requirements() },
+            bidding(&[(1, PeerId::random())]),
+            delegated(PeerId::random(), 1),
+            proving(PeerId::random(), None),
+        ];
+        for state in states {
+            let (state, actions) = state.next(JobInput::Expired).unwrap();
+            assert!(matches!(state, JobState::Failed(_)));
+            assert_eq!(actions, vec![JobAction::Emit(DelegatorEvent::Expired)]);
+        }
+    }
+
+    #[test]
+    fn expiry_waits_for_announced_proof() {
+        assert_eq!(proving(PeerId::random(), Some(key(1))).next(JobInput::Expired), None);
+    }
+
+    #[test]
+    fn driver_failure_fails_job() {
+        let state = delegated(PeerId::random(), 1);
+        let (state, actions) = state.next(JobInput::Failed("channel closed".to_string())).unwrap();
+        assert_eq!(state, JobState::Failed("channel closed".to_string()));
+        assert!(matches!(actions[..], [JobAction::Emit(DelegatorEvent::Failed(_))]));
+    }
+
+    #[test]
+    fn terminal_states_ignore_inputs() {
+        for state in [JobState::Finished, JobState::Failed(String::new())] {
+            assert_eq!(state.next(JobInput::Expired), None);
+            assert_eq!(state.next(JobInput::Failed(String::new())), None);
+            let input = JobInput::ProofAnnounced { identity: PeerId::random(), proof_key: key(1) };
+            assert_eq!(state.next(input), None);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod api;
+pub mod delegator;
+pub mod job_state;
@@ -1,18 +1,12 @@
-pub mod api;
-pub mod bid_queue;
-pub mod delegator;
-
-use api::ServerState;
 use axum::{
     extract::DefaultBodyLimit,
     routing::{get, post},
     Router,
 };
 use clap::Parser;
-use delegator::{Delegator, DelegatorEvent};
-use libp2p::{kad, Multiaddr};
+use libp2p::{kad, Multiaddr, PeerId};
 use starknet::{core::types::FieldElement, signers::SigningKey};
-use std::{str::FromStr, time::Duration};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 use tokio::{
     net::TcpListener,
     sync::{broadcast, mpsc},
@@ -24,7 +18,19 @@ use tower_http::{
 };
 use tracing_subscriber::EnvFilter;
 use zetina_common::{graceful_shutdown::shutdown_signal, job::JobData};
-use zetina_peer::{GossipsubMessage, KademliaMessage, SwarmRunner};
+use zetina_delegator::{
+    api::{self, ServerState},
+    delegator::{Delegator, DelegatorEvent},
+};
+use zetina_peer::{
+    access::{read_swarm_key, AccessConfig},
+    args::{ConnectionArgs, QueryArgs},
+    blob::{BlobClient, BlobMessage, BlobStoreConfig, DEFAULT_BLOB_STORE_BYTES},
+    connection::ConnectionConfig,
+    query::{KademliaClient, QueryConfig},
+    store::{DiskStoreConfig, StoreConfig},
+    GossipsubMessage, KademliaMessage, NatConfig, SwarmRunner,
+};
 
 #[derive(Parser)]
 struct Cli {
@@ -35,11 +41,50 @@ struct Cli {
     #[arg(short, long)]
     listen_address: String,
 
+    /// Publicly reachable multiaddr, detected through AutoNAT when omitted
     #[arg(short, long)]
-    address: String,
+    address: Option<String>,
 
     #[arg(short, long)]
     dial_addresses: Vec<String>,
+
+    /// Relays to listen through when this node is behind a NAT, including their /p2p/ peer id
+    #[arg(long)]
+    relay_addresses: Vec<String>,
+
+    /// Act as a circuit relay for peers behind a NAT
+    #[arg(long)]
+    relay_server: bool,
+
+    /// Directory to persist DHT records and blob chunks in, both are kept in memory when omitted
+    #[arg(long)]
+    record_store_path: Option<PathBuf>,
+
+    /// Maximum total size of the DHT record values in bytes, records are held in memory and persisted
+    #[arg(long, requires = "record_store_path")]
+    record_store_max_bytes: Option<u64>,
+
+    /// Maximum total size of the blob chunks kept and served to other peers in bytes
+    #[arg(long)]
+    blob_store_max_bytes: Option<usize>,
+
+    /// Swarm key of a private network, only peers holding the same key can connect
+    #[arg(long)]
+    swarm_key: Option<PathBuf>,
+
+    /// Peers allowed to connect, every peer is admitted when none are given
+    #[arg(long)]
+    allowed_peers: Vec<String>,
+
+    /// Peers never allowed to connect
+    #[arg(long)]
+    denied_peers: Vec<String>,
+
+    #[command(flatten)]
+    query: QueryArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
 }
 
 #[tokio::main]
@@ -59,6 +104,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         FieldElement::from_byte_slice_be(private_key.as_slice()).unwrap(),
     );
 
+    let query_config = QueryConfig::from(&cli.query);
+
+    // Chunks are persisted next to the records pointing at them.
+    let blob_store_config = BlobStoreConfig {
+        path: cli.record_store_path.as_ref().map(|path| path.join("blobs")),
+        max_total_bytes: cli.blob_store_max_bytes.unwrap_or(DEFAULT_BLOB_STORE_BYTES),
+    };
+
     let swarm_runner = SwarmRunner::new(
         cli.listen_address.parse()?,
         cli.dial_addresses
@@ -66,16 +119,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .map(|addr| Multiaddr::from_str(addr))
             .collect::<Result<Vec<Multiaddr>, _>>()?,
         p2p_keypair,
-        Multiaddr::from_str(&cli.address).unwrap(),
+        cli.address.as_deref().map(Multiaddr::from_str).transpose()?,
+        NatConfig {
+            relay_multiaddrs: cli
+                .relay_addresses
+                .iter()
+                .map(|addr| Multiaddr::from_str(addr))
+                .collect::<Result<Vec<Multiaddr>, _>>()?,
+            relay_server: cli.relay_server,
+        },
+        match cli.record_store_path {
+            Some(path) => {
+                let default_store = DiskStoreConfig::new(path);
+                StoreConfig::Disk(DiskStoreConfig {
+                    max_total_bytes: cli
+                        .record_store_max_bytes
+                        .unwrap_or(default_store.max_total_bytes),
+                    ..default_store
+                })
+            }
+            None => StoreConfig::default(),
+        },
+        blob_store_config,
+        AccessConfig {
+            psk: cli.swarm_key.as_deref().map(read_swarm_key).transpose()?,
+            allowed_peers: cli
+                .allowed_peers
+                .iter()
+                .map(|peer_id| PeerId::from_str(peer_id))
+                .collect::<Result<Vec<PeerId>, _>>()?,
+            denied_peers: cli
+                .denied_peers
+                .iter()
+                .map(|peer_id| PeerId::from_str(peer_id))
+                .collect::<Result<Vec<PeerId>, _>>()?,
+        },
+        ConnectionConfig::from(&cli.connection),
+        query_config.to_owned(),
     )?;
 
     let (gossipsub_tx, gossipsub_rx) = mpsc::channel::<GossipsubMessage>(100);
     let (kademlia_tx, kademlia_rx) = mpsc::channel::<KademliaMessage>(100);
-    let swarm_events = swarm_runner.run(gossipsub_rx, kademlia_rx);
+    let (blob_tx, blob_rx) = mpsc::channel::<BlobMessage>(100);
+    let swarm_events = swarm_runner.run(gossipsub_rx, kademlia_rx, blob_rx);
 
     let (delegate_tx, delegate_rx) = mpsc::channel::<JobData>(100);
     let (events_tx, events_rx) = broadcast::channel::<(kad::RecordKey, DelegatorEvent)>(100);
-    Delegator::new(swarm_events, gossipsub_tx, kademlia_tx, delegate_rx, events_tx, signing_key);
+    Delegator::new(
+        swarm_events,
+        gossipsub_tx,
+        KademliaClient::new(kademlia_tx, query_config),
+        BlobClient::new(blob_tx),
+        delegate_rx,
+        events_tx,
+        signing_key,
+    );
 
     // Create a `TcpListener` using tokio.
     let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
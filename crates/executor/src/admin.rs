@@ -0,0 +1,223 @@
+use crate::bidding::{DEFAULT_BASE_PRICE, DEFAULT_LOAD_PRICE};
+use libp2p::kad;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use zetina_common::job::unix_now;
+
+/*
+    Executor Administration
+    Lets the operator of an executor look at the jobs it works on and the errors it ran into,
+    pause and resume bidding, abort a job, tune its concurrency and prices at runtime and drain it.
+    Requests reach the executor loop as messages carrying a oneshot sender for the reply.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExecutorSettings {
+    // Whether announced jobs are bid on.
+    pub bidding: bool,
+    // Delegated jobs worked on at once, beyond it no bids are placed and delegations are handed back.
+    pub max_jobs: Option<usize>,
+    // Price of a bid without load, in registry token base units like every price.
+    pub base_price: u64,
+    // Added to the price for every unit of load, a running job weighs one and a proving job two.
+    pub load_price: u64,
+}
+
+impl Default for ExecutorSettings {
+    fn default() -> Self {
+        Self {
+            bidding: true,
+            max_jobs: None,
+            base_price: DEFAULT_BASE_PRICE,
+            load_price: DEFAULT_LOAD_PRICE,
+        }
+    }
+}
+
+impl ExecutorSettings {
+    pub fn price(&self, load: u64) -> u64 {
+        self.base_price.saturating_add(self.load_price.saturating_mul(load))
+    }
+
+    pub fn accepts(&self, active: usize) -> bool {
+        self.bidding && self.has_capacity(active)
+    }
+
+    // Whether another delegated job can be taken, a job delegated past the limit is handed back.
+    pub fn has_capacity(&self, active: usize) -> bool {
+        self.max_jobs.map_or(true, |max_jobs| active < max_jobs)
+    }
+
+    pub fn apply(&mut self, update: SettingsUpdate) {
+        if let Some(bidding) = update.bidding {
+            self.bidding = bidding;
+        }
+        if let Some(max_jobs) = update.max_jobs {
+            self.max_jobs = (max_jobs > 0).then_some(max_jobs);
+        }
+        if let Some(base_price) = update.base_price {
+            self.base_price = base_price;
+        }
+        if let Some(load_price) = update.load_price {
+            self.load_price = load_price;
+        }
+    }
+}
+
+// Settings to change, the ones left out are kept. A max_jobs of 0 lifts the limit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SettingsUpdate {
+    pub bidding: Option<bool>,
+    pub max_jobs: Option<usize>,
+    pub base_price: Option<u64>,
+    pub load_price: Option<u64>,
+}
+
+// Progress of a delegated job, the step is the position of its stage in the pipeline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub job_key: String,
+    pub stage: String,
+    pub step: usize,
+    pub steps: usize,
+    pub elapsed_secs: u64,
+    pub stage_elapsed_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub timestamp: u64,
+    pub job_key: Option<String>,
+    pub message: String,
+}
+
+// Number of errors kept for the operator.
+pub const RECENT_ERRORS: usize = 100;
+
+// Most recent errors of the executor, the oldest are dropped once it is full.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    records: VecDeque<ErrorRecord>,
+}
+
+impl ErrorLog {
+    pub fn push(&mut self, job_key: Option<&kad::RecordKey>, message: String) {
+        if self.records.len() == RECENT_ERRORS {
+            self.records.pop_front();
+        }
+        let job_key = job_key.map(hex::encode);
+        self.records.push_back(ErrorRecord { timestamp: unix_now(), job_key, message });
+    }
+
+    // Newest first.
+    pub fn records(&self) -> Vec<ErrorRecord> {
+        self.records.iter().rev().cloned().collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum AdminMessage {
+    Jobs(oneshot::Sender<Vec<JobStatus>>),
+    Errors(oneshot::Sender<Vec<ErrorRecord>>),
+    Settings(SettingsUpdate, oneshot::Sender<ExecutorSettings>),
+    // Replies whether the job was delegated to the executor and still in progress.
+    Abort(kad::RecordKey, oneshot::Sender<bool>),
+    Drain(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    admin_tx: mpsc::Sender<AdminMessage>,
+}
+
+impl AdminClient {
+    pub fn new(admin_tx: mpsc::Sender<AdminMessage>) -> Self {
+        Self { admin_tx }
+    }
+
+    pub async fn jobs(&self) -> Result<Vec<JobStatus>, AdminError> {
+        self.request(AdminMessage::Jobs).await
+    }
+
+    pub async fn errors(&self) -> Result<Vec<ErrorRecord>, AdminError> {
+        self.request(AdminMessage::Errors).await
+    }
+
+    // Applies the update and returns the resulting settings, an empty update only reads them.
+    pub async fn settings(&self, update: SettingsUpdate) -> Result<ExecutorSettings, AdminError> {
+        self.request(|reply_tx| AdminMessage::Settings(update, reply_tx)).await
+    }
+
+    pub async fn abort(&self, job_key: kad::RecordKey) -> Result<bool, AdminError> {
+        self.request(|reply_tx| AdminMessage::Abort(job_key, reply_tx)).await
+    }
+
+    // Starts draining the executor, it exits once its jobs are finished or handed back.
+    pub async fn drain(&self) -> Result<(), AdminError> {
+        self.request(AdminMessage::Drain).await
+    }
+
+    async fn request<T>(
+        &self,
+        message: impl FnOnce(oneshot::Sender<T>) -> AdminMessage,
+    ) -> Result<T, AdminError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.admin_tx.send(message(reply_tx)).await.map_err(|_| AdminError::Stopped)?;
+        reply_rx.await.map_err(|_| AdminError::Stopped)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("executor stopped")]
+    Stopped,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_price_follows_load() {
+        let settings = ExecutorSettings::default();
+        assert_eq!(settings.price(0), DEFAULT_BASE_PRICE);
+        assert_eq!(settings.price(3), DEFAULT_BASE_PRICE + 3 * DEFAULT_LOAD_PRICE);
+        let settings = ExecutorSettings { base_price: 10, load_price: 5, ..settings };
+        assert_eq!(settings.price(2), 20);
+    }
+
+    #[test]
+    fn update_keeps_omitted_settings() {
+        let mut settings = ExecutorSettings::default();
+        settings.apply(SettingsUpdate { max_jobs: Some(2), ..Default::default() });
+        assert_eq!(settings, ExecutorSettings { max_jobs: Some(2), ..Default::default() });
+        assert!(settings.accepts(1));
+        assert!(!settings.accepts(2));
+        assert!(!settings.has_capacity(2));
+        settings.apply(SettingsUpdate { bidding: Some(false), ..Default::default() });
+        assert!(!settings.accepts(1));
+        assert!(settings.has_capacity(1));
+
+        settings.apply(SettingsUpdate {
+            bidding: Some(false),
+            max_jobs: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(settings.max_jobs, None);
+        assert!(!settings.accepts(0));
+    }
+
+    #[test]
+    fn error_log_keeps_recent_errors() {
+        let mut errors = ErrorLog::default();
+        for index in 0..RECENT_ERRORS + 1 {
+            errors.push(None, index.to_string());
+        }
+        let records = errors.records();
+        assert_eq!(records.len(), RECENT_ERRORS);
+        assert_eq!(records[0].message, RECENT_ERRORS.to_string());
+        assert_eq!(records[RECENT_ERRORS - 1].message, "1");
+    }
+}
@@ -1,6 +1,153 @@
-use axum::response::IntoResponse;
-use hyper::StatusCode;
+use axum::{
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use libp2p::kad;
+use serde::Serialize;
+use std::sync::Arc;
+use zetina_peer::access::AccessStats;
+
+use crate::admin::{
+    AdminClient, AdminError, ErrorRecord, ExecutorSettings, JobStatus, SettingsUpdate,
+};
+
+#[derive(Debug, Clone)]
+pub struct ServerState {
+    pub admin: AdminClient,
+    pub access_stats: Arc<AccessStats>,
+    // Bearer token admin requests must carry, none are authenticated without it.
+    pub token: Option<String>,
+}
+
+// Rejects admin requests not carrying the bearer token of the executor.
+pub async fn require_token(
+    State(state): State<ServerState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    match &state.token {
+        Some(token) if !authorized(request.headers(), token) => Err(StatusCode::UNAUTHORIZED),
+        _ => Ok(next.run(request).await),
+    }
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(bearer) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    // Compared in full, so the time taken does not tell how much of the token was guessed.
+    bearer.len() == token.len()
+        && bearer.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
 
 pub async fn health_check_handler() -> impl IntoResponse {
     (StatusCode::OK, "Health check: OK")
 }
+
+// Delegated jobs in progress with their stage and elapsed time.
+pub async fn jobs_handler(
+    State(state): State<ServerState>,
+) -> Result<Json<Vec<JobStatus>>, StatusCode> {
+    Ok(Json(state.admin.jobs().await.map_err(status)?))
+}
+
+// Aborts a delegated job, its process is killed and the job rejected to its delegator.
+pub async fn abort_handler(
+    State(state): State<ServerState>,
+    Path(job_key): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let job_key = kad::RecordKey::new(&hex::decode(job_key).map_err(|_| StatusCode::BAD_REQUEST)?);
+    match state.admin.abort(job_key).await.map_err(status)? {
+        true => Ok(StatusCode::ACCEPTED),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+pub async fn pause_bidding_handler(
+    State(state): State<ServerState>,
+) -> Result<Json<ExecutorSettings>, StatusCode> {
+    let update = SettingsUpdate { bidding: Some(false), ..Default::default() };
+    Ok(Json(state.admin.settings(update).await.map_err(status)?))
+}
+
+pub async fn resume_bidding_handler(
+    State(state): State<ServerState>,
+) -> Result<Json<ExecutorSettings>, StatusCode> {
+    let update = SettingsUpdate { bidding: Some(true), ..Default::default() };
+    Ok(Json(state.admin.settings(update).await.map_err(status)?))
+}
+
+pub async fn settings_handler(
+    State(state): State<ServerState>,
+) -> Result<Json<ExecutorSettings>, StatusCode> {
+    Ok(Json(state.admin.settings(SettingsUpdate::default()).await.map_err(status)?))
+}
+
+pub async fn update_settings_handler(
+    State(state): State<ServerState>,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<ExecutorSettings>, StatusCode> {
+    Ok(Json(state.admin.settings(update).await.map_err(status)?))
+}
+
+// Most recent errors, newest first.
+pub async fn errors_handler(
+    State(state): State<ServerState>,
+) -> Result<Json<Vec<ErrorRecord>>, StatusCode> {
+    Ok(Json(state.admin.errors().await.map_err(status)?))
+}
+
+// Connections refused by the allowlist or the denylist since the executor started.
+#[derive(Debug, Serialize)]
+pub struct AccessReport {
+    pub blocked_inbound: u64,
+    pub blocked_outbound: u64,
+}
+
+pub async fn access_handler(State(state): State<ServerState>) -> Json<AccessReport> {
+    Json(AccessReport {
+        blocked_inbound: state.access_stats.blocked_inbound(),
+        blocked_outbound: state.access_stats.blocked_outbound(),
+    })
+}
+
+// Starts draining the executor, it exits once its jobs are finished or handed back.
+pub async fn drain_handler(State(state): State<ServerState>) -> impl IntoResponse {
+    match state.admin.drain().await {
+        Ok(()) => (StatusCode::ACCEPTED, "Draining"),
+        Err(AdminError::Stopped) => (StatusCode::CONFLICT, "Executor already stopped"),
+    }
+}
+
+fn status(err: AdminError) -> StatusCode {
+    match err {
+        AdminError::Stopped => StatusCode::CONFLICT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_token_is_required() {
+        assert!(authorized(&headers("Bearer secret"), "secret"));
+        assert!(!authorized(&headers("Bearer secreT"), "secret"));
+        assert!(!authorized(&headers("Bearer secret2"), "secret"));
+        assert!(!authorized(&headers("secret"), "secret"));
+        assert!(!authorized(&HeaderMap::new(), "secret"));
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+use zetina_common::registry::BalanceOracle;
+
+/*
+    Bid Policy
+    Decides whether the executor bids on a job and at which price.
+    When a balance oracle is configured the delegator deposit in the registry is checked before bidding,
+    so the executor does not spend resources on jobs whose delegator cannot cover the reward.
+    Bids never go below the minimum price, so an idle executor does not bid on delegators without funds.
+    Prices are amounts of the registry token in its base units, the same unit as the delegator deposit
+    and the reward the registry transfers, so a price compares with a balance without any conversion.
+*/
+
+// Base units in one registry token, the registry token has 18 decimals.
+pub const TOKEN: u64 = 1_000_000_000_000_000_000;
+
+// Lowest price bid by default, a delegator has to hold at least this much to be bid on.
+pub const DEFAULT_MIN_PRICE: u64 = TOKEN / 1000;
+// Price of an idle executor and price added for every unit of load, in registry token base units.
+pub const DEFAULT_BASE_PRICE: u64 = TOKEN / 1000;
+pub const DEFAULT_LOAD_PRICE: u64 = TOKEN / 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InsufficientBalancePolicy {
+    /// Do not bid on jobs whose delegator cannot cover the price
+    #[default]
+    Skip,
+    /// Lower the price to what the delegator can still cover
+    AdjustPrice,
+}
+
+#[derive(Clone)]
+pub struct BidPolicy {
+    pub balance_oracle: Option<Arc<dyn BalanceOracle>>,
+    pub insufficient_balance: InsufficientBalancePolicy,
+    pub min_price: u64,
+}
+
+impl Default for BidPolicy {
+    fn default() -> Self {
+        Self::new(None, InsufficientBalancePolicy::default(), DEFAULT_MIN_PRICE)
+    }
+}
+
+impl BidPolicy {
+    pub fn new(
+        balance_oracle: Option<Arc<dyn BalanceOracle>>,
+        insufficient_balance: InsufficientBalancePolicy,
+        min_price: u64,
+    ) -> Self {
+        Self { balance_oracle, insufficient_balance, min_price }
+    }
+
+    pub fn requires_balance_check(&self) -> bool {
+        self.balance_oracle.is_some()
+    }
+
+    // Price to bid at the given load price, raised to the minimum price. Both are in registry token base units.
+    pub fn floor(&self, price: u64) -> u64 {
+        price.max(self.min_price)
+    }
+
+    // Returns the price to bid given the delegator balance, or None if the job should be skipped.
+    pub fn price(&self, price: u64, balance: u128) -> Option<u64> {
+        let price = self.floor(price);
+        if balance >= price as u128 {
+            return Some(price);
+        }
+        match self.insufficient_balance {
+            InsufficientBalancePolicy::Skip => None,
+            InsufficientBalancePolicy::AdjustPrice
+                if balance > 0 && balance >= self.min_price as u128 =>
+            {
+                Some(balance as u64)
+            }
+            InsufficientBalancePolicy::AdjustPrice => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covered_price_is_kept() {
+        let policy = BidPolicy::default();
+        let price = 2 * TOKEN;
+        assert_eq!(policy.price(price, price as u128), Some(price));
+        assert_eq!(policy.price(price, u128::MAX), Some(price));
+    }
+
+    #[test]
+    fn uncovered_price_is_skipped() {
+        let policy = BidPolicy::new(None, InsufficientBalancePolicy::Skip, DEFAULT_MIN_PRICE);
+        assert_eq!(policy.price(TOKEN, TOKEN as u128 - 1), None);
+    }
+
+    #[test]
+    fn uncovered_price_is_adjusted() {
+        let policy = BidPolicy::new(None, InsufficientBalancePolicy::AdjustPrice, 5);
+        assert_eq!(policy.price(10, 9), Some(9));
+        assert_eq!(policy.price(10, 4), None);
+        assert_eq!(policy.price(10, 0), None);
+    }
+
+    #[test]
+    fn idle_price_needs_funds() {
+        let policy = BidPolicy::default();
+        assert_eq!(policy.price(0, 0), None);
+        assert_eq!(policy.price(0, 1), None);
+        assert_eq!(policy.price(0, DEFAULT_MIN_PRICE as u128), Some(DEFAULT_MIN_PRICE));
+    }
+}
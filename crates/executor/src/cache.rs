@@ -0,0 +1,272 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tracing::warn;
+use zetina_common::job::Job;
+
+/*
+    Witness Cache
+    Proofs this executor already produced, stored on disk so a job delegated to it again, like a job handed back
+    and retried or one delegated before a restart, is published right away instead of being run and proven from scratch.
+    The bootloader writes the executor and the delegator into the public output of a proof and the registry pays
+    the executor out of the balance of that delegator, so a proof only answers the very job it was made for.
+    Every proof is stored under the SHA-256 of the executor, bootloader and prover parameters, the delegator,
+    the deadline and the PIE of its job. The same PIE delegated by another delegator or with another deadline
+    is a different job and is proven again.
+    Proofs live in their own subdirectory under names the cache gives them, anything else found there is left alone.
+    The cache holds a bounded number of proof bytes, the least recently used proofs are evicted first.
+*/
+
+// Bytes of proofs an executor keeps by default.
+pub const DEFAULT_WITNESS_CACHE_BYTES: u64 = 1024 * 1024 * 1024;
+
+const PROOFS_DIR: &str = "proofs";
+const PROOF_SUFFIX: &str = ".proof";
+const PARTIAL_SUFFIX: &str = ".proof.partial";
+
+#[derive(Debug)]
+pub struct WitnessCache {
+    dir: PathBuf,
+    params: String,
+    sizes: HashMap<String, u64>,
+    // Least recently used first.
+    order: VecDeque<String>,
+    total_bytes: u64,
+    max_total_bytes: u64,
+}
+
+impl WitnessCache {
+    // Opens the cache in the directory, picking up the proofs cached by earlier runs.
+    // The parameters identify the bootloader and prover, proofs cached under others are not returned.
+    pub fn open(dir: PathBuf, params: String, max_total_bytes: u64) -> io::Result<Self> {
+        let dir = dir.join(PROOFS_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut entries = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let name = entry.file_name();
+            let name = name.to_str().filter(|_| metadata.is_file());
+            if name.and_then(|name| parse_key(name, PARTIAL_SUFFIX)).is_some() {
+                fs::remove_file(entry.path())?;
+            } else if let Some(key) = name.and_then(|name| parse_key(name, PROOF_SUFFIX)) {
+                entries.push((metadata.modified()?, key.to_string(), metadata.len()));
+            } else {
+                warn!("Ignoring unknown entry {} in the witness cache", entry.path().display());
+            }
+        }
+        entries.sort();
+
+        let mut cache = Self {
+            dir,
+            params,
+            sizes: HashMap::new(),
+            order: VecDeque::new(),
+            total_bytes: 0,
+            max_total_bytes,
+        };
+        for (_, key, size) in entries {
+            cache.total_bytes += size;
+            cache.sizes.insert(key.to_owned(), size);
+            cache.order.push_back(key);
+        }
+        cache.evict();
+        Ok(cache)
+    }
+
+    pub fn params(&self) -> &str {
+        &self.params
+    }
+
+    // Key the proof of the job is cached under, hashing the whole PIE is left to the caller
+    // so it can run away from the cache and the executor loop.
+    pub fn key(params: &str, job: &Job) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(params.as_bytes());
+        hasher.update(job.public_key.to_bytes_be());
+        hasher.update(job.job_data.deadline.to_be_bytes());
+        hasher.update(&job.job_data.cairo_pie_compressed);
+        hex::encode(hasher.finalize())
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        if !self.sizes.contains_key(key) {
+            return None;
+        }
+        let path = self.path(key);
+        match fs::read(&path) {
+            Ok(proof) => {
+                self.touch(key);
+                let _ = touch_file(&path);
+                Some(proof)
+            }
+            Err(_) => {
+                self.forget(key);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: String, proof: &[u8]) -> io::Result<()> {
+        let size = proof.len() as u64;
+        if size > self.max_total_bytes {
+            return Ok(());
+        }
+        // Written aside and renamed, so a crash never leaves a truncated proof behind.
+        let partial = self.dir.join(format!("{key}{PARTIAL_SUFFIX}"));
+        fs::write(&partial, proof)?;
+        fs::rename(&partial, self.path(&key))?;
+
+        self.forget(&key);
+        self.total_bytes += size;
+        self.sizes.insert(key.to_owned(), size);
+        self.order.push_back(key);
+        self.evict();
+        Ok(())
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}{PROOF_SUFFIX}"))
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.order.retain(|used| used != key);
+        self.order.push_back(key.to_string());
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(size) = self.sizes.remove(key) {
+            self.total_bytes -= size;
+            self.order.retain(|used| used != key);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.total_bytes > self.max_total_bytes {
+            let Some(oldest) = self.order.pop_front() else { break };
+            if let Some(size) = self.sizes.remove(&oldest) {
+                self.total_bytes -= size;
+            }
+            let _ = fs::remove_file(self.path(&oldest));
+        }
+    }
+}
+
+// Key of a file named by the cache with the given suffix, None for any other file.
+fn parse_key<'a>(name: &'a str, suffix: &str) -> Option<&'a str> {
+    name.strip_suffix(suffix)
+        .filter(|key| key.len() == 64 && key.bytes().all(|byte| byte.is_ascii_hexdigit()))
+}
+
+// Marks the file as used now, so the order of use survives a restart.
+fn touch_file(path: &Path) -> io::Result<()> {
+    fs::File::options().write(true).open(path)?.set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use starknet::core::types::FieldElement;
+    use tempfile::TempDir;
+    use zetina_common::job::JobData;
+
+    fn job(pie: u8) -> Job {
+        Job {
+            job_data: JobData::new(vec![pie; 3], 0),
+            public_key: FieldElement::ZERO,
+            signature_r: FieldElement::ZERO,
+            signature_s: FieldElement::ZERO,
+        }
+    }
+
+    fn open(dir: &TempDir, params: &str, max_total_bytes: u64) -> WitnessCache {
+        WitnessCache::open(dir.path().to_owned(), params.to_string(), max_total_bytes).unwrap()
+    }
+
+    fn insert(cache: &mut WitnessCache, pie: u8, size: usize) {
+        cache.insert(WitnessCache::key(cache.params(), &job(pie)), &vec![7; size]).unwrap();
+    }
+
+    fn get(cache: &mut WitnessCache, pie: u8) -> Option<Vec<u8>> {
+        cache.get(&WitnessCache::key(cache.params(), &job(pie)))
+    }
+
+    #[test]
+    fn cached_proof_answers_same_job() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = open(&dir, "stone", 100);
+        insert(&mut cache, 1, 10);
+        assert_eq!(get(&mut cache, 1), Some(vec![7; 10]));
+        assert_eq!(get(&mut cache, 2), None);
+    }
+
+    #[test]
+    fn proofs_survive_reopening_under_same_params() {
+        let dir = TempDir::new().unwrap();
+        insert(&mut open(&dir, "stone", 100), 1, 10);
+        assert_eq!(get(&mut open(&dir, "stone", 100), 1), Some(vec![7; 10]));
+        assert_eq!(get(&mut open(&dir, "stone-next", 100), 1), None);
+    }
+
+    #[test]
+    fn least_recently_used_proof_is_evicted() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = open(&dir, "stone", 30);
+        for pie in 1..=3 {
+            insert(&mut cache, pie, 10);
+        }
+        assert!(get(&mut cache, 1).is_some());
+        insert(&mut cache, 4, 10);
+        assert_eq!(cache.total_bytes(), 30);
+        assert_eq!(fs::read_dir(dir.path().join(PROOFS_DIR)).unwrap().count(), 3);
+        assert!(get(&mut cache, 2).is_none());
+        for pie in [1, 3, 4] {
+            assert!(get(&mut cache, pie).is_some());
+        }
+    }
+
+    #[test]
+    fn oversized_proof_is_not_cached() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = open(&dir, "stone", 30);
+        insert(&mut cache, 1, 31);
+        assert_eq!(cache.total_bytes(), 0);
+        assert!(get(&mut cache, 1).is_none());
+    }
+
+    #[test]
+    fn same_pie_of_another_job_misses_cache() {
+        let dir = TempDir::new().unwrap();
+        let mut cache = open(&dir, "stone", 100);
+        insert(&mut cache, 1, 10);
+        let other_delegator = Job { public_key: FieldElement::ONE, ..job(1) };
+        let other_deadline = Job { job_data: JobData::new(vec![1; 3], 42), ..job(1) };
+        for other in [other_delegator, other_deadline] {
+            assert_eq!(cache.get(&WitnessCache::key(cache.params(), &other)), None);
+        }
+        // Signatures are checked by the validation, they do not change which job it is.
+        let resigned = Job { signature_r: FieldElement::TWO, ..job(1) };
+        assert_eq!(cache.get(&WitnessCache::key(cache.params(), &resigned)), Some(vec![7; 10]));
+    }
+
+    #[test]
+    fn foreign_files_are_left_alone() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("notes.txt"), [1; 10]).unwrap();
+        fs::create_dir_all(dir.path().join(PROOFS_DIR).join("nested")).unwrap();
+        fs::write(dir.path().join(PROOFS_DIR).join("notes.partial"), [1; 10]).unwrap();
+        let cache = open(&dir, "stone", 0);
+        assert_eq!(cache.total_bytes(), 0);
+        assert!(dir.path().join("notes.txt").exists());
+        assert!(dir.path().join(PROOFS_DIR).join("nested").exists());
+        assert!(dir.path().join(PROOFS_DIR).join("notes.partial").exists());
+    }
+}
@@ -1,169 +1,742 @@
-use futures::{stream::FuturesUnordered, Stream};
-use libp2p::{gossipsub, kad, PeerId};
-use std::collections::{HashMap, HashSet};
+use crate::admin::{AdminMessage, ErrorLog, ExecutorSettings, JobStatus};
+use crate::bidding::BidPolicy;
+use crate::cache::WitnessCache;
+use crate::job_state::{
+    JobAction, JobInput, JobState, CAPACITY_REASON, DRAIN_REASON, PIPELINE_STEPS,
+};
+use crate::validation::JobValidator;
+use futures::{future::BoxFuture, stream::FuturesUnordered};
+use libp2p::{kad, PeerId};
+use std::collections::{HashMap, VecDeque};
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::mpsc;
+use tokio::time::{self, interval, sleep_until};
 use tokio::{sync::mpsc::Sender, task::JoinHandle};
 use tokio_stream::StreamExt;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+use zetina_common::capability::{ExecutorCapabilities, CAPABILITY_ADVERTISEMENT_INTERVAL};
 use zetina_common::hash;
-use zetina_common::job::Job;
+use zetina_common::job::{Job, JobRejection, MAX_JOB_TTL};
+use zetina_common::registry::RegistryClient;
 use zetina_common::{
-    graceful_shutdown::shutdown_signal, job::JobBid, job_trace::JobTrace, job_witness::JobWitness,
-    process::Process,
+    graceful_shutdown::shutdown_signal, job::JobBid, job_witness::JobWitness, process::Process,
 };
 use zetina_peer::{
-    DelegationMessage, GossipsubMessage, KademliaMessage, MarketMessage, PeerBehaviourEvent, Topic,
-};
-use zetina_prover::{
-    errors::ProverControllerError, stone_prover::StoneProver, traits::ProverController,
-};
-use zetina_runner::{
-    cairo_runner::CairoRunner, errors::RunnerControllerError, traits::RunnerController,
+    blob::{BlobClient, BlobError, BlobHash, MAX_BLOB_SIZE},
+    event::{PeerEvent, PeerEvents},
+    query::{KademliaClient, QueryError},
+    wire::{self, WireError, WireMessage},
+    DelegationMessage, GossipsubMessage, MarketMessage, NetworkingMessage, Topic, MAX_RECORD_SIZE,
 };
+use zetina_prover::{errors::ProverControllerError, traits::ProverController};
+use zetina_runner::{errors::RunnerControllerError, traits::RunnerController};
+
+// How long a draining executor waits for its running jobs by default.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(300);
+
+// Shortest time between capabilities advertised on request of delegators.
+const CAPABILITY_REQUEST_COOLDOWN: Duration = Duration::from_secs(5);
+
+// How long the delegator of an announced job is remembered once the executor no longer tracks the job.
+const ANNOUNCEMENT_TTL: Duration = Duration::from_secs(600);
+
+// How long a stopping executor keeps its swarm running for the gossip it queued last.
+const GOSSIP_FLUSH_PERIOD: Duration = Duration::from_secs(1);
+const GOSSIP_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Executor {
     handle: Option<JoinHandle<Result<(), Error>>>,
 }
 
 impl Executor {
-    pub fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<R, P>(
         identity: PeerId,
-        mut swarm_events: Pin<Box<dyn Stream<Item = PeerBehaviourEvent> + Send>>,
+        mut swarm_events: PeerEvents,
         gossipsub_tx: Sender<GossipsubMessage>,
-        kademlia_tx: Sender<KademliaMessage>,
-        runner: CairoRunner,
-        prover: StoneProver,
-    ) -> Self {
+        kademlia: KademliaClient,
+        blobs: BlobClient,
+        runner: R,
+        prover: P,
+        witnesses: Option<WitnessCache>,
+        registry: Option<Arc<RegistryClient>>,
+        bid_policy: BidPolicy,
+        validator: Arc<JobValidator>,
+        mut capabilities: ExecutorCapabilities,
+        settings: ExecutorSettings,
+        mut admin_rx: mpsc::Receiver<AdminMessage>,
+        grace_period: Duration,
+    ) -> Self
+    where
+        R: RunnerController + Send + Sync + 'static,
+        P: ProverController + Send + Sync + 'static,
+    {
         Self {
             handle: Some(tokio::spawn(async move {
-                let mut runner_scheduler =
-                    FuturesUnordered::<Process<'_, Result<JobTrace, RunnerControllerError>>>::new();
-                let mut prover_scheduler = FuturesUnordered::<
-                    Process<'_, Result<JobWitness, ProverControllerError>>,
-                >::new();
-
-                let mut job_hash_store = HashSet::<kad::RecordKey>::new();
-                let mut proof_hash_store = HashMap::<kad::RecordKey, kad::RecordKey>::new();
+                let mut driver = Driver {
+                    identity,
+                    gossipsub_tx,
+                    kademlia,
+                    blobs,
+                    runner: Arc::new(runner),
+                    prover: Arc::new(prover),
+                    witnesses: witnesses.map(|witnesses| Arc::new(Mutex::new(witnesses))),
+                    cache_keys: Default::default(),
+                    registry,
+                    bid_policy,
+                    validator,
+                    settings,
+                    errors: ErrorLog::default(),
+                    jobs: HashMap::new(),
+                    delegators: HashMap::new(),
+                    aborts: HashMap::new(),
+                    scheduler: FuturesUnordered::new(),
+                };
+                let mut capability_interval = interval(CAPABILITY_ADVERTISEMENT_INTERVAL);
+                let mut advertised_at = Instant::now();
+                // Set once the executor drains, it exits when its jobs are done or at this deadline.
+                let mut drain_deadline: Option<time::Instant> = None;
 
                 loop {
                     tokio::select! {
                         Some(event) = swarm_events.next() => {
                             match event {
-                                PeerBehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. }) => {
-                                    if message.topic == Topic::Market.into() {
-                                        match serde_json::from_slice::<MarketMessage>(&message.data)? {
-                                            MarketMessage::JobBidPropagation(job_key) => {
-                                                gossipsub_tx
-                                                    .send(GossipsubMessage {
-                                                        topic: Topic::Market.into(),
-                                                        data: serde_json::to_vec(&MarketMessage::JobBid(JobBid {
-                                                            identity,
-                                                            job_key,
-                                                            price: (runner_scheduler.len() + 2 * prover_scheduler.len()) as u64,
-                                                        }))?
-                                                    })
-                                                    .await?
-                                            }
-                                            _ => {}
-                                        }
+                                PeerEvent::JobAnnounced { source, job_key, requirements } => {
+                                    if drain_deadline.is_some() || !driver.settings.accepts(driver.active()) {
+                                        continue;
                                     }
-                                    if message.topic == Topic::Delegation.into() {
-                                        match serde_json::from_slice::<DelegationMessage>(&message.data)? {
-                                            DelegationMessage::Delegate(job_delegation) => {
-                                                if job_delegation.identity == identity {
-                                                    info!("received delegation of job: {}", hex::encode(&job_delegation.job_key));
-                                                    job_hash_store.insert(job_delegation.job_key.to_owned());
-                                                    kademlia_tx.send(KademliaMessage::GET(job_delegation.job_key)).await?;
-                                                }
-                                            }
-                                            _ => {}
-                                        }
+                                    if !capabilities.supports(&requirements) {
+                                        info!("Skipping job {}: requirements {:?} exceed capabilities", hex::encode(&job_key), requirements);
+                                        continue;
                                     }
+                                    // Without a balance check the job is bid on at the current load right away.
+                                    let price = (!driver.bid_policy.requires_balance_check()).then(|| driver.price());
+                                    // The first announcement names the delegator, re-announcements of other peers do not take the job over.
+                                    driver.delegators.entry(job_key.to_owned()).or_insert((source, Instant::now()));
+                                    driver.feed(job_key, JobInput::Announced { price }).await;
                                 }
-                                PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { result, ..}) => {
-                                    match result {
-                                        kad::QueryResult::GetRecord(Ok(
-                                            kad::GetRecordOk::FoundRecord(kad::PeerRecord {
-                                                record: kad::Record { key, value, .. },
-                                                ..
-                                            })
-                                        )) => {
-                                            if job_hash_store.remove(&key) {
-                                                let job: Job = serde_json::from_slice(&value)?;
-                                                info!("received delegation of job: {}", hex::encode(&key));
-                                                runner_scheduler.push(runner.run(job)?);
-                                            }
-                                        },
-                                        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
-                                            if let Some ((proof_key, job_key)) = proof_hash_store.remove_entry(&key) {
-                                                info!("job {} proof with key: {} stored in DHT", hex::encode(&job_key), hex::encode(&proof_key));
-                                                gossipsub_tx.send(GossipsubMessage {
-                                                    topic: Topic::Delegation.into(),
-                                                    data: serde_json::to_vec(&DelegationMessage::Finished(proof_key, job_key))?
-                                                }).await?;
-                                            }
+                                PeerEvent::Delegated { source, bid: job_delegation } => {
+                                    // Only the delegator that announced the job may delegate it or tell this executor it was outbid.
+                                    if driver.delegators.get(&job_delegation.job_key).map(|(delegator, _)| *delegator) != Some(source) {
+                                        debug!("Ignoring delegation of job {} gossiped by {source}", hex::encode(&job_delegation.job_key));
+                                        continue;
+                                    }
+                                    // Repeated delegations of a job taken before are left to its state.
+                                    let taken = driver.jobs.get(&job_delegation.job_key).is_some_and(|job| job.state.is_delegated());
+                                    let refused = match drain_deadline {
+                                        Some(_) => Some(DRAIN_REASON),
+                                        // The limit may have been lowered or reached by other delegations since the bid.
+                                        None => (!driver.settings.has_capacity(driver.active())).then_some(CAPACITY_REASON),
+                                    };
+                                    if let (true, false, Some(reason)) = (job_delegation.identity == identity, taken, refused) {
+                                        let reason = reason.to_string();
+                                        if let Err(err) = driver.perform(&job_delegation.job_key, JobAction::Return(reason)).await {
+                                            error!("Failed to hand back job {}: {err}", hex::encode(&job_delegation.job_key));
+                                            driver.errors.push(Some(&job_delegation.job_key), format!("failed to hand back job: {err}"));
                                         }
-                                        _ => {}
+                                        // The bid on the handed back job is forgotten.
+                                        driver.feed(job_delegation.job_key, JobInput::Abandon).await;
+                                    } else if job_delegation.identity == identity {
+                                        info!("received delegation of job: {}", hex::encode(&job_delegation.job_key));
+                                        driver.feed(job_delegation.job_key, JobInput::Delegated).await;
+                                    } else if driver.jobs.contains_key(&job_delegation.job_key) {
+                                        driver.feed(job_delegation.job_key, JobInput::Outbid).await;
+                                    }
+                                }
+                                PeerEvent::CapabilitiesRequested { identity: requested, .. } => {
+                                    // Asked by delegators that got a bid before any advertisement.
+                                    if requested == identity && drain_deadline.is_none() && advertised_at.elapsed() >= CAPABILITY_REQUEST_COOLDOWN {
+                                        driver.advertise(&mut capabilities).await;
+                                        advertised_at = Instant::now();
                                     }
                                 }
                                 _ => {}
                             }
                         }
-                        Some(Ok(job_trace)) = runner_scheduler.next() => {
-                            info!("Scheduled proving of job_trace: {}", hex::encode(&job_trace.job_key));
-                            prover_scheduler.push(prover.run(job_trace)?);
+                        Some((job_key, input)) = driver.scheduler.next() => {
+                            driver.feed(job_key, input).await;
                         },
-                        Some(Ok(job_witness)) = prover_scheduler.next() => {
-                            let proof_key = kad::RecordKey::new(&hash!(job_witness).to_be_bytes());
-                            info!("Finished proving job: {} proof key: {}", hex::encode(&job_witness.job_key), hex::encode(&proof_key));
-                            proof_hash_store.insert(proof_key.to_owned(), job_witness.job_key.to_owned());
-                            kademlia_tx.send(KademliaMessage::PUT(
-                                (proof_key, serde_json::to_vec(&job_witness)?)
-                            )).await?;
+                        _ = capability_interval.tick(), if drain_deadline.is_none() => {
+                            driver.prune(Instant::now());
+                            driver.advertise(&mut capabilities).await;
+                            advertised_at = Instant::now();
                         },
                         _ = shutdown_signal() => {
+                            // A second signal cuts the grace period short.
+                            drain_deadline = match drain_deadline {
+                                None => Some(driver.drain(grace_period).await),
+                                Some(_) => Some(time::Instant::now()),
+                            };
+                        }
+                        Some(message) = admin_rx.recv() => {
+                            match message {
+                                AdminMessage::Jobs(reply_tx) => {
+                                    let _ = reply_tx.send(driver.statuses());
+                                }
+                                AdminMessage::Errors(reply_tx) => {
+                                    let _ = reply_tx.send(driver.errors.records());
+                                }
+                                AdminMessage::Settings(update, reply_tx) => {
+                                    driver.settings.apply(update);
+                                    info!("Executor settings: {:?}", driver.settings);
+                                    let _ = reply_tx.send(driver.settings.to_owned());
+                                }
+                                AdminMessage::Abort(job_key, reply_tx) => {
+                                    let active = driver.jobs.get(&job_key)
+                                        .is_some_and(|job| job.state.is_active());
+                                    if active {
+                                        driver.feed(job_key, JobInput::Aborted).await;
+                                    }
+                                    let _ = reply_tx.send(active);
+                                }
+                                AdminMessage::Drain(reply_tx) => {
+                                    if drain_deadline.is_none() {
+                                        drain_deadline = Some(driver.drain(grace_period).await);
+                                    }
+                                    let _ = reply_tx.send(());
+                                }
+                            }
+                        }
+                        _ = sleep_until(drain_deadline.unwrap_or_else(time::Instant::now)), if drain_deadline.is_some() => {
+                            warn!("Grace period is over, handing back {} unfinished jobs", driver.active());
+                            for job_key in driver.jobs.keys().cloned().collect::<Vec<_>>() {
+                                driver.feed(job_key, JobInput::Abandon).await;
+                            }
                             break
                         }
                         else => break
                     };
+                    if drain_deadline.is_some() && driver.active() == 0 {
+                        info!("Executor drained");
+                        break;
+                    }
                 }
+                flush_gossip(&mut swarm_events, &driver.gossipsub_tx).await;
                 Ok(())
             })),
         }
     }
+
+    // Waits until the executor stopped, after a drain or a failure.
+    pub async fn join(mut self) {
+        if let Some(handle) = self.handle.take() {
+            report(handle).await;
+        }
+    }
 }
 
 impl Drop for Executor {
     fn drop(&mut self) {
-        let handle = self.handle.take();
-        tokio::spawn(async move {
-            if let Some(handle) = handle {
-                handle.await.unwrap().unwrap();
+        if let Some(handle) = self.handle.take() {
+            tokio::spawn(report(handle));
+        }
+    }
+}
+
+// The swarm only runs while its events are polled, so a stopping executor keeps polling them until the gossip
+// it queued last, like a finished proof or a handed back job, is published and had a moment to reach its peers.
+async fn flush_gossip(swarm_events: &mut PeerEvents, gossipsub_tx: &Sender<GossipsubMessage>) {
+    let deadline = sleep_until(time::Instant::now() + GOSSIP_FLUSH_TIMEOUT);
+    tokio::pin!(deadline);
+    let mut check_interval = interval(Duration::from_millis(50));
+    let mut flushed_at = None;
+    loop {
+        tokio::select! {
+            event = swarm_events.next() => {
+                if event.is_none() {
+                    break;
+                }
+            }
+            _ = check_interval.tick() => {
+                let now = time::Instant::now();
+                let queued = gossipsub_tx.capacity() < gossipsub_tx.max_capacity();
+                if !queued && now >= *flushed_at.get_or_insert(now + GOSSIP_FLUSH_PERIOD) {
+                    break;
+                }
+            }
+            _ = &mut deadline => {
+                warn!("Stopping with unpublished gossip");
+                break;
+            }
+        }
+    }
+}
+
+async fn report(handle: JoinHandle<Result<(), Error>>) {
+    match handle.await {
+        Ok(Err(err)) => error!("Executor stopped: {err}"),
+        Err(err) if err.is_panic() => error!("Executor panicked: {err}"),
+        _ => {}
+    }
+}
+
+/*
+    Job Driver
+    Feeds inputs into the state machines of the jobs this executor knows about and performs the actions of every transition.
+    Fetching, validation, running, proving and publishing run in the scheduler and come back as an input for their job,
+    an action that fails fails its own job only, so one bad job never takes the executor down.
+    Jobs delegated to this executor are kept until their deadline, so repeated delegations are not run twice.
+    Running and proving jobs keep the sender that aborts their process.
+    Failed jobs and errors of the driver itself are kept in a short log for the operator.
+*/
+struct Driver<R, P> {
+    identity: PeerId,
+    gossipsub_tx: Sender<GossipsubMessage>,
+    kademlia: KademliaClient,
+    blobs: BlobClient,
+    runner: Arc<R>,
+    prover: Arc<P>,
+    // Cache reads and writes touch the disk and run on the blocking pool.
+    witnesses: Option<Arc<Mutex<WitnessCache>>>,
+    // Keys the proofs of running and proving jobs are going to be cached under, set by the tasks running them.
+    cache_keys: Arc<Mutex<HashMap<kad::RecordKey, String>>>,
+    registry: Option<Arc<RegistryClient>>,
+    bid_policy: BidPolicy,
+    validator: Arc<JobValidator>,
+    settings: ExecutorSettings,
+    errors: ErrorLog,
+    jobs: HashMap<kad::RecordKey, TrackedJob>,
+    // Peers that announced the jobs, with when they did.
+    delegators: HashMap<kad::RecordKey, (PeerId, Instant)>,
+    aborts: HashMap<kad::RecordKey, mpsc::Sender<()>>,
+    scheduler: FuturesUnordered<BoxFuture<'static, (kad::RecordKey, JobInput)>>,
+}
+
+impl<R, P> Driver<R, P>
+where
+    R: RunnerController + Send + Sync + 'static,
+    P: ProverController + Send + Sync + 'static,
+{
+    async fn feed(&mut self, job_key: kad::RecordKey, input: JobInput) {
+        let mut inputs = VecDeque::from([input]);
+        while let Some(input) = inputs.pop_front() {
+            let expires_at = match &input {
+                JobInput::Fetched(Ok(job)) => Some(job.job_data.expires_at()),
+                _ => None,
+            };
+            let idle = JobState::Idle;
+            let state = self.jobs.get(&job_key).map(|job| &job.state).unwrap_or(&idle);
+            let Some((state, actions)) = state.next(input) else {
+                debug!(
+                    "Ignoring input for job {} in stage {}",
+                    hex::encode(&job_key),
+                    state.stage()
+                );
+                continue;
+            };
+            match state {
+                JobState::Idle => {
+                    self.jobs.remove(&job_key);
+                }
+                state => {
+                    let now = Instant::now();
+                    let job = self.jobs.entry(job_key.to_owned()).or_insert_with(|| TrackedJob {
+                        state: JobState::Idle,
+                        expires_at: None,
+                        delegated_at: None,
+                        stage_since: now,
+                    });
+                    if job.state.stage() != state.stage() {
+                        job.stage_since = now;
+                    }
+                    if state.is_delegated() {
+                        job.delegated_at.get_or_insert(now);
+                    }
+                    if let JobState::Failed(reason) = &state {
+                        self.errors.push(Some(&job_key), reason.to_owned());
+                    }
+                    job.state = state;
+                    job.expires_at = job.expires_at.or(expires_at);
+                }
+            }
+
+            for action in actions {
+                match self.perform(&job_key, action).await {
+                    Ok(Some(input)) => inputs.push_back(input),
+                    Ok(None) => {}
+                    Err(err) => {
+                        error!("Job {} failed: {err}", hex::encode(&job_key));
+                        inputs.push_back(JobInput::Failed(err.to_string()));
+                        break;
+                    }
+                }
+            }
+        }
+        // Also after ignored inputs, a task finishing after its job settled may have left a cache key behind.
+        let state = self.jobs.get(&job_key).map(|job| &job.state);
+        if !matches!(state, Some(JobState::Running | JobState::Proving)) {
+            self.aborts.remove(&job_key);
+            self.cache_keys.lock().unwrap().remove(&job_key);
+        }
+    }
+
+    async fn advertise(&mut self, capabilities: &mut ExecutorCapabilities) {
+        capabilities.queue_depth = self.queue_depth();
+        let message = NetworkingMessage::Capabilities(capabilities.to_owned());
+        if let Err(err) = self.gossip(Topic::Networking, &message).await {
+            error!("Failed to advertise capabilities: {err}");
+            self.errors.push(None, format!("failed to advertise capabilities: {err}"));
+        }
+    }
+
+    // Stops bidding, announces the departure and hands back the jobs that did not start running.
+    async fn drain(&mut self, grace_period: Duration) -> time::Instant {
+        info!("Draining executor, waiting up to {grace_period:?} for {} jobs", self.active());
+        let departure = NetworkingMessage::Departure(self.identity);
+        if let Err(err) = self.gossip(Topic::Networking, &departure).await {
+            error!("Failed to announce departure: {err}");
+            self.errors.push(None, format!("failed to announce departure: {err}"));
+        }
+        for job_key in self.jobs.keys().cloned().collect::<Vec<_>>() {
+            self.feed(job_key, JobInput::Drain).await;
+        }
+        time::Instant::now() + grace_period
+    }
+
+    // Performs an action of the job, returns the input it immediately leads to.
+    async fn perform(
+        &mut self,
+        job_key: &kad::RecordKey,
+        action: JobAction,
+    ) -> Result<Option<JobInput>, Error> {
+        let key = job_key.to_owned();
+        match action {
+            JobAction::FetchJob => {
+                let (kademlia, blobs) = (self.kademlia.to_owned(), self.blobs.to_owned());
+                self.scheduler.push(Box::pin(async move {
+                    let result = fetch_job(&kademlia, &blobs, key.to_owned()).await;
+                    if let Err(err) = &result {
+                        warn!("Failed to fetch job {}: {err}", hex::encode(&key));
+                    }
+                    (key, JobInput::Fetched(result.map_err(|err| err.to_string())))
+                }));
+            }
+            JobAction::CheckBalance(job) => {
+                let Some(balance_oracle) = self.bid_policy.balance_oracle.to_owned() else {
+                    return Ok(Some(JobInput::Priced(Some(self.price()))));
+                };
+                if job.job_data.time_left().is_none() {
+                    info!("Skipping expired job {}", hex::encode(job_key));
+                    return Ok(Some(JobInput::Priced(None)));
+                }
+                let (price, bid_policy) = (self.price(), self.bid_policy.to_owned());
+                self.scheduler.push(Box::pin(async move {
+                    let price = match balance_oracle.balance(job.public_key).await {
+                        Ok(balance) => {
+                            let bid = bid_policy.price(price, balance);
+                            if bid.is_none() {
+                                info!(
+                                    "Skipping job {}: delegator balance {} does not cover price {}",
+                                    hex::encode(&key),
+                                    balance,
+                                    price
+                                );
+                            }
+                            bid
+                        }
+                        Err(err) => {
+                            error!(
+                                "Failed to check delegator balance for job {}: {err}",
+                                hex::encode(&key)
+                            );
+                            None
+                        }
+                    };
+                    (key, JobInput::Priced(price))
+                }));
+            }
+            JobAction::Bid(price) => {
+                let bid = JobBid { identity: self.identity, job_key: key, price };
+                self.gossip(Topic::Market, &MarketMessage::JobBid(bid)).await?;
+            }
+            JobAction::Validate(job) => {
+                let validator = self.validator.to_owned();
+                self.scheduler.push(Box::pin(async move {
+                    let result = validator.validate_owned(job).await;
+                    (key, JobInput::Validated(result.map_err(|err| err.to_string())))
+                }));
+            }
+            JobAction::Run(mut job) => {
+                info!("Validated job: {}", hex::encode(job_key));
+                let (witnesses, cache_keys) =
+                    (self.witnesses.to_owned(), self.cache_keys.to_owned());
+                let runner = self.runner.to_owned();
+                let (abort_tx, abort_rx) = mpsc::channel(1);
+                self.aborts.insert(key.to_owned(), abort_tx);
+                self.scheduler.push(Box::pin(async move {
+                    if let Some(witnesses) = witnesses {
+                        // Hashing the PIE takes a while, the cache is only locked to read its params and the proof.
+                        let lookup = tokio::task::spawn_blocking(move || {
+                            let params = witnesses.lock().unwrap().params().to_owned();
+                            let cache_key = WitnessCache::key(&params, &job);
+                            let proof = witnesses.lock().unwrap().get(&cache_key);
+                            (job, cache_key, proof)
+                        })
+                        .await;
+                        let Ok((looked_up, cache_key, proof)) = lookup else {
+                            let reason = "failed to look up the witness cache".to_string();
+                            return (key, JobInput::Ran(Err(reason)));
+                        };
+                        job = looked_up;
+                        if let Some(proof) = proof {
+                            info!("Found cached proof of job {}", hex::encode(&key));
+                            // Same key the runner gives the trace of the job.
+                            let trace_key = kad::RecordKey::new(&hash!(job).to_be_bytes());
+                            let job_witness = JobWitness { job_key: trace_key, proof };
+                            return (key, JobInput::Cached(job_witness));
+                        }
+                        cache_keys.lock().unwrap().insert(key.to_owned(), cache_key);
+                    }
+                    let result = match runner.run(job) {
+                        Ok(process) => supervise(process, abort_rx).await,
+                        Err(err) => Err(err),
+                    };
+                    (key, JobInput::Ran(result.map_err(|err| err.to_string())))
+                }));
+            }
+            JobAction::Prove(job_trace) => {
+                info!("Scheduled proving of job_trace: {}", hex::encode(&job_trace.job_key));
+                let prover = self.prover.to_owned();
+                let (abort_tx, abort_rx) = mpsc::channel(1);
+                self.aborts.insert(key.to_owned(), abort_tx);
+                self.scheduler.push(Box::pin(async move {
+                    let result = match prover.run(job_trace) {
+                        Ok(process) => supervise(process, abort_rx).await,
+                        Err(err) => Err(err),
+                    };
+                    (key, JobInput::Proved(result.map_err(|err| err.to_string())))
+                }));
             }
+            JobAction::CacheWitness(job_witness) => {
+                let cache_key = self.cache_keys.lock().unwrap().remove(job_key);
+                if let (Some(witnesses), Some(cache_key)) = (self.witnesses.to_owned(), cache_key) {
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(err) =
+                            witnesses.lock().unwrap().insert(cache_key, &job_witness.proof)
+                        {
+                            warn!("Failed to cache proof of job {}: {err}", hex::encode(&key));
+                        }
+                    });
+                }
+            }
+            JobAction::SubmitWitness(job_witness) => {
+                if let Some(registry) = self.registry.to_owned() {
+                    tokio::spawn(async move {
+                        match registry.verify_job_witness(&job_witness).await {
+                            Ok(transaction_hash) => info!(
+                                "Submitted job {} witness to registry, transaction: {:#x}",
+                                hex::encode(&job_witness.job_key),
+                                transaction_hash
+                            ),
+                            Err(err) => error!(
+                                "Failed to submit job {} witness to registry: {err}",
+                                hex::encode(&job_witness.job_key)
+                            ),
+                        }
+                    });
+                }
+            }
+            JobAction::PublishProof(job_witness) => {
+                let proof_key = kad::RecordKey::new(&hash!(job_witness).to_be_bytes());
+                info!(
+                    "Finished proving job: {} proof key: {}",
+                    hex::encode(job_key),
+                    hex::encode(&proof_key)
+                );
+                let (kademlia, blobs) = (self.kademlia.to_owned(), self.blobs.to_owned());
+                // The proof is only of use to the delegator until the job deadline.
+                let expires = self.jobs.get(job_key).and_then(|job| job.expires_at);
+                let expires = expires.unwrap_or_else(|| Instant::now() + MAX_JOB_TTL);
+                self.scheduler.push(Box::pin(async move {
+                    let result = publish_proof(
+                        &kademlia,
+                        &blobs,
+                        proof_key.to_owned(),
+                        &job_witness,
+                        expires,
+                    )
+                    .await;
+                    if let Err(err) = &result {
+                        error!("Failed to publish proof {}: {err}", hex::encode(&proof_key));
+                    }
+                    let result = result.map(|()| proof_key).map_err(|err| err.to_string());
+                    (key, JobInput::Published(result))
+                }));
+            }
+            JobAction::AnnounceProof(proof_key) => {
+                info!(
+                    "job {} proof with key: {} stored in DHT",
+                    hex::encode(job_key),
+                    hex::encode(&proof_key)
+                );
+                self.gossip(Topic::Delegation, &DelegationMessage::Finished(proof_key, key))
+                    .await?;
+            }
+            JobAction::Reject(reason) => {
+                warn!("Rejected job {}: {reason}", hex::encode(job_key));
+                let rejection = JobRejection { identity: self.identity, job_key: key, reason };
+                self.gossip(Topic::Delegation, &DelegationMessage::Rejected(rejection)).await?;
+            }
+            JobAction::Abort => {
+                if let Some(abort) = self.aborts.remove(job_key) {
+                    let _ = abort.try_send(());
+                }
+            }
+            JobAction::Return(reason) => {
+                info!("Handing back job {}: {reason}", hex::encode(job_key));
+                let rejection = JobRejection { identity: self.identity, job_key: key, reason };
+                self.gossip(Topic::Delegation, &DelegationMessage::Returned(rejection)).await?;
+            }
+        }
+        Ok(None)
+    }
+
+    async fn gossip<T: WireMessage>(&self, topic: Topic, message: &T) -> Result<(), Error> {
+        let data = wire::encode(message)?;
+        self.gossipsub_tx.send(GossipsubMessage { topic: topic.into(), data }).await?;
+        Ok(())
+    }
+
+    // Price of a bid at the current load.
+    fn price(&self) -> u64 {
+        self.bid_policy.floor(self.settings.price(self.load()))
+    }
+
+    // Running jobs weigh in once and proving jobs twice.
+    fn load(&self) -> u64 {
+        self.jobs.values().fold(0, |load, job| match job.state {
+            JobState::Running => load + 1,
+            JobState::Proving => load + 2,
+            _ => load,
+        })
+    }
+
+    fn active(&self) -> usize {
+        self.jobs.values().filter(|job| job.state.is_active()).count()
+    }
+
+    fn queue_depth(&self) -> u64 {
+        let queued = |state: &JobState| {
+            matches!(state, JobState::Validating | JobState::Running | JobState::Proving)
+        };
+        self.jobs.values().filter(|job| queued(&job.state)).count() as u64
+    }
+
+    // Progress of the delegated jobs in progress, the longest running first.
+    fn statuses(&self) -> Vec<JobStatus> {
+        let now = Instant::now();
+        let mut statuses: Vec<JobStatus> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.state.is_active())
+            .map(|(job_key, job)| JobStatus {
+                job_key: hex::encode(job_key),
+                stage: job.state.stage().to_string(),
+                step: job.state.step().unwrap_or_default(),
+                steps: PIPELINE_STEPS,
+                elapsed_secs: job.delegated_at.map_or(0, |at| (now - at).as_secs()),
+                stage_elapsed_secs: (now - job.stage_since).as_secs(),
+            })
+            .collect();
+        statuses.sort_by(|a, b| b.elapsed_secs.cmp(&a.elapsed_secs));
+        statuses
+    }
+
+    // Forgets finished and failed jobs, and jobs bid on but never delegated, once their deadline passed.
+    fn prune(&mut self, now: Instant) {
+        self.jobs.retain(|_, job| match job.expires_at {
+            Some(expires_at) => expires_at > now || job.state.is_active(),
+            None => !job.state.is_terminal(),
         });
+        let jobs = &self.jobs;
+        self.delegators.retain(|job_key, (_, announced_at)| {
+            jobs.contains_key(job_key) || now.duration_since(*announced_at) < ANNOUNCEMENT_TTL
+        });
+    }
+}
+
+// State of a job, its deadline once fetched, and when it was delegated and entered its stage.
+struct TrackedJob {
+    state: JobState,
+    expires_at: Option<Instant>,
+    delegated_at: Option<Instant>,
+    stage_since: Instant,
+}
+
+// Waits for a process to finish, aborting it when asked to through the abort channel.
+async fn supervise<T>(mut process: Process<'_, T>, mut abort_rx: mpsc::Receiver<()>) -> T {
+    tokio::select! {
+        output = &mut process => output,
+        Some(()) = abort_rx.recv() => {
+            if let Err(err) = process.abort().await {
+                warn!("Failed to abort process: {err}");
+            }
+            process.await
+        }
+    }
+}
+
+// Resolves the job record to its blob root and fetches the job it points to.
+async fn fetch_job(
+    kademlia: &KademliaClient,
+    blobs: &BlobClient,
+    job_key: kad::RecordKey,
+) -> Result<Job, Error> {
+    let root =
+        wire::decode::<BlobHash>(&kademlia.get_record(job_key.to_owned()).await?, MAX_RECORD_SIZE)?;
+    // The deadline is only known once the job is fetched, its chunks are kept for the longest job ttl.
+    let job =
+        wire::decode::<Job>(&blobs.get(root, Instant::now() + MAX_JOB_TTL).await?, MAX_BLOB_SIZE)?;
+    // The record may be overwritten by any peer, only a job hashing to its key is the one delegated.
+    if kad::RecordKey::new(&hash!(job).to_be_bytes()) != job_key {
+        return Err(Error::KeyMismatch(hex::encode(job_key)));
     }
+    Ok(job)
+}
+
+// Stores the job witness as a blob and publishes its root under the proof key.
+async fn publish_proof(
+    kademlia: &KademliaClient,
+    blobs: &BlobClient,
+    proof_key: kad::RecordKey,
+    job_witness: &JobWitness,
+    expires: Instant,
+) -> Result<(), Error> {
+    let root = blobs.put(wire::encode(job_witness)?, expires).await?;
+    debug!("Stored proof {} as blob {root}", hex::encode(&proof_key));
+    kademlia.put_record(proof_key, wire::encode(&root)?, None).await?;
+    Ok(())
 }
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("prover_controller_error")]
+    #[error("prover controller error: {0}")]
     ProverControllerError(#[from] ProverControllerError),
 
-    #[error("runner_controller_error")]
+    #[error("runner controller error: {0}")]
     RunnerControllerError(#[from] RunnerControllerError),
 
-    #[error("mpsc_send_error GossipsubMessage")]
+    #[error("gossipsub message send error: {0}")]
     MpscSendErrorGossipsubMessage(#[from] mpsc::error::SendError<GossipsubMessage>),
 
-    #[error("mpsc_send_error KademliaMessage")]
-    MpscSendErrorKademliaMessage(#[from] mpsc::error::SendError<KademliaMessage>),
-
-    #[error("io")]
+    #[error("io error: {0}")]
     Io(#[from] std::io::Error),
 
-    #[error("serde")]
+    #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
+
+    #[error("wire error: {0}")]
+    Wire(#[from] WireError),
+
+    #[error("blob error: {0}")]
+    Blob(#[from] BlobError),
+
+    #[error("query error: {0}")]
+    Query(#[from] QueryError),
+
+    #[error("fetched content does not match key {0}")]
+    KeyMismatch(String),
 }
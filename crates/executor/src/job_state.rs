@@ -0,0 +1,489 @@
+use libp2p::kad;
+use zetina_common::{job::Job, job_trace::JobTrace, job_witness::JobWitness};
+
+/*
+    Executor Job State
+    Lifecycle of a job on this executor, from the bid to the delegated job being validated, run, proven and published:
+    Idle → Checking → Bid → Fetching → Validating → Running → Proving → Publishing → Finished/Failed.
+    A job proven before goes from running straight to publishing with its cached proof, which was submitted
+    to the registry when it was made and is not submitted again, the registry would charge the delegator twice.
+    Transitions are pure, the current state and an input give the next state and the actions the driver performs,
+    inputs that do not apply to the current state like repeated delegations give no transition at all.
+    Once the job is delegated every failure is reported back to the delegator as a rejection,
+    before that a failed job simply goes back to idle and is forgotten.
+    While the executor drains, jobs that did not start running are handed back to their delegator right away,
+    running jobs are handed back only once the grace period is over.
+    An operator can abort a delegated job until it settles, the job is then rejected to its delegator.
+*/
+
+#[derive(Debug, Clone, Default)]
+pub enum JobState {
+    // Nothing is known about the job.
+    #[default]
+    Idle,
+    // Job is fetched and priced against the balance of its delegator, the job is set once fetched.
+    Checking {
+        job: Option<Job>,
+    },
+    // Bid is published, the job is kept when it was already fetched.
+    Bid {
+        job: Option<Job>,
+    },
+    // Delegated job is being fetched.
+    Fetching,
+    Validating,
+    Running,
+    Proving,
+    // Proof is being stored in the DHT.
+    Publishing,
+    Finished,
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub enum JobInput {
+    // Job is up for bidding, with the price to bid or None when it has to be fetched to be priced.
+    Announced { price: Option<u64> },
+    // Price the job is worth bidding at, None to skip it.
+    Priced(Option<u64>),
+    Delegated,
+    // Job was delegated to another executor.
+    Outbid,
+    Fetched(Result<Job, String>),
+    Validated(Result<Job, String>),
+    Ran(Result<JobTrace, String>),
+    // Job was proven before, its cached proof stands in for running and proving it.
+    Cached(JobWitness),
+    Proved(Result<JobWitness, String>),
+    Published(Result<kad::RecordKey, String>),
+    // An action of the job failed in the driver.
+    Failed(String),
+    // Executor started draining.
+    Drain,
+    // Grace period of the drain is over.
+    Abandon,
+    // Operator aborted the job through the admin API.
+    Aborted,
+}
+
+#[derive(Debug)]
+pub enum JobAction {
+    FetchJob,
+    CheckBalance(Job),
+    Bid(u64),
+    Validate(Job),
+    Run(Job),
+    Prove(JobTrace),
+    CacheWitness(JobWitness),
+    SubmitWitness(JobWitness),
+    PublishProof(JobWitness),
+    AnnounceProof(kad::RecordKey),
+    Reject(String),
+    Abort,
+    Return(String),
+}
+
+// Reason given to delegators for jobs handed back by a draining executor.
+pub const DRAIN_REASON: &str = "executor is shutting down";
+
+// Reason given to delegators for jobs delegated while the executor already works on its maximum of jobs.
+pub const CAPACITY_REASON: &str = "executor is at capacity";
+
+// Reason given to delegators for jobs aborted by the operator.
+pub const ABORT_REASON: &str = "aborted by executor operator";
+
+pub const PIPELINE_STEPS: usize = 5;
+
+impl JobState {
+    pub fn next(&self, input: JobInput) -> Option<(JobState, Vec<JobAction>)> {
+        use JobAction::*;
+        use JobInput as Input;
+
+        let next = match (self, input) {
+            (Self::Idle, Input::Announced { price: Some(price) }) => (Self::Idle, vec![Bid(price)]),
+            (Self::Idle, Input::Announced { price: None }) => {
+                (Self::Checking { job: None }, vec![FetchJob])
+            }
+            (Self::Checking { job: None }, Input::Fetched(Ok(job))) => {
+                (Self::Checking { job: Some(job.to_owned()) }, vec![CheckBalance(job)])
+            }
+            (Self::Checking { job: Some(job) }, Input::Priced(Some(price))) => {
+                (Self::Bid { job: Some(job.to_owned()) }, vec![Bid(price)])
+            }
+            (Self::Checking { job: None }, Input::Fetched(Err(_)) | Input::Failed(_))
+            | (Self::Checking { job: Some(_) }, Input::Priced(None) | Input::Failed(_))
+            | (Self::Bid { .. }, Input::Failed(_))
+            | (
+                Self::Checking { .. } | Self::Bid { .. },
+                Input::Outbid | Input::Drain | Input::Abandon,
+            ) => (Self::Idle, vec![]),
+            (Self::Idle | Self::Bid { job: None }, Input::Delegated) => {
+                (Self::Fetching, vec![FetchJob])
+            }
+            // A job delegated while it is still being fetched for the balance check uses that fetch.
+            (Self::Checking { job: None }, Input::Delegated) => (Self::Fetching, vec![]),
+            (
+                Self::Checking { job: Some(job) } | Self::Bid { job: Some(job) },
+                Input::Delegated,
+            ) => (Self::Validating, vec![Validate(job.to_owned())]),
+            (Self::Fetching, Input::Fetched(Ok(job))) => (Self::Validating, vec![Validate(job)]),
+            (Self::Fetching, Input::Fetched(Err(reason))) => {
+                rejected(format!("failed to fetch job: {reason}"))
+            }
+            (Self::Validating, Input::Validated(Ok(job))) => (Self::Running, vec![Run(job)]),
+            (Self::Validating, Input::Validated(Err(reason))) => rejected(reason),
+            (Self::Running, Input::Ran(Ok(job_trace))) => (Self::Proving, vec![Prove(job_trace)]),
+            (Self::Running, Input::Ran(Err(reason))) => {
+                rejected(format!("failed to run job: {reason}"))
+            }
+            (Self::Running, Input::Cached(job_witness)) => {
+                (Self::Publishing, vec![PublishProof(job_witness)])
+            }
+            (Self::Proving, Input::Proved(Ok(job_witness))) => (
+                Self::Publishing,
+                vec![
+                    CacheWitness(job_witness.to_owned()),
+                    SubmitWitness(job_witness.to_owned()),
+                    PublishProof(job_witness),
+                ],
+            ),
+            (Self::Proving, Input::Proved(Err(reason))) => {
+                rejected(format!("failed to prove job: {reason}"))
+            }
+            (Self::Publishing, Input::Published(Ok(proof_key))) => {
+                (Self::Finished, vec![AnnounceProof(proof_key)])
+            }
+            (Self::Publishing, Input::Published(Err(reason))) => {
+                rejected(format!("failed to publish proof: {reason}"))
+            }
+            (state, Input::Failed(reason)) if state.is_delegated() && !state.is_terminal() => {
+                rejected(reason)
+            }
+            (Self::Fetching | Self::Validating, Input::Drain | Input::Abandon) => {
+                (Self::Failed(DRAIN_REASON.to_string()), vec![Return(DRAIN_REASON.to_string())])
+            }
+            (Self::Running | Self::Proving, Input::Abandon) => (
+                Self::Failed(DRAIN_REASON.to_string()),
+                vec![Abort, Return(DRAIN_REASON.to_string())],
+            ),
+            (Self::Publishing, Input::Abandon) => {
+                (Self::Failed(DRAIN_REASON.to_string()), vec![Return(DRAIN_REASON.to_string())])
+            }
+            (Self::Running | Self::Proving, Input::Aborted) => (
+                Self::Failed(ABORT_REASON.to_string()),
+                vec![Abort, Reject(ABORT_REASON.to_string())],
+            ),
+            (Self::Fetching | Self::Validating | Self::Publishing, Input::Aborted) => {
+                rejected(ABORT_REASON.to_string())
+            }
+            _ => return None,
+        };
+        Some(next)
+    }
+
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Finished | Self::Failed(_))
+    }
+
+    // Whether the job was delegated to this executor, repeated delegations of it are ignored.
+    pub fn is_delegated(&self) -> bool {
+        !matches!(self, Self::Idle | Self::Checking { .. } | Self::Bid { .. })
+    }
+
+    // Whether the job is delegated and still in progress, a draining executor waits for it.
+    pub fn is_active(&self) -> bool {
+        self.is_delegated() && !self.is_terminal()
+    }
+
+    // Position of a delegated job in its pipeline, from fetching at 1 to publishing at PIPELINE_STEPS.
+    pub fn step(&self) -> Option<usize> {
+        match self {
+            Self::Fetching => Some(1),
+            Self::Validating => Some(2),
+            Self::Running => Some(3),
+            Self::Proving => Some(4),
+            Self::Publishing => Some(5),
+            _ => None,
+        }
+    }
+
+    // Name of the stage the job is in.
+    pub fn stage(&self) -> &'static str {
+        match self {
+            Self::Idle => "idle",
+            Self::Checking { .. } => "checking",
+            Self::Bid { .. } => "bid",
+            Self::Fetching => "fetching",
+            Self::Validating => "validating",
+            Self::Running => "running",
+            Self::Proving => "proving",
+            Self::Publishing => "publishing",
+            Self::Finished => "finished",
+            Self::Failed(_) => "failed",
+        }
+    }
+}
+
+fn rejected(reason: String) -> (JobState, Vec<JobAction>) {
+    (JobState::Failed(reason.to_owned()), vec![JobAction::Reject(reason)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use starknet::core::types::FieldElement;
+    use zetina_common::job::JobData;
+
+    fn job() -> Job {
+        Job {
+            job_data: JobData::new(vec![1, 2, 3], u64::MAX),
+            public_key: FieldElement::ZERO,
+            signature_r: FieldElement::ZERO,
+            signature_s: FieldElement::ZERO,
+        }
+    }
+
+    fn job_witness() -> JobWitness {
+        JobWitness { job_key: kad::RecordKey::new(&[1]), proof: vec![7] }
+    }
+
+    fn next(state: JobState, input: JobInput) -> (JobState, Vec<JobAction>) {
+        state.next(input).unwrap()
+    }
+
+    #[test]
+    fn priced_announcement_is_bid_on_directly() {
+        let (state, actions) = next(JobState::Idle, JobInput::Announced { price: Some(3) });
+        assert!(matches!(state, JobState::Idle));
+        assert!(matches!(actions[..], [JobAction::Bid(3)]));
+    }
+
+    #[test]
+    fn unpriced_announcement_fetches_job() {
+        let (state, actions) = next(JobState::Idle, JobInput::Announced { price: None });
+        assert!(matches!(state, JobState::Checking { job: None }));
+        assert!(matches!(actions[..], [JobAction::FetchJob]));
+    }
+
+    #[test]
+    fn fetched_job_is_priced() {
+        let (state, actions) = next(JobState::Checking { job: None }, JobInput::Fetched(Ok(job())));
+        assert!(matches!(state, JobState::Checking { job: Some(_) }));
+        assert!(matches!(actions[..], [JobAction::CheckBalance(_)]));
+    }
+
+    #[test]
+    fn covered_job_is_bid_on() {
+        let state = JobState::Checking { job: Some(job()) };
+        let (state, actions) = next(state, JobInput::Priced(Some(2)));
+        assert!(matches!(state, JobState::Bid { job: Some(_) }));
+        assert!(matches!(actions[..], [JobAction::Bid(2)]));
+    }
+
+    #[test]
+    fn uncovered_job_is_forgotten() {
+        let (state, actions) =
+            next(JobState::Checking { job: Some(job()) }, JobInput::Priced(None));
+        assert!(matches!(state, JobState::Idle));
+        assert!(actions.is_empty());
+        let input = JobInput::Fetched(Err("not found".to_string()));
+        assert!(matches!(next(JobState::Checking { job: None }, input).0, JobState::Idle));
+    }
+
+    #[test]
+    fn outbid_job_is_forgotten() {
+        for state in [JobState::Checking { job: None }, JobState::Bid { job: Some(job()) }] {
+            let (state, actions) = next(state, JobInput::Outbid);
+            assert!(matches!(state, JobState::Idle));
+            assert!(actions.is_empty());
+        }
+    }
+
+    #[test]
+    fn delegation_fetches_unknown_job() {
+        for state in [JobState::Idle, JobState::Bid { job: None }] {
+            let (state, actions) = next(state, JobInput::Delegated);
+            assert!(matches!(state, JobState::Fetching));
+            assert!(matches!(actions[..], [JobAction::FetchJob]));
+        }
+    }
+
+    #[test]
+    fn delegation_validates_fetched_job() {
+        let (state, actions) = next(JobState::Bid { job: Some(job()) }, JobInput::Delegated);
+        assert!(matches!(state, JobState::Validating));
+        assert!(matches!(actions[..], [JobAction::Validate(_)]));
+    }
+
+    #[test]
+    fn delegation_during_balance_check_reuses_fetch() {
+        let (state, actions) = next(JobState::Checking { job: None }, JobInput::Delegated);
+        assert!(matches!(state, JobState::Fetching));
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn repeated_delegation_is_ignored() {
+        let states = [
+            JobState::Fetching,
+            JobState::Validating,
+            JobState::Running,
+            JobState::Proving,
+            JobState::Publishing,
+            JobState::Finished,
+            JobState::Failed(String::new()),
+        ];
+        for state in states {
+            assert!(state.next(JobInput::Delegated).is_none(), "{}", state.stage());
+        }
+    }
+
+    #[test]
+    fn unfetched_delegated_job_is_rejected() {
+        let (state, actions) =
+            next(JobState::Fetching, JobInput::Fetched(Err("not found".to_string())));
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(
+            matches!(&actions[..], [JobAction::Reject(reason)] if reason.contains("not found"))
+        );
+    }
+
+    #[test]
+    fn fetched_delegated_job_is_validated() {
+        let (state, actions) = next(JobState::Fetching, JobInput::Fetched(Ok(job())));
+        assert!(matches!(state, JobState::Validating));
+        assert!(matches!(actions[..], [JobAction::Validate(_)]));
+    }
+
+    #[test]
+    fn valid_job_is_run() {
+        let (state, actions) = next(JobState::Validating, JobInput::Validated(Ok(job())));
+        assert!(matches!(state, JobState::Running));
+        assert!(matches!(actions[..], [JobAction::Run(_)]));
+    }
+
+    #[test]
+    fn invalid_job_is_rejected() {
+        let input = JobInput::Validated(Err("too many steps".to_string()));
+        let (state, actions) = next(JobState::Validating, input);
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(&actions[..], [JobAction::Reject(reason)] if reason == "too many steps"));
+    }
+
+    #[test]
+    fn failed_run_is_rejected() {
+        let (state, actions) = next(JobState::Running, JobInput::Ran(Err("exit 1".to_string())));
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Reject(_)]));
+    }
+
+    #[test]
+    fn proven_job_is_cached_and_published() {
+        let (state, actions) = next(JobState::Proving, JobInput::Proved(Ok(job_witness())));
+        assert!(matches!(state, JobState::Publishing));
+        assert!(matches!(
+            actions[..],
+            [JobAction::CacheWitness(_), JobAction::SubmitWitness(_), JobAction::PublishProof(_)]
+        ));
+    }
+
+    #[test]
+    fn cached_job_is_published_without_proving_or_submitting() {
+        let (state, actions) = next(JobState::Running, JobInput::Cached(job_witness()));
+        assert!(matches!(state, JobState::Publishing));
+        assert!(matches!(actions[..], [JobAction::PublishProof(_)]));
+    }
+
+    #[test]
+    fn failed_proof_is_rejected() {
+        let (state, actions) = next(JobState::Proving, JobInput::Proved(Err("oom".to_string())));
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Reject(_)]));
+    }
+
+    #[test]
+    fn published_proof_is_announced() {
+        let proof_key = kad::RecordKey::new(&[2]);
+        let input = JobInput::Published(Ok(proof_key.to_owned()));
+        let (state, actions) = next(JobState::Publishing, input);
+        assert!(matches!(state, JobState::Finished));
+        assert!(matches!(&actions[..], [JobAction::AnnounceProof(key)] if *key == proof_key));
+    }
+
+    #[test]
+    fn unpublished_proof_is_rejected() {
+        let input = JobInput::Published(Err("quorum failed".to_string()));
+        let (state, actions) = next(JobState::Publishing, input);
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Reject(_)]));
+    }
+
+    #[test]
+    fn driver_failure_rejects_delegated_job_only() {
+        let (state, actions) = next(JobState::Running, JobInput::Failed("closed".to_string()));
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Reject(_)]));
+
+        let (state, actions) = next(JobState::Bid { job: None }, JobInput::Failed(String::new()));
+        assert!(matches!(state, JobState::Idle));
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn drain_hands_back_jobs_not_running() {
+        for state in [JobState::Fetching, JobState::Validating] {
+            let (state, actions) = next(state, JobInput::Drain);
+            assert!(matches!(state, JobState::Failed(_)));
+            assert!(matches!(actions[..], [JobAction::Return(_)]));
+        }
+        let (state, actions) = next(JobState::Bid { job: None }, JobInput::Drain);
+        assert!(matches!(state, JobState::Idle));
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn aborted_running_job_is_stopped_and_rejected() {
+        for state in [JobState::Running, JobState::Proving] {
+            let (state, actions) = next(state, JobInput::Aborted);
+            assert!(matches!(state, JobState::Failed(_)));
+            assert!(matches!(actions[..], [JobAction::Abort, JobAction::Reject(_)]));
+        }
+        let (state, actions) = next(JobState::Validating, JobInput::Aborted);
+        assert!(matches!(state, JobState::Failed(_)));
+        assert!(matches!(actions[..], [JobAction::Reject(_)]));
+    }
+
+    #[test]
+    fn settled_and_undelegated_jobs_cannot_be_aborted() {
+        for state in [JobState::Bid { job: None }, JobState::Finished] {
+            assert!(state.next(JobInput::Aborted).is_none(), "{}", state.stage());
+        }
+    }
+
+    #[test]
+    fn drain_lets_running_jobs_finish() {
+        for state in [JobState::Running, JobState::Proving, JobState::Publishing] {
+            assert!(state.next(JobInput::Drain).is_none(), "{}", state.stage());
+        }
+    }
+
+    #[test]
+    fn abandon_aborts_and_hands_back_running_jobs() {
+        for state in [JobState::Running, JobState::Proving] {
+            let (state, actions) = next(state, JobInput::Abandon);
+            assert!(matches!(state, JobState::Failed(_)));
+            assert!(matches!(actions[..], [JobAction::Abort, JobAction::Return(_)]));
+        }
+        let (_, actions) = next(JobState::Publishing, JobInput::Abandon);
+        assert!(matches!(actions[..], [JobAction::Return(_)]));
+        assert!(JobState::Finished.next(JobInput::Abandon).is_none());
+    }
+
+    #[test]
+    fn terminal_states_ignore_inputs() {
+        for state in [JobState::Finished, JobState::Failed(String::new())] {
+            assert!(state.next(JobInput::Failed(String::new())).is_none());
+            assert!(state.next(JobInput::Published(Err(String::new()))).is_none());
+        }
+    }
+}
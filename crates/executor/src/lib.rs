@@ -0,0 +1,7 @@
+pub mod admin;
+pub mod api;
+pub mod bidding;
+pub mod cache;
+pub mod executor;
+pub mod job_state;
+pub mod validation;
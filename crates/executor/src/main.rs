@@ -1,17 +1,49 @@
-pub mod api;
-pub mod executor;
-
-use axum::{routing::get, Router};
+use axum::{
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use cairo_vm::{program_hash::compute_program_hash_chain, types::program::Program};
 use clap::Parser;
-use executor::Executor;
-use libp2p::Multiaddr;
+use libp2p::{Multiaddr, PeerId};
 use starknet::{core::types::FieldElement, signers::SigningKey};
-use std::{str::FromStr, time::Duration};
+use std::{
+    future::IntoFuture,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{net::TcpListener, sync::mpsc};
+use tokio_util::sync::CancellationToken;
 use tower_http::{timeout::TimeoutLayer, trace::TraceLayer};
+use tracing::warn;
 use tracing_subscriber::EnvFilter;
-use zetina_common::graceful_shutdown::shutdown_signal;
-use zetina_peer::{GossipsubMessage, KademliaMessage, SwarmRunner};
+use zetina_common::{
+    capability::ExecutorCapabilities,
+    layout::Layout,
+    process::ProcessLimits,
+    registry::{BalanceOracle, RegistryClient, RegistryConfig},
+    workspace::{Retention, WorkspaceConfig},
+};
+use zetina_executor::{
+    admin::{AdminClient, ExecutorSettings},
+    api::{self, ServerState},
+    bidding::{BidPolicy, InsufficientBalancePolicy, DEFAULT_MIN_PRICE},
+    cache::{WitnessCache, DEFAULT_WITNESS_CACHE_BYTES},
+    executor::{Executor, DEFAULT_GRACE_PERIOD},
+    validation::{JobValidator, ValidationConfig},
+};
+use zetina_peer::{
+    access::{read_swarm_key, AccessConfig},
+    args::{ConnectionArgs, QueryArgs},
+    blob::{BlobClient, BlobMessage, BlobStoreConfig, DEFAULT_BLOB_STORE_BYTES},
+    connection::ConnectionConfig,
+    query::{KademliaClient, QueryConfig},
+    store::{DiskStoreConfig, StoreConfig},
+    GossipsubMessage, KademliaMessage, NatConfig, SwarmRunner,
+};
 use zetina_prover::stone_prover::StoneProver;
 use zetina_runner::cairo_runner::CairoRunner;
 
@@ -24,11 +56,150 @@ struct Cli {
     #[arg(short, long)]
     listen_address: String,
 
+    /// Publicly reachable multiaddr, detected through AutoNAT when omitted
     #[arg(short, long)]
-    address: String,
+    address: Option<String>,
 
     #[arg(short, long)]
     dial_addresses: Vec<String>,
+
+    /// Relays to listen through when this node is behind a NAT, including their /p2p/ peer id
+    #[arg(long)]
+    relay_addresses: Vec<String>,
+
+    /// Act as a circuit relay for peers behind a NAT
+    #[arg(long)]
+    relay_server: bool,
+
+    /// Directory to persist DHT records and blob chunks in, both are kept in memory when omitted
+    #[arg(long)]
+    record_store_path: Option<PathBuf>,
+
+    /// Maximum total size of the DHT record values in bytes, records are held in memory and persisted
+    #[arg(long, requires = "record_store_path")]
+    record_store_max_bytes: Option<u64>,
+
+    /// Maximum total size of the blob chunks kept and served to other peers in bytes
+    #[arg(long)]
+    blob_store_max_bytes: Option<usize>,
+
+    /// Swarm key of a private network, only peers holding the same key can connect
+    #[arg(long)]
+    swarm_key: Option<PathBuf>,
+
+    /// Peers allowed to connect, every peer is admitted when none are given
+    #[arg(long)]
+    allowed_peers: Vec<String>,
+
+    /// Peers never allowed to connect
+    #[arg(long)]
+    denied_peers: Vec<String>,
+
+    #[command(flatten)]
+    query: QueryArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Starknet JSON-RPC endpoint used to submit proofs to the registry
+    #[arg(long, requires_all = ["registry_address", "account_address"])]
+    registry_rpc_url: Option<String>,
+
+    /// Address of the ZetinaRegistry contract as a hex string
+    #[arg(long, requires = "registry_rpc_url")]
+    registry_address: Option<String>,
+
+    /// Address of the Starknet account that submits proofs and receives rewards
+    #[arg(long, requires = "registry_rpc_url")]
+    account_address: Option<String>,
+
+    /// Check delegator balances in the registry before bidding on their jobs
+    #[arg(long, requires = "registry_rpc_url")]
+    check_balance: bool,
+
+    /// Lowest price bid in registry token base units, delegators whose balance does not cover it are skipped
+    #[arg(long, default_value_t = DEFAULT_MIN_PRICE)]
+    min_price: u64,
+
+    /// What to do with jobs whose delegator balance does not cover the bid price
+    #[arg(long, value_enum, default_value_t = InsufficientBalancePolicy::Skip)]
+    insufficient_balance: InsufficientBalancePolicy,
+
+    /// Maximum size of a compressed Cairo PIE in bytes
+    #[arg(long)]
+    max_pie_size: Option<usize>,
+
+    /// Maximum number of steps of a Cairo PIE
+    #[arg(long)]
+    max_steps: Option<usize>,
+
+    /// Hint code a Cairo PIE program may carry, PIEs with any other hint are rejected
+    #[arg(long)]
+    allowed_hints: Vec<String>,
+
+    /// Seconds a draining executor waits for running jobs before handing them back
+    #[arg(long)]
+    drain_grace_period: Option<u64>,
+
+    /// Stone prover version advertised to delegators, the hash of the installed binary when omitted
+    #[arg(long)]
+    prover_version: Option<String>,
+
+    /// Address the admin API listens on, keep it on a private interface or set an admin token
+    #[arg(long, default_value = "127.0.0.1:3001")]
+    admin_address: SocketAddr,
+
+    /// Bearer token the admin API requires, admin requests are not authenticated when omitted
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Maximum number of delegated jobs worked on at once, no limit when omitted
+    #[arg(long)]
+    max_jobs: Option<usize>,
+
+    /// Base price of a bid in registry token base units
+    #[arg(long)]
+    base_price: Option<u64>,
+
+    /// Price in registry token base units added for every unit of load, a running job weighs one and a proving job two
+    #[arg(long)]
+    load_price: Option<u64>,
+
+    /// Seconds cairo-run may take on a job before it is killed
+    #[arg(long)]
+    run_timeout: Option<u64>,
+
+    /// Seconds the prover may take on a job before it is killed
+    #[arg(long)]
+    prove_timeout: Option<u64>,
+
+    /// Maximum address space of the runner and prover processes in bytes
+    #[arg(long)]
+    max_process_memory: Option<u64>,
+
+    /// Maximum CPU time of the runner and prover processes in seconds
+    #[arg(long)]
+    max_process_cpu_time: Option<u64>,
+
+    /// Directory to cache proofs in, so jobs delegated again are not proven twice
+    #[arg(long)]
+    witness_cache_path: Option<PathBuf>,
+
+    /// Maximum total size of the cached proofs in bytes
+    #[arg(long, requires = "witness_cache_path")]
+    witness_cache_max_bytes: Option<u64>,
+
+    /// Directory the per-job workspaces of the runner and prover are created in
+    #[arg(long)]
+    workspace_root: Option<PathBuf>,
+
+    /// Free disk space in bytes a job needs under the workspace root to be run
+    #[arg(long)]
+    workspace_min_free_bytes: Option<u64>,
+
+    /// Which job workspaces are kept on disk for debugging: never, on-failure or always
+    #[arg(long, default_value_t = Retention::Never)]
+    keep_workspaces: Retention,
 }
 
 #[tokio::main]
@@ -56,6 +227,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     .join("../../");
     let bootloader_program_path = ws_root.join("target/bootloader.json");
 
+    let query_config = QueryConfig::from(&cli.query);
+
+    // Chunks are persisted next to the records pointing at them.
+    let blob_store_config = BlobStoreConfig {
+        path: cli.record_store_path.as_ref().map(|path| path.join("blobs")),
+        max_total_bytes: cli.blob_store_max_bytes.unwrap_or(DEFAULT_BLOB_STORE_BYTES),
+    };
+
     let swarm_runner = SwarmRunner::new(
         cli.listen_address.parse()?,
         cli.dial_addresses
@@ -63,31 +242,205 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .map(|addr| Multiaddr::from_str(addr))
             .collect::<Result<Vec<Multiaddr>, _>>()?,
         p2p_keypair,
-        Multiaddr::from_str(&cli.address)?,
+        cli.address.as_deref().map(Multiaddr::from_str).transpose()?,
+        NatConfig {
+            relay_multiaddrs: cli
+                .relay_addresses
+                .iter()
+                .map(|addr| Multiaddr::from_str(addr))
+                .collect::<Result<Vec<Multiaddr>, _>>()?,
+            relay_server: cli.relay_server,
+        },
+        match cli.record_store_path {
+            Some(path) => {
+                let default_store = DiskStoreConfig::new(path);
+                StoreConfig::Disk(DiskStoreConfig {
+                    max_total_bytes: cli
+                        .record_store_max_bytes
+                        .unwrap_or(default_store.max_total_bytes),
+                    ..default_store
+                })
+            }
+            None => StoreConfig::default(),
+        },
+        blob_store_config,
+        AccessConfig {
+            psk: cli.swarm_key.as_deref().map(read_swarm_key).transpose()?,
+            allowed_peers: cli
+                .allowed_peers
+                .iter()
+                .map(|peer_id| PeerId::from_str(peer_id))
+                .collect::<Result<Vec<PeerId>, _>>()?,
+            denied_peers: cli
+                .denied_peers
+                .iter()
+                .map(|peer_id| PeerId::from_str(peer_id))
+                .collect::<Result<Vec<PeerId>, _>>()?,
+        },
+        ConnectionConfig::from(&cli.connection),
+        query_config.to_owned(),
     )?;
 
     let (gossipsub_tx, gossipsub_rx) = mpsc::channel::<GossipsubMessage>(100);
     let (kademlia_tx, kademlia_rx) = mpsc::channel::<KademliaMessage>(100);
-    let swarm_events = swarm_runner.run(gossipsub_rx, kademlia_rx);
-
-    let runner = CairoRunner::new(bootloader_program_path, signing_key.verifying_key());
-    let prover = StoneProver::new();
-    Executor::new(identity, swarm_events, gossipsub_tx, kademlia_tx, runner, prover);
-
-    // Create a `TcpListener` using tokio.
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-
-    // Run the server with graceful shutdown
-    axum::serve(
-        listener,
-        Router::new().route("/health", get(api::health_check_handler)).layer((
-            TraceLayer::new_for_http(),
-            // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
-            // requests don't hang forever.
-            TimeoutLayer::new(Duration::from_secs(10)),
+    let (blob_tx, blob_rx) = mpsc::channel::<BlobMessage>(100);
+    let access_stats = swarm_runner.access_stats.to_owned();
+    let swarm_events = swarm_runner.run(gossipsub_rx, kademlia_rx, blob_rx);
+
+    let registry = match (cli.registry_rpc_url, cli.registry_address, cli.account_address) {
+        (Some(rpc_url), Some(registry_address), Some(account_address)) => Some(Arc::new(
+            RegistryClient::new(RegistryConfig {
+                rpc_url: rpc_url.parse()?,
+                registry_address: FieldElement::from_hex_be(&registry_address)?,
+            })
+            .with_account(FieldElement::from_hex_be(&account_address)?, signing_key.to_owned())
+            .await?,
         )),
+        _ => None,
+    };
+
+    let balance_oracle = match &registry {
+        Some(registry) if cli.check_balance => Some(registry.to_owned() as Arc<dyn BalanceOracle>),
+        _ => None,
+    };
+    let bid_policy = BidPolicy::new(balance_oracle, cli.insufficient_balance, cli.min_price);
+
+    let default_validation = ValidationConfig::default();
+    let validation_config = ValidationConfig {
+        max_pie_size: cli.max_pie_size.unwrap_or(default_validation.max_pie_size),
+        max_steps: cli.max_steps.unwrap_or(default_validation.max_steps),
+        allowed_hints: cli.allowed_hints,
+        ..default_validation
+    };
+
+    // Both are advertised in the capabilities jobs are matched against, so they are never guessed.
+    let bootloader_program_hash = bootloader_program_hash(&bootloader_program_path)
+        .map_err(|err| format!("failed to hash bootloader program: {err}"))?;
+    let prover_version = match cli.prover_version {
+        Some(prover_version) => prover_version,
+        None => StoneProver::version()?,
+    };
+    let capabilities = ExecutorCapabilities {
+        identity,
+        layouts: vec![Layout::Starknet],
+        max_steps: validation_config.max_steps,
+        max_memory_cells: validation_config.max_memory_cells,
+        prover_backend: StoneProver::BACKEND.to_string(),
+        prover_version: prover_version.to_owned(),
+        bootloader_program_hash,
+        queue_depth: 0,
+    };
+    let validator = Arc::new(JobValidator::new(validation_config));
+
+    let limits = ProcessLimits {
+        timeout: None,
+        max_memory: cli.max_process_memory,
+        max_cpu_time: cli.max_process_cpu_time.map(Duration::from_secs),
+    };
+    let defaults = WorkspaceConfig::default();
+    let workspaces = WorkspaceConfig {
+        root: cli.workspace_root.unwrap_or(defaults.root),
+        min_free_bytes: cli.workspace_min_free_bytes.unwrap_or(defaults.min_free_bytes),
+        retention: cli.keep_workspaces,
+    };
+    let runner = CairoRunner::new(bootloader_program_path, signing_key.verifying_key())
+        .with_limits(ProcessLimits {
+            timeout: Some(
+                cli.run_timeout.map(Duration::from_secs).unwrap_or(CairoRunner::DEFAULT_TIMEOUT),
+            ),
+            ..limits
+        })
+        .with_workspaces(workspaces);
+    let prover = StoneProver::new().with_limits(ProcessLimits {
+        timeout: Some(
+            cli.prove_timeout.map(Duration::from_secs).unwrap_or(StoneProver::DEFAULT_TIMEOUT),
+        ),
+        ..limits
+    });
+    // Proofs depend on the executor key, the bootloader and the prover that made them.
+    let witnesses = cli
+        .witness_cache_path
+        .map(|path| {
+            let params = format!(
+                "{identity}/{bootloader_program_hash:#x}/{}/{prover_version}",
+                StoneProver::BACKEND,
+            );
+            let max_bytes = cli.witness_cache_max_bytes.unwrap_or(DEFAULT_WITNESS_CACHE_BYTES);
+            WitnessCache::open(path, params, max_bytes)
+        })
+        .transpose()?;
+    let default_settings = ExecutorSettings::default();
+    let settings = ExecutorSettings {
+        max_jobs: cli.max_jobs,
+        base_price: cli.base_price.unwrap_or(default_settings.base_price),
+        load_price: cli.load_price.unwrap_or(default_settings.load_price),
+        ..default_settings
+    };
+    let (admin_tx, admin_rx) = mpsc::channel(100);
+    let executor = Executor::new(
+        identity,
+        swarm_events,
+        gossipsub_tx,
+        KademliaClient::new(kademlia_tx, query_config),
+        BlobClient::new(blob_tx),
+        runner,
+        prover,
+        witnesses,
+        registry,
+        bid_policy,
+        validator,
+        capabilities,
+        settings,
+        admin_rx,
+        cli.drain_grace_period.map(Duration::from_secs).unwrap_or(DEFAULT_GRACE_PERIOD),
+    );
+
+    // Health checks are public, the admin API listens on its own, by default private, address.
+    let listener = TcpListener::bind("0.0.0.0:3000").await?;
+    let admin_listener = TcpListener::bind(cli.admin_address).await?;
+    if cli.admin_token.is_none() && !cli.admin_address.ip().is_loopback() {
+        warn!("Admin API on {} is not authenticated, set --admin-token", cli.admin_address);
+    }
+
+    let admin_state =
+        ServerState { admin: AdminClient::new(admin_tx), access_stats, token: cli.admin_token };
+    let stopped = CancellationToken::new();
+    let health =
+        axum::serve(listener, Router::new().route("/health", get(api::health_check_handler)))
+            .with_graceful_shutdown(stopped.clone().cancelled_owned());
+
+    // Run the admin server until the executor drained, on a shutdown signal or through /drain
+    let admin = axum::serve(
+        admin_listener,
+        Router::new()
+            .route("/jobs", get(api::jobs_handler))
+            .route("/jobs/:job_key/abort", post(api::abort_handler))
+            .route("/bidding/pause", post(api::pause_bidding_handler))
+            .route("/bidding/resume", post(api::resume_bidding_handler))
+            .route("/settings", get(api::settings_handler).patch(api::update_settings_handler))
+            .route("/errors", get(api::errors_handler))
+            .route("/access", get(api::access_handler))
+            .route("/drain", post(api::drain_handler))
+            .route_layer(middleware::from_fn_with_state(admin_state.to_owned(), api::require_token))
+            .layer((
+                TraceLayer::new_for_http(),
+                // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
+                // requests don't hang forever.
+                TimeoutLayer::new(Duration::from_secs(10)),
+            ))
+            .with_state(admin_state),
     )
-    .with_graceful_shutdown(shutdown_signal())
-    .await?;
+    .with_graceful_shutdown(async move {
+        executor.join().await;
+        stopped.cancel();
+    });
+    tokio::try_join!(health.into_future(), admin.into_future())?;
     Ok(())
 }
+
+// Hash of the bootloader program the executor runs tasks with, advertised in its capabilities.
+fn bootloader_program_hash(path: &Path) -> Result<FieldElement, Box<dyn std::error::Error>> {
+    let program = Program::from_file(path, Some("main"))?;
+    let hash = compute_program_hash_chain(&program.get_stripped_program()?, 0)?;
+    Ok(FieldElement::from_bytes_be(&hash.to_bytes_be())?)
+}
@@ -0,0 +1,314 @@
+use cairo_vm::vm::runners::cairo_pie::CairoPie;
+use std::{sync::Arc, time::Duration};
+use thiserror::Error;
+use zetina_common::job::{Job, JobData, JobError, MEMORY_CELL_SIZE};
+
+/*
+    Job Validator
+    Checks a `Job` fetched from the DHT before the executor spends any resources on it.
+    The cheap checks on the compressed size and the deadline come first. The Cairo PIE is then decompressed once,
+    up to the size its memory cell limit allows, and the delegator signature, builtins, steps and hints are all
+    checked on that single copy.
+    Jobs whose signed deadline cannot be met, given a rough estimate of the proving time from the step count, are rejected too.
+    PIEs should carry a stripped program without hint code, a PIE whose program still lists hints is rejected
+    unless every one of them is on the configured allowlist.
+    Every check returns a typed `ValidationError` that can be reported back to the delegator.
+*/
+
+pub const DEFAULT_ALLOWED_BUILTINS: &[&str] =
+    &["output", "pedersen", "range_check", "ecdsa", "bitwise", "ec_op", "poseidon"];
+
+#[derive(Debug, Clone)]
+pub struct ValidationConfig {
+    pub max_pie_size: usize,
+    pub max_memory_cells: usize,
+    pub max_steps: usize,
+    pub allowed_builtins: Vec<String>,
+    pub allowed_hints: Vec<String>,
+    pub steps_per_second: usize,
+    pub proving_overhead: Duration,
+}
+
+impl ValidationConfig {
+    // Most bytes a PIE may inflate to, its memory at the cell limit and its JSON entries
+    // up to the compressed size limit.
+    pub fn max_uncompressed_size(&self) -> u64 {
+        (self.max_memory_cells as u64)
+            .saturating_mul(MEMORY_CELL_SIZE)
+            .saturating_add(self.max_pie_size as u64)
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_pie_size: 1024 * 1024 * 100,
+            max_memory_cells: 1 << 26,
+            max_steps: 1 << 24,
+            allowed_builtins: DEFAULT_ALLOWED_BUILTINS.iter().map(|b| b.to_string()).collect(),
+            allowed_hints: vec![],
+            steps_per_second: 1 << 16,
+            proving_overhead: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JobValidator {
+    config: ValidationConfig,
+}
+
+impl JobValidator {
+    pub fn new(config: ValidationConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn validate(&self, job: &Job) -> Result<(), ValidationError> {
+        let size = job.job_data.cairo_pie_compressed.len();
+        if size > self.config.max_pie_size {
+            return Err(ValidationError::PieTooLarge { size, limit: self.config.max_pie_size });
+        }
+
+        let time_left = job.job_data.time_left().ok_or(ValidationError::Expired)?;
+
+        let (pie, hints) = JobData::decompress_cairo_pie_with_hints(
+            &job.job_data.cairo_pie_compressed,
+            self.config.max_uncompressed_size(),
+        )?;
+        if !job.verify_signature_with(&pie)? {
+            return Err(ValidationError::InvalidSignature);
+        }
+
+        self.validate_pie(&pie)?;
+        if let Some(hint) = hints
+            .into_iter()
+            .find(|hint| !self.config.allowed_hints.iter().any(|allowed| allowed == hint))
+        {
+            return Err(ValidationError::DisallowedHint(hint));
+        }
+
+        let estimate = self.estimate(pie.execution_resources.n_steps);
+        if estimate > time_left {
+            return Err(ValidationError::DeadlineUnreachable { estimate, time_left });
+        }
+
+        Ok(())
+    }
+
+    // Rough upper bound of the time needed to run and prove a PIE with the given number of steps.
+    pub fn estimate(&self, steps: usize) -> Duration {
+        self.config.proving_overhead
+            + Duration::from_secs_f64(steps as f64 / self.config.steps_per_second.max(1) as f64)
+    }
+
+    // Runs the validation on the blocking pool, decompressing large PIEs would otherwise stall the executor loop.
+    pub async fn validate_owned(self: Arc<Self>, job: Job) -> Result<Job, ValidationError> {
+        tokio::task::spawn_blocking(move || self.validate(&job).map(|_| job))
+            .await
+            .map_err(|e| ValidationError::Aborted(e.to_string()))?
+    }
+
+    fn validate_pie(&self, pie: &CairoPie) -> Result<(), ValidationError> {
+        pie.run_validity_checks().map_err(|e| ValidationError::MalformedPie(e.to_string()))?;
+
+        if let Some(builtin) =
+            pie.metadata.program.builtins.iter().map(|builtin| builtin.to_str()).find(|builtin| {
+                !self.config.allowed_builtins.iter().any(|allowed| allowed == builtin)
+            })
+        {
+            return Err(ValidationError::DisallowedBuiltin(builtin.to_string()));
+        }
+
+        let steps = pie.execution_resources.n_steps;
+        if steps > self.config.max_steps {
+            return Err(ValidationError::TooManySteps { steps, limit: self.config.max_steps });
+        }
+
+        let cells = pie.memory.0.len();
+        if cells > self.config.max_memory_cells {
+            return Err(ValidationError::TooManyMemoryCells {
+                cells,
+                limit: self.config.max_memory_cells,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ValidationError {
+    #[error("compressed pie of {size} bytes exceeds the limit of {limit} bytes")]
+    PieTooLarge { size: usize, limit: usize },
+
+    #[error("invalid delegator signature")]
+    InvalidSignature,
+
+    #[error("job error: {0}")]
+    Job(#[from] JobError),
+
+    #[error("malformed pie: {0}")]
+    MalformedPie(String),
+
+    #[error("builtin {0} is not allowed")]
+    DisallowedBuiltin(String),
+
+    #[error("hint is not allowed: {0}")]
+    DisallowedHint(String),
+
+    #[error("pie has {steps} steps, the limit is {limit}")]
+    TooManySteps { steps: usize, limit: usize },
+
+    #[error("pie has {cells} memory cells, the limit is {limit}")]
+    TooManyMemoryCells { cells: usize, limit: usize },
+
+    #[error("job deadline has passed")]
+    Expired,
+
+    #[error("proving takes about {estimate:?}, only {time_left:?} left until the deadline")]
+    DeadlineUnreachable { estimate: Duration, time_left: Duration },
+
+    #[error("validation aborted: {0}")]
+    Aborted(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use starknet::{core::types::FieldElement, signers::SigningKey};
+    use std::{
+        env, fs,
+        io::{Cursor, Read, Write},
+        path::PathBuf,
+    };
+    use zetina_common::job::DEFAULT_JOB_TTL;
+    use zip::{write::FileOptions, ZipArchive, ZipWriter};
+
+    fn fixture() -> Job {
+        fixture_with_ttl(DEFAULT_JOB_TTL)
+    }
+
+    fn fixture_with_ttl(ttl: Duration) -> Job {
+        signed(fixture_pie(), ttl)
+    }
+
+    fn fixture_pie() -> Vec<u8> {
+        let ws_root = PathBuf::from(
+            env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR env not present"),
+        )
+        .join("../../");
+        fs::read(ws_root.join("crates/tests/cairo/fibonacci_pie.zip")).unwrap()
+    }
+
+    fn signed(cairo_pie_compressed: Vec<u8>, ttl: Duration) -> Job {
+        Job::try_from_job_data(
+            JobData::with_ttl(cairo_pie_compressed, ttl),
+            &SigningKey::from_random(),
+        )
+        .unwrap()
+    }
+
+    // Fixture PIE whose program lists a hint, the way a full program carries them.
+    fn fixture_with_hint(code: &str) -> Job {
+        let pie = fixture_pie();
+        let mut archive = ZipArchive::new(Cursor::new(pie.as_slice())).unwrap();
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).unwrap();
+            let mut data = vec![];
+            entry.read_to_end(&mut data).unwrap();
+            if entry.name() == "metadata.json" {
+                let mut metadata: serde_json::Value = serde_json::from_slice(&data).unwrap();
+                metadata["program"]["hints"] = serde_json::json!({ "0": [{ "code": code }] });
+                data = serde_json::to_vec(&metadata).unwrap();
+            }
+            writer.start_file(entry.name(), FileOptions::default()).unwrap();
+            writer.write_all(&data).unwrap();
+        }
+        signed(writer.finish().unwrap().into_inner(), DEFAULT_JOB_TTL)
+    }
+
+    #[test]
+    fn valid_job() {
+        JobValidator::default().validate(&fixture()).unwrap();
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let mut job = fixture();
+        job.signature_r = job.signature_r + FieldElement::ONE;
+        assert!(matches!(
+            JobValidator::default().validate(&job),
+            Err(ValidationError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn malformed_pie() {
+        let mut job = fixture();
+        job.job_data.cairo_pie_compressed = vec![0; 32];
+        assert!(matches!(JobValidator::default().validate(&job), Err(ValidationError::Job(_))));
+    }
+
+    #[test]
+    fn pie_too_large() {
+        let validator =
+            JobValidator::new(ValidationConfig { max_pie_size: 1, ..Default::default() });
+        assert!(matches!(validator.validate(&fixture()), Err(ValidationError::PieTooLarge { .. })));
+    }
+
+    #[test]
+    fn pie_inflating_beyond_memory_limit() {
+        let validator = JobValidator::new(ValidationConfig {
+            max_memory_cells: 1,
+            max_pie_size: 2048,
+            ..Default::default()
+        });
+        assert!(matches!(
+            validator.validate(&fixture()),
+            Err(ValidationError::Job(JobError::PieTooLarge { .. }))
+        ));
+    }
+
+    #[test]
+    fn expired_deadline() {
+        let job_data = JobData { deadline: 0, ..fixture().job_data };
+        let job = Job::try_from_job_data(job_data, &SigningKey::from_random()).unwrap();
+        assert!(matches!(JobValidator::default().validate(&job), Err(ValidationError::Expired)));
+    }
+
+    #[test]
+    fn unreachable_deadline() {
+        assert!(matches!(
+            JobValidator::default().validate(&fixture_with_ttl(Duration::from_secs(1))),
+            Err(ValidationError::DeadlineUnreachable { .. })
+        ));
+    }
+
+    #[test]
+    fn step_limit() {
+        let validator = JobValidator::new(ValidationConfig { max_steps: 1, ..Default::default() });
+        assert!(matches!(
+            validator.validate(&fixture()),
+            Err(ValidationError::TooManySteps { .. })
+        ));
+    }
+
+    #[test]
+    fn disallowed_hint() {
+        let job = fixture_with_hint("memory[ap] = 1");
+        assert!(matches!(
+            JobValidator::default().validate(&job),
+            Err(ValidationError::DisallowedHint(hint)) if hint == "memory[ap] = 1"
+        ));
+    }
+
+    #[test]
+    fn allowed_hint() {
+        let validator = JobValidator::new(ValidationConfig {
+            allowed_hints: vec!["memory[ap] = 1".to_string()],
+            ..Default::default()
+        });
+        validator.validate(&fixture_with_hint("memory[ap] = 1")).unwrap();
+    }
+}
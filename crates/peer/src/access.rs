@@ -0,0 +1,124 @@
+use libp2p::allow_block_list::{self, AllowedPeers, BlockedPeers};
+use libp2p::pnet::{KeyParseError, PreSharedKey};
+use libp2p::swarm::ConnectionDenied;
+use libp2p::PeerId;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+/*
+    Access Control
+    A private network is closed to outsiders at two levels.
+    With a pre-shared key every connection is wrapped in the key before anything else is exchanged,
+    peers without the key cannot even complete the handshake. QUIC cannot be wrapped, so private nodes only speak TCP.
+    On top of that the allowlist, when not empty, admits only the listed peers and the denylist always refuses its peers,
+    both are checked for inbound and outbound connections as soon as the identity of the remote peer is known.
+    Connections refused by the lists are logged and counted.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct AccessConfig {
+    pub psk: Option<PreSharedKey>,
+    pub allowed_peers: Vec<PeerId>, // Every peer is admitted when empty
+    pub denied_peers: Vec<PeerId>,
+}
+
+impl AccessConfig {
+    pub fn permits(&self, peer_id: &PeerId) -> bool {
+        (self.allowed_peers.is_empty() || self.allowed_peers.contains(peer_id))
+            && !self.denied_peers.contains(peer_id)
+    }
+
+    pub(crate) fn allow_list(&self) -> Option<allow_block_list::Behaviour<AllowedPeers>> {
+        (!self.allowed_peers.is_empty()).then(|| {
+            let mut behaviour = allow_block_list::Behaviour::<AllowedPeers>::default();
+            for peer_id in self.allowed_peers.iter() {
+                behaviour.allow_peer(*peer_id);
+            }
+            behaviour
+        })
+    }
+
+    pub(crate) fn deny_list(&self) -> allow_block_list::Behaviour<BlockedPeers> {
+        let mut behaviour = allow_block_list::Behaviour::<BlockedPeers>::default();
+        for peer_id in self.denied_peers.iter() {
+            behaviour.block_peer(*peer_id);
+        }
+        behaviour
+    }
+}
+
+// Reads a swarm key in the usual "/key/swarm/psk/1.0.0/" format shared with other libp2p implementations.
+pub fn read_swarm_key(path: &Path) -> Result<PreSharedKey, AccessError> {
+    Ok(fs::read_to_string(path)?.parse()?)
+}
+
+// Whether a connection was refused by the allowlist or the denylist rather than by another behaviour.
+pub(crate) fn is_blocked(cause: &ConnectionDenied) -> bool {
+    cause.downcast_ref::<allow_block_list::NotAllowed>().is_some()
+        || cause.downcast_ref::<allow_block_list::Blocked>().is_some()
+}
+
+#[derive(Debug, Default)]
+pub struct AccessStats {
+    blocked_inbound: AtomicU64,
+    blocked_outbound: AtomicU64,
+}
+
+impl AccessStats {
+    pub fn blocked_inbound(&self) -> u64 {
+        self.blocked_inbound.load(Ordering::Relaxed)
+    }
+
+    pub fn blocked_outbound(&self) -> u64 {
+        self.blocked_outbound.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_inbound(&self) {
+        self.blocked_inbound.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_outbound(&self) {
+        self.blocked_outbound.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum AccessError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid swarm key: {0}")]
+    Key(#[from] KeyParseError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn swarm_key() {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "/key/swarm/psk/1.0.0/\n/base16/\n{}\n", "ab".repeat(32)).unwrap();
+        assert!(read_swarm_key(file.path()).is_ok());
+
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "/key/swarm/psk/1.0.0/\n/base16/\nnot a key\n").unwrap();
+        assert!(matches!(read_swarm_key(file.path()), Err(AccessError::Key(_))));
+    }
+
+    #[test]
+    fn lists() {
+        let (allowed, denied, outsider) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let config = AccessConfig { psk: None, allowed_peers: vec![], denied_peers: vec![denied] };
+        assert!(config.permits(&outsider));
+        assert!(!config.permits(&denied));
+
+        let config = AccessConfig { allowed_peers: vec![allowed, denied], ..config };
+        assert!(config.permits(&allowed));
+        assert!(!config.permits(&denied));
+        assert!(!config.permits(&outsider));
+    }
+}
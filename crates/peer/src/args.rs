@@ -0,0 +1,158 @@
+use clap::Args;
+use std::{num::NonZeroUsize, time::Duration};
+
+use crate::{
+    connection::{ConnectionConfig, ReconnectConfig},
+    query::QueryConfig,
+};
+
+/*
+    Peer Arguments
+    Command line flags shared by every binary running a peer, flattened into their own clap parsers.
+    Flags left out keep the defaults of the configs they fill in.
+*/
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct QueryArgs {
+    /// Number of peers that must store a DHT record for the put to succeed
+    #[arg(long)]
+    pub dht_put_quorum: Option<NonZeroUsize>,
+
+    /// Timeout of a single DHT query in seconds
+    #[arg(long)]
+    pub dht_query_timeout: Option<u64>,
+
+    /// Number of times a failed DHT query is retried
+    #[arg(long)]
+    pub dht_query_retries: Option<u32>,
+}
+
+impl From<&QueryArgs> for QueryConfig {
+    fn from(args: &QueryArgs) -> Self {
+        let default = QueryConfig::default();
+        Self {
+            timeout: args.dht_query_timeout.map(Duration::from_secs).unwrap_or(default.timeout),
+            put_quorum: args.dht_put_quorum.unwrap_or(default.put_quorum),
+            retries: args.dht_query_retries.unwrap_or(default.retries),
+            ..default
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct ConnectionArgs {
+    /// Maximum number of incoming connections being negotiated at once
+    #[arg(long)]
+    pub max_pending_incoming: Option<u32>,
+
+    /// Maximum number of outgoing connections being negotiated at once
+    #[arg(long)]
+    pub max_pending_outgoing: Option<u32>,
+
+    /// Maximum number of established incoming connections
+    #[arg(long)]
+    pub max_established_incoming: Option<u32>,
+
+    /// Maximum number of established outgoing connections
+    #[arg(long)]
+    pub max_established_outgoing: Option<u32>,
+
+    /// Maximum number of established connections to a single peer
+    #[arg(long)]
+    pub max_established_per_peer: Option<u32>,
+
+    /// Backoff before the first re-dial of a disconnected peer in seconds
+    #[arg(long)]
+    pub reconnect_initial_backoff: Option<u64>,
+
+    /// Longest backoff between re-dials of a disconnected peer in seconds
+    #[arg(long)]
+    pub reconnect_max_backoff: Option<u64>,
+
+    /// Number of failed re-dials after which a disconnected peer is given up
+    #[arg(long)]
+    pub reconnect_max_attempts: Option<u32>,
+}
+
+impl From<&ConnectionArgs> for ConnectionConfig {
+    fn from(args: &ConnectionArgs) -> Self {
+        let default = ConnectionConfig::default();
+        let reconnect = ReconnectConfig {
+            initial_backoff: args
+                .reconnect_initial_backoff
+                .map(Duration::from_secs)
+                .unwrap_or(default.reconnect.initial_backoff),
+            max_backoff: args
+                .reconnect_max_backoff
+                .map(Duration::from_secs)
+                .unwrap_or(default.reconnect.max_backoff),
+            max_attempts: args.reconnect_max_attempts.unwrap_or(default.reconnect.max_attempts),
+            ..default.reconnect
+        };
+        Self {
+            max_pending_incoming: args.max_pending_incoming.unwrap_or(default.max_pending_incoming),
+            max_pending_outgoing: args.max_pending_outgoing.unwrap_or(default.max_pending_outgoing),
+            max_established_incoming: args
+                .max_established_incoming
+                .unwrap_or(default.max_established_incoming),
+            max_established_outgoing: args
+                .max_established_outgoing
+                .unwrap_or(default.max_established_outgoing),
+            max_established_per_peer: args
+                .max_established_per_peer
+                .unwrap_or(default.max_established_per_peer),
+            reconnect,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        query: QueryArgs,
+
+        #[command(flatten)]
+        connection: ConnectionArgs,
+    }
+
+    #[test]
+    fn omitted_flags_keep_defaults() {
+        let config = QueryConfig::from(&Cli::parse_from(["peer"]).query);
+        let default = QueryConfig::default();
+        assert_eq!(
+            (config.timeout, config.put_quorum, config.retries),
+            (default.timeout, default.put_quorum, default.retries)
+        );
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let cli = Cli::parse_from(["peer", "--dht-query-timeout", "5", "--dht-put-quorum", "2"]);
+        let config = QueryConfig::from(&cli.query);
+        assert_eq!(config.timeout, Duration::from_secs(5));
+        assert_eq!(config.put_quorum.get(), 2);
+        assert_eq!(config.retries, QueryConfig::default().retries);
+    }
+
+    #[test]
+    fn connection_flags_override_defaults() {
+        let cli = Cli::parse_from([
+            "peer",
+            "--max-established-per-peer",
+            "2",
+            "--reconnect-max-backoff",
+            "30",
+        ]);
+        let config = ConnectionConfig::from(&cli.connection);
+        let default = ConnectionConfig::default();
+        assert_eq!(config.max_established_per_peer, 2);
+        assert_eq!(config.max_established_incoming, default.max_established_incoming);
+        assert_eq!(config.reconnect.max_backoff, Duration::from_secs(30));
+        assert_eq!(config.reconnect.initial_backoff, default.reconnect.initial_backoff);
+    }
+}
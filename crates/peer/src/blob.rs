@@ -0,0 +1,990 @@
+use crate::store::{from_unix_millis, list_files, unix_millis, write_file};
+use crate::wire::{self, MessageType, WireError, WireMessage};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::{self, OutboundRequestId};
+use libp2p::{kad, PeerId, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, warn};
+
+/*
+    Blob Storage
+    Large payloads, compressed PIEs and proofs, are not stored in the DHT directly.
+    A blob is split into fixed size chunks addressed by their SHA-256 hash,
+    the manifest lists the chunk hashes in order and the hash of the encoded manifest is the root of the blob.
+    Job and proof records only carry that root.
+    Every node holding a chunk announces itself as a provider of the chunk hash in Kademlia,
+    a fetch looks up the providers of the manifest and of every chunk in parallel and requests each chunk
+    from the least busy provider over the blob protocol, chunks are verified against their hash before use.
+    Fetched chunks are kept and provided in turn, so popular blobs are served by more and more peers.
+    Chunks are only kept until the deadline of the job they belong to, a blob outliving its job is of no use.
+    Nodes persisting their DHT records persist their chunks next to them, serve them from disk
+    and provide them again after a restart.
+*/
+
+pub const BLOB_PROTOCOL: StreamProtocol = StreamProtocol::new("/zetina/blob/1");
+
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Largest blob accepted, a proof or compressed PIE never comes close.
+pub const MAX_BLOB_SIZE: usize = 1024 * 1024 * 100;
+
+const MAX_MANIFEST_SIZE: usize = 64 * 1024;
+
+// Bytes of chunks a node keeps, roughly as many chunks as the record store can provide keys for.
+pub const DEFAULT_BLOB_STORE_BYTES: usize = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlobHash(pub [u8; 32]);
+
+impl BlobHash {
+    pub fn digest(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl Display for BlobHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl From<BlobHash> for kad::RecordKey {
+    fn from(value: BlobHash) -> Self {
+        kad::RecordKey::new(&value.0)
+    }
+}
+
+// Records referencing a blob hold its wire encoded root.
+impl WireMessage for BlobHash {
+    const MESSAGE_TYPE: MessageType = MessageType::BlobRoot;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlobManifest {
+    pub size: u64,
+    pub chunks: Vec<BlobHash>,
+}
+
+impl WireMessage for BlobManifest {
+    const MESSAGE_TYPE: MessageType = MessageType::BlobManifest;
+}
+
+impl BlobManifest {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            size: data.len() as u64,
+            chunks: data.chunks(CHUNK_SIZE).map(BlobHash::digest).collect(),
+        }
+    }
+
+    pub fn assemble(&self, chunks: &HashMap<BlobHash, Vec<u8>>) -> Result<Vec<u8>, BlobError> {
+        let mut data = Vec::with_capacity(self.size as usize);
+        for hash in self.chunks.iter() {
+            data.extend_from_slice(chunks.get(hash).ok_or(BlobError::MissingChunk(*hash))?);
+        }
+        if data.len() as u64 != self.size {
+            return Err(BlobError::SizeMismatch { expected: self.size, actual: data.len() as u64 });
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Debug)]
+pub enum BlobMessage {
+    GET((BlobHash, Instant, oneshot::Sender<Result<Vec<u8>, BlobError>>)),
+    PUT((Vec<u8>, Instant, oneshot::Sender<Result<BlobHash, BlobError>>)),
+}
+
+// Handle through which the delegator and executor store and fetch blobs via the swarm.
+#[derive(Debug, Clone)]
+pub struct BlobClient {
+    tx: mpsc::Sender<BlobMessage>,
+}
+
+impl BlobClient {
+    pub fn new(tx: mpsc::Sender<BlobMessage>) -> Self {
+        Self { tx }
+    }
+
+    // Stores the blob until it expires, which is the deadline of the job it belongs to.
+    pub async fn put(&self, data: Vec<u8>, expires: Instant) -> Result<BlobHash, BlobError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(BlobMessage::PUT((data, expires, tx))).await.map_err(|_| BlobError::Closed)?;
+        rx.await.map_err(|_| BlobError::Closed)?
+    }
+
+    // Fetches the blob, its chunks are kept and provided to other peers until it expires.
+    pub async fn get(&self, root: BlobHash, expires: Instant) -> Result<Vec<u8>, BlobError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(BlobMessage::GET((root, expires, tx))).await.map_err(|_| BlobError::Closed)?;
+        rx.await.map_err(|_| BlobError::Closed)?
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobStoreConfig {
+    // Directory chunks are stored in and served from, chunks are kept in memory when omitted.
+    pub path: Option<PathBuf>,
+    // Bytes of chunks kept, blobs larger than it are refused up front.
+    pub max_total_bytes: usize,
+}
+
+impl Default for BlobStoreConfig {
+    fn default() -> Self {
+        Self { path: None, max_total_bytes: DEFAULT_BLOB_STORE_BYTES }
+    }
+}
+
+const CHUNK_SUFFIX: &str = ".chunk";
+
+// What is written to disk, the expiry is stored as unix milliseconds like the expiry of a record.
+#[derive(Serialize, Deserialize)]
+struct StoredChunk {
+    expires: u64,
+    data: Vec<u8>,
+}
+
+struct Chunk {
+    size: usize,
+    expires: Instant,
+    // Position in the eviction order, refreshed whenever the chunk is stored again.
+    seq: u64,
+    // Held in memory only when the store has no directory.
+    data: Option<Vec<u8>>,
+}
+
+// Chunks held by this node until their job expires, the oldest are evicted once the quota is exceeded.
+// With a directory only the chunk sizes and expiries stay in memory, chunks are written and read on demand.
+// Persisted chunks are loaded back on startup, so records kept across a restart still point at their blobs.
+pub struct BlobStore {
+    chunks: HashMap<BlobHash, Chunk>,
+    order: BTreeMap<u64, BlobHash>,
+    next_seq: u64,
+    total_bytes: usize,
+    max_total_bytes: usize,
+    dir: Option<PathBuf>,
+}
+
+impl BlobStore {
+    pub fn new(max_total_bytes: usize) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+            total_bytes: 0,
+            max_total_bytes,
+            dir: None,
+        }
+    }
+
+    pub fn open(config: BlobStoreConfig) -> io::Result<Self> {
+        let mut store = Self::new(config.max_total_bytes);
+        let Some(dir) = config.path else {
+            return Ok(store);
+        };
+        let now = Instant::now();
+        let mut files = vec![];
+        for path in list_files(&dir, CHUNK_SUFFIX)? {
+            let stored = match bincode::deserialize::<StoredChunk>(&fs::read(&path)?) {
+                Ok(stored) => stored,
+                Err(err) => {
+                    warn!("Removing unreadable chunk file {}: {err}", path.display());
+                    fs::remove_file(&path)?;
+                    continue;
+                }
+            };
+            let hash = BlobHash::digest(&stored.data);
+            let expires = from_unix_millis(stored.expires);
+            if expires <= now || path != Self::path(&dir, &hash) {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            files.push((fs::metadata(&path)?.modified()?, hash, stored.data.len(), expires));
+        }
+        files.sort_by_key(|(modified, _, _, _)| *modified);
+
+        for (_, hash, size, expires) in files {
+            store.track(hash, size, expires, None);
+        }
+        store.dir = Some(dir);
+        // The quota may have been lowered since the chunks were stored.
+        store.evict();
+        Ok(store)
+    }
+
+    pub fn get(&self, hash: &BlobHash) -> Option<Vec<u8>> {
+        let chunk = self.chunks.get(hash)?;
+        if let Some(data) = &chunk.data {
+            return Some(data.to_owned());
+        }
+        let path = Self::path(self.dir.as_ref()?, hash);
+        match fs::read(&path).map(|data| bincode::deserialize::<StoredChunk>(&data)) {
+            Ok(Ok(stored)) => Some(stored.data),
+            Ok(Err(err)) => {
+                warn!("Failed to decode chunk file {}: {err}", path.display());
+                None
+            }
+            Err(err) => {
+                warn!("Failed to read chunk file {}: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    pub fn contains(&self, hash: &BlobHash) -> bool {
+        self.chunks.contains_key(hash)
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = &BlobHash> {
+        self.order.values()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn max_total_bytes(&self) -> usize {
+        self.max_total_bytes
+    }
+
+    // Returns whether the chunk is new and the chunks evicted to make room for it.
+    // A chunk stored again moves to the back of the eviction order and is kept until the later expiry,
+    // so the chunks of the blob being stored are never evicted in favour of each other.
+    pub fn insert(
+        &mut self,
+        hash: BlobHash,
+        data: Vec<u8>,
+        expires: Instant,
+    ) -> (bool, Vec<BlobHash>) {
+        let size = data.len();
+        if size > self.max_total_bytes {
+            return (false, vec![]);
+        }
+        let previous = self.chunks.get(&hash).map(|chunk| chunk.expires);
+        let expires = previous.map_or(expires, |previous| previous.max(expires));
+        let data = match &self.dir {
+            // Rewritten only when the expiry moved, so the one read back after a restart is current.
+            Some(dir) if previous != Some(expires) => {
+                let stored = StoredChunk { expires: unix_millis(expires), data };
+                let written = bincode::serialize(&stored)
+                    .map_err(io::Error::other)
+                    .and_then(|encoded| write_file(dir, &Self::path(dir, &hash), &encoded));
+                if let Err(err) = written {
+                    error!("Failed to persist chunk {hash}: {err}");
+                    return (false, vec![]);
+                }
+                None
+            }
+            Some(_) => None,
+            None => Some(data),
+        };
+        self.track(hash, size, expires, data);
+        (previous.is_none(), self.evict())
+    }
+
+    // Drops the chunks whose job expired and returns them.
+    pub fn gc(&mut self, now: Instant) -> Vec<BlobHash> {
+        let expired: Vec<BlobHash> = self
+            .chunks
+            .iter()
+            .filter(|(_, chunk)| chunk.expires <= now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.remove(hash);
+        }
+        expired
+    }
+
+    fn track(&mut self, hash: BlobHash, size: usize, expires: Instant, data: Option<Vec<u8>>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let chunk = Chunk { size, expires, seq, data };
+        if let Some(previous) = self.chunks.insert(hash, chunk) {
+            self.order.remove(&previous.seq);
+            self.total_bytes -= previous.size;
+        }
+        self.total_bytes += size;
+        self.order.insert(seq, hash);
+    }
+
+    // Evicts the oldest chunks until the store is within its quota.
+    fn evict(&mut self) -> Vec<BlobHash> {
+        let mut evicted = vec![];
+        while self.total_bytes > self.max_total_bytes {
+            let Some(oldest) = self.order.values().next().copied() else {
+                break;
+            };
+            self.remove(&oldest);
+            evicted.push(oldest);
+        }
+        evicted
+    }
+
+    fn remove(&mut self, hash: &BlobHash) {
+        let Some(chunk) = self.chunks.remove(hash) else {
+            return;
+        };
+        self.order.remove(&chunk.seq);
+        self.total_bytes -= chunk.size;
+        if let Some(dir) = &self.dir {
+            let path = Self::path(dir, hash);
+            if let Err(err) = fs::remove_file(&path) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    error!("Failed to remove chunk file {}: {err}", path.display());
+                }
+            }
+        }
+    }
+
+    fn path(dir: &Path, hash: &BlobHash) -> PathBuf {
+        dir.join(format!("{hash}{CHUNK_SUFFIX}"))
+    }
+}
+
+// Swarm operations requested by the blob manager, carried out by the swarm runner.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlobAction {
+    FindProviders(BlobHash),
+    Request(PeerId, BlobHash),
+    Provide(BlobHash),
+    Unprovide(BlobHash),
+}
+
+struct Fetch {
+    expires: Instant,
+    manifest: Option<BlobManifest>,
+    received: HashMap<BlobHash, Vec<u8>>,
+    responders: Vec<oneshot::Sender<Result<Vec<u8>, BlobError>>>,
+}
+
+impl Fetch {
+    fn wants(&self, root: &BlobHash, hash: &BlobHash) -> bool {
+        match &self.manifest {
+            None => root == hash,
+            Some(manifest) => manifest.chunks.contains(hash) && !self.received.contains_key(hash),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.manifest.as_ref().is_some_and(|manifest| {
+            manifest.chunks.iter().all(|hash| self.received.contains_key(hash))
+        })
+    }
+
+    fn respond(self, result: Result<Vec<u8>, BlobError>) {
+        for responder in self.responders {
+            let _ = responder.send(result.to_owned());
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChunkFetch {
+    providers: Vec<PeerId>,
+    tried: HashSet<PeerId>,
+    in_flight: bool,
+    lookup_finished: bool,
+}
+
+/*
+    Blob Manager
+    Keeps the local chunk store and the state of ongoing fetches.
+    It does not touch the swarm itself, every method returns the actions the swarm runner has to carry out,
+    which keeps the fetch logic independent of the network and testable on its own.
+    Lookups and requests are identified by the Kademlia query id and the request id the swarm assigned them.
+*/
+pub struct BlobManager<Q = kad::QueryId, R = OutboundRequestId> {
+    local_peer_id: PeerId,
+    store: BlobStore,
+    fetches: HashMap<BlobHash, Fetch>,
+    chunks: HashMap<BlobHash, ChunkFetch>,
+    lookups: HashMap<Q, BlobHash>,
+    requests: HashMap<R, (PeerId, BlobHash)>,
+    load: HashMap<PeerId, usize>,
+}
+
+impl<Q: Hash + Eq, R: Hash + Eq> BlobManager<Q, R> {
+    pub fn new(local_peer_id: PeerId, store: BlobStore) -> Self {
+        Self {
+            local_peer_id,
+            store,
+            fetches: HashMap::new(),
+            chunks: HashMap::new(),
+            lookups: HashMap::new(),
+            requests: HashMap::new(),
+            load: HashMap::new(),
+        }
+    }
+
+    pub fn store(&self) -> &BlobStore {
+        &self.store
+    }
+
+    // Announces the chunks loaded from disk, provider records do not survive a restart.
+    pub fn provide_stored(&self) -> Vec<BlobAction> {
+        self.store.hashes().copied().map(BlobAction::Provide).collect()
+    }
+
+    // Chunks and stores a blob locally until it expires, the returned actions announce it to the network.
+    pub fn put(
+        &mut self,
+        data: &[u8],
+        expires: Instant,
+    ) -> Result<(BlobHash, Vec<BlobAction>), BlobError> {
+        if data.len() > MAX_BLOB_SIZE {
+            return Err(BlobError::TooLarge(data.len()));
+        }
+        let manifest = BlobManifest::new(data);
+        let encoded = wire::encode(&manifest)?;
+        self.fits(data.len() + encoded.len())?;
+        let root = BlobHash::digest(&encoded);
+
+        let mut actions = vec![];
+        for (hash, chunk) in manifest.chunks.iter().zip(data.chunks(CHUNK_SIZE)) {
+            actions.extend(self.insert(*hash, chunk.to_vec(), expires));
+        }
+        actions.extend(self.insert(root, encoded, expires));
+        Ok((root, actions))
+    }
+
+    pub fn get(
+        &mut self,
+        root: BlobHash,
+        expires: Instant,
+        responder: oneshot::Sender<Result<Vec<u8>, BlobError>>,
+    ) -> Vec<BlobAction> {
+        if let Some(fetch) = self.fetches.get_mut(&root) {
+            fetch.expires = fetch.expires.max(expires);
+            fetch.responders.push(responder);
+            return vec![];
+        }
+        let mut fetch = Fetch {
+            expires,
+            manifest: None,
+            received: HashMap::new(),
+            responders: vec![responder],
+        };
+        match self.store.get(&root) {
+            Some(encoded) => match self.open_manifest(&mut fetch, &encoded) {
+                Ok(actions) => self.progress(root, fetch, actions),
+                Err(err) => {
+                    fetch.respond(Err(err));
+                    vec![]
+                }
+            },
+            None => {
+                self.fetches.insert(root, fetch);
+                self.want(root).into_iter().collect()
+            }
+        }
+    }
+
+    // Answers a chunk request of another peer.
+    pub fn serve(&self, hash: &BlobHash) -> Option<Vec<u8>> {
+        self.store.get(hash)
+    }
+
+    // Drops the chunks of expired jobs, the returned actions stop providing them.
+    pub fn gc(&mut self) -> Vec<BlobAction> {
+        self.store.gc(Instant::now()).into_iter().map(BlobAction::Unprovide).collect()
+    }
+
+    pub fn lookup_started(&mut self, query_id: Q, hash: BlobHash) {
+        self.lookups.insert(query_id, hash);
+    }
+
+    pub fn providers_found(&mut self, query_id: Q, providers: HashSet<PeerId>) -> Vec<BlobAction> {
+        let Some(hash) = self.lookups.get(&query_id).copied() else {
+            return vec![];
+        };
+        if let Some(chunk) = self.chunks.get_mut(&hash) {
+            for provider in providers {
+                if provider != self.local_peer_id && !chunk.providers.contains(&provider) {
+                    chunk.providers.push(provider);
+                }
+            }
+        }
+        self.next_request(hash).into_iter().collect()
+    }
+
+    pub fn lookup_finished(&mut self, query_id: Q) -> Vec<BlobAction> {
+        let Some(hash) = self.lookups.remove(&query_id) else {
+            return vec![];
+        };
+        if let Some(chunk) = self.chunks.get_mut(&hash) {
+            chunk.lookup_finished = true;
+        }
+        self.retry(hash)
+    }
+
+    pub fn request_sent(&mut self, request_id: R, peer: PeerId, hash: BlobHash) {
+        *self.load.entry(peer).or_default() += 1;
+        self.requests.insert(request_id, (peer, hash));
+    }
+
+    pub fn response(&mut self, request_id: R, data: Option<Vec<u8>>) -> Vec<BlobAction> {
+        let Some((peer, hash)) = self.finish_request(request_id) else {
+            return vec![];
+        };
+        match data {
+            Some(data) if BlobHash::digest(&data) == hash => self.received(hash, data),
+            Some(_) => {
+                warn!("Peer {peer} sent a chunk not matching its hash {hash}");
+                self.retry(hash)
+            }
+            None => self.retry(hash),
+        }
+    }
+
+    pub fn request_failed(&mut self, request_id: R) -> Vec<BlobAction> {
+        match self.finish_request(request_id) {
+            Some((_, hash)) => self.retry(hash),
+            None => vec![],
+        }
+    }
+
+    fn finish_request(&mut self, request_id: R) -> Option<(PeerId, BlobHash)> {
+        let (peer, hash) = self.requests.remove(&request_id)?;
+        if let Some(load) = self.load.get_mut(&peer) {
+            *load -= 1;
+            if *load == 0 {
+                self.load.remove(&peer);
+            }
+        }
+        if let Some(chunk) = self.chunks.get_mut(&hash) {
+            chunk.in_flight = false;
+        }
+        Some((peer, hash))
+    }
+
+    // Blobs larger than the store would evict their own chunks while being stored, they are refused instead.
+    fn fits(&self, size: usize) -> Result<(), BlobError> {
+        match size > self.store.max_total_bytes() {
+            true => Err(BlobError::OverQuota(size, self.store.max_total_bytes())),
+            false => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, hash: BlobHash, data: Vec<u8>, expires: Instant) -> Vec<BlobAction> {
+        let (inserted, evicted) = self.store.insert(hash, data, expires);
+        let mut actions: Vec<BlobAction> = evicted.into_iter().map(BlobAction::Unprovide).collect();
+        if inserted {
+            actions.push(BlobAction::Provide(hash));
+        }
+        actions
+    }
+
+    fn want(&mut self, hash: BlobHash) -> Option<BlobAction> {
+        if self.chunks.contains_key(&hash) {
+            return None;
+        }
+        self.chunks.insert(hash, ChunkFetch::default());
+        Some(BlobAction::FindProviders(hash))
+    }
+
+    // Requests the chunk from the least busy provider not tried yet.
+    fn next_request(&mut self, hash: BlobHash) -> Option<BlobAction> {
+        let chunk = self.chunks.get_mut(&hash).filter(|chunk| !chunk.in_flight)?;
+        let provider = chunk
+            .providers
+            .iter()
+            .filter(|provider| !chunk.tried.contains(*provider))
+            .min_by_key(|provider| self.load.get(*provider).copied().unwrap_or_default())
+            .copied()?;
+        chunk.tried.insert(provider);
+        chunk.in_flight = true;
+        Some(BlobAction::Request(provider, hash))
+    }
+
+    // Moves on to the next provider, the chunk is unavailable once the lookup is over and every provider failed.
+    fn retry(&mut self, hash: BlobHash) -> Vec<BlobAction> {
+        if let Some(action) = self.next_request(hash) {
+            return vec![action];
+        }
+        if self.chunks.get(&hash).is_some_and(|chunk| chunk.lookup_finished && !chunk.in_flight) {
+            self.chunks.remove(&hash);
+            let failed: Vec<BlobHash> = self
+                .fetches
+                .iter()
+                .filter(|(root, fetch)| fetch.wants(root, &hash))
+                .map(|(root, _)| *root)
+                .collect();
+            for root in failed {
+                if let Some(fetch) = self.fetches.remove(&root) {
+                    fetch.respond(Err(BlobError::Unavailable(hash)));
+                }
+            }
+        }
+        vec![]
+    }
+
+    fn received(&mut self, hash: BlobHash, data: Vec<u8>) -> Vec<BlobAction> {
+        self.chunks.remove(&hash);
+        let mut actions = vec![];
+        let (roots, expires): (Vec<BlobHash>, Vec<Instant>) = self
+            .fetches
+            .iter()
+            .filter(|(root, fetch)| fetch.wants(root, &hash))
+            .map(|(root, fetch)| (*root, fetch.expires))
+            .unzip();
+        // Kept as long as the longest fetch wanting it, a chunk no fetch wants anymore is dropped.
+        let Some(expires) = expires.into_iter().max() else {
+            return actions;
+        };
+        for root in roots {
+            let Some(mut fetch) = self.fetches.remove(&root) else {
+                continue;
+            };
+            if root == hash {
+                match self.open_manifest(&mut fetch, &data) {
+                    Ok(wanted) => actions.extend(self.progress(root, fetch, wanted)),
+                    Err(err) => fetch.respond(Err(err)),
+                }
+            } else {
+                fetch.received.insert(hash, data.to_owned());
+                actions.extend(self.progress(root, fetch, vec![]));
+            }
+        }
+        actions.extend(self.insert(hash, data, expires));
+        actions
+    }
+
+    // Decodes the manifest of a fetch, takes the chunks already held locally and wants the others.
+    fn open_manifest(
+        &mut self,
+        fetch: &mut Fetch,
+        encoded: &[u8],
+    ) -> Result<Vec<BlobAction>, BlobError> {
+        let manifest: BlobManifest = wire::decode(encoded, MAX_MANIFEST_SIZE)?;
+        if manifest.size > MAX_BLOB_SIZE as u64 {
+            return Err(BlobError::TooLarge(manifest.size as usize));
+        }
+        self.fits(manifest.size as usize + encoded.len())?;
+        let mut actions = vec![];
+        for hash in manifest.chunks.iter() {
+            match self.store.get(hash) {
+                Some(data) => {
+                    fetch.received.insert(*hash, data);
+                }
+                None => actions.extend(self.want(*hash)),
+            }
+        }
+        fetch.manifest = Some(manifest);
+        Ok(actions)
+    }
+
+    fn progress(
+        &mut self,
+        root: BlobHash,
+        fetch: Fetch,
+        actions: Vec<BlobAction>,
+    ) -> Vec<BlobAction> {
+        if fetch.is_complete() {
+            let result = fetch.manifest.as_ref().map(|manifest| manifest.assemble(&fetch.received));
+            if let Some(result) = result {
+                fetch.respond(result);
+            }
+        } else {
+            self.fetches.insert(root, fetch);
+        }
+        actions
+    }
+}
+
+/*
+    Blob Codec
+    A request is the 32 byte hash of the wanted chunk,
+    the response is a single byte telling whether the chunk was found followed by the raw chunk.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct BlobCodec;
+
+#[async_trait]
+impl request_response::Codec for BlobCodec {
+    type Protocol = StreamProtocol;
+    type Request = BlobHash;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut hash = [0u8; 32];
+        io.read_exact(&mut hash).await?;
+        Ok(BlobHash(hash))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut found = [0u8; 1];
+        io.read_exact(&mut found).await?;
+        if found[0] == 0 {
+            return Ok(None);
+        }
+        // Manifests are smaller than a chunk, anything longer is cut off and fails verification.
+        let mut data = Vec::new();
+        io.take(CHUNK_SIZE as u64).read_to_end(&mut data).await?;
+        Ok(Some(data))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&request.0).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match response {
+            Some(data) => {
+                io.write_all(&[1]).await?;
+                io.write_all(&data).await?;
+            }
+            None => io.write_all(&[0]).await?,
+        }
+        io.close().await
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum BlobError {
+    #[error("blob of {0} bytes exceeds the limit of {MAX_BLOB_SIZE} bytes")]
+    TooLarge(usize),
+
+    #[error("blob of {0} bytes exceeds the blob store quota of {1} bytes")]
+    OverQuota(usize, usize),
+
+    #[error("chunk {0} is not available from any provider")]
+    Unavailable(BlobHash),
+
+    #[error("chunk {0} is missing")]
+    MissingChunk(BlobHash),
+
+    #[error("blob size {actual} does not match the manifest size {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+
+    #[error("malformed manifest: {0}")]
+    Manifest(String),
+
+    #[error("swarm is not running")]
+    Closed,
+}
+
+impl From<WireError> for BlobError {
+    fn from(value: WireError) -> Self {
+        BlobError::Manifest(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn manager() -> BlobManager<u64, u64> {
+        BlobManager::new(PeerId::random(), BlobStore::new(DEFAULT_BLOB_STORE_BYTES))
+    }
+
+    fn later() -> Instant {
+        Instant::now() + Duration::from_secs(600)
+    }
+
+    fn blob(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn requests(actions: &[BlobAction]) -> Vec<(PeerId, BlobHash)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                BlobAction::Request(peer, hash) => Some((*peer, *hash)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn find_providers(actions: &[BlobAction]) -> Vec<BlobHash> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                BlobAction::FindProviders(hash) => Some(*hash),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn manifest_roundtrip() {
+        let data = blob(CHUNK_SIZE * 2 + 10);
+        let manifest = BlobManifest::new(&data);
+        assert_eq!(manifest.chunks.len(), 3);
+        let chunks = manifest
+            .chunks
+            .iter()
+            .copied()
+            .zip(data.chunks(CHUNK_SIZE).map(<[u8]>::to_vec))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(manifest.assemble(&chunks).unwrap(), data);
+        assert!(matches!(
+            manifest.assemble(&HashMap::new()),
+            Err(BlobError::MissingChunk(hash)) if hash == manifest.chunks[0]
+        ));
+    }
+
+    #[test]
+    fn local_blob_is_served_without_network() {
+        let mut manager = manager();
+        let data = blob(CHUNK_SIZE + 1);
+        let (root, actions) = manager.put(&data, later()).unwrap();
+        assert_eq!(actions.iter().filter(|a| matches!(a, BlobAction::Provide(_))).count(), 3);
+
+        let (tx, mut rx) = oneshot::channel();
+        assert!(manager.get(root, later(), tx).is_empty());
+        assert_eq!(rx.try_recv().unwrap().unwrap(), data);
+    }
+
+    #[test]
+    fn fetch_from_providers() {
+        let data = blob(CHUNK_SIZE * 2);
+        let mut seeder = manager();
+        let (root, _) = seeder.put(&data, later()).unwrap();
+
+        let (honest, corrupt) = (PeerId::random(), PeerId::random());
+        let mut manager = manager();
+        let mut ids = 0..;
+        let (tx, mut rx) = oneshot::channel();
+
+        let mut pending = manager.get(root, later(), tx);
+        while !pending.is_empty() {
+            let mut next = vec![];
+            for hash in find_providers(&pending) {
+                let query_id = ids.next().unwrap();
+                manager.lookup_started(query_id, hash);
+                next.extend(manager.providers_found(query_id, HashSet::from([corrupt, honest])));
+            }
+            for (peer, hash) in requests(&pending) {
+                let request_id = ids.next().unwrap();
+                manager.request_sent(request_id, peer, hash);
+                let response = match peer == corrupt {
+                    true => Some(vec![0; 4]),
+                    false => seeder.serve(&hash),
+                };
+                next.extend(manager.response(request_id, response));
+            }
+            pending = next;
+        }
+
+        assert_eq!(rx.try_recv().unwrap().unwrap(), data);
+        assert!(manager.serve(&root).is_some());
+    }
+
+    #[test]
+    fn unavailable_chunk_fails_fetch() {
+        let mut manager = manager();
+        let root = BlobHash::digest(b"missing");
+        let (tx, mut rx) = oneshot::channel();
+        assert_eq!(manager.get(root, later(), tx), vec![BlobAction::FindProviders(root)]);
+
+        manager.lookup_started(0, root);
+        assert!(manager.lookup_finished(0).is_empty());
+        assert!(
+            matches!(rx.try_recv().unwrap(), Err(BlobError::Unavailable(hash)) if hash == root)
+        );
+    }
+
+    #[test]
+    fn store_evicts_oldest_chunks() {
+        let mut store = BlobStore::new(8);
+        let (first, second) = (BlobHash::digest(b"first"), BlobHash::digest(b"second"));
+        assert_eq!(store.insert(first, vec![0; 6], later()), (true, vec![]));
+        assert_eq!(store.insert(second, vec![0; 6], later()), (true, vec![first]));
+        assert_eq!(store.insert(second, vec![0; 6], later()), (false, vec![]));
+        assert_eq!(store.total_bytes(), 6);
+        // A chunk larger than the quota is not stored and evicts nothing.
+        assert_eq!(store.insert(first, vec![0; 9], later()), (false, vec![]));
+        assert!(store.contains(&second));
+    }
+
+    #[test]
+    fn expired_chunks_are_collected() {
+        let mut manager = manager();
+        let (root, _) = manager.put(&blob(10), Instant::now()).unwrap();
+        let kept = BlobHash::digest(&blob(20));
+        manager.store.insert(kept, blob(20), later());
+        let actions = manager.gc();
+        assert_eq!(actions.len(), 2);
+        assert!(actions.contains(&BlobAction::Unprovide(root)));
+        assert!(manager.serve(&root).is_none());
+        assert!(manager.serve(&kept).is_some());
+    }
+
+    #[test]
+    fn blob_over_quota_is_refused() {
+        let mut manager =
+            BlobManager::<u64, u64>::new(PeerId::random(), BlobStore::new(CHUNK_SIZE * 2));
+        assert!(matches!(
+            manager.put(&blob(CHUNK_SIZE * 2), later()),
+            Err(BlobError::OverQuota(_, limit)) if limit == CHUNK_SIZE * 2
+        ));
+        assert_eq!(manager.store().total_bytes(), 0);
+        assert!(manager.put(&blob(CHUNK_SIZE + 1), later()).is_ok());
+    }
+
+    #[test]
+    fn persisted_chunks_are_provided_after_reopen() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = BlobStoreConfig {
+            path: Some(dir.path().join("blobs")),
+            max_total_bytes: DEFAULT_BLOB_STORE_BYTES,
+        };
+        let data = blob(CHUNK_SIZE + 10);
+        let (root, _) = BlobManager::<u64, u64>::new(
+            PeerId::random(),
+            BlobStore::open(config.to_owned()).unwrap(),
+        )
+        .put(&data, later())
+        .unwrap();
+        fs::write(dir.path().join("blobs").join("notes.txt"), [1]).unwrap();
+
+        let mut manager =
+            BlobManager::<u64, u64>::new(PeerId::random(), BlobStore::open(config).unwrap());
+        assert_eq!(manager.provide_stored().len(), 3);
+        // Only sizes and expiries are held in memory, chunks are read from disk when served.
+        assert!(manager.store().chunks.values().all(|chunk| chunk.data.is_none()));
+        let (tx, mut rx) = oneshot::channel();
+        assert!(manager.get(root, later(), tx).is_empty());
+        assert_eq!(rx.try_recv().unwrap().unwrap(), data);
+    }
+}
@@ -0,0 +1,224 @@
+use futures::future::BoxFuture;
+use libp2p::connection_limits::ConnectionLimits;
+use libp2p::{Multiaddr, PeerId};
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/*
+    Connection Management
+    The swarm caps the number of pending and established connections, in total and per peer,
+    so a burst of inbound connections or a misbehaving peer cannot exhaust the node.
+    Peers this node dialed itself are known peers, when the last connection to one of them closes
+    it is re-dialed after an exponential backoff with jitter, and given up after too many failed attempts.
+    The backoff is only reset once a connection stayed up for a while, so a flapping peer keeps backing off.
+*/
+
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    pub max_pending_incoming: u32,
+    pub max_pending_outgoing: u32,
+    pub max_established_incoming: u32,
+    pub max_established_outgoing: u32,
+    // A peer may be reached over TCP and QUIC, directly and through a relay at the same time.
+    pub max_established_per_peer: u32,
+    pub reconnect: ReconnectConfig,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_pending_incoming: 64,
+            max_pending_outgoing: 64,
+            max_established_incoming: 128,
+            max_established_outgoing: 128,
+            max_established_per_peer: 4,
+            reconnect: ReconnectConfig::default(),
+        }
+    }
+}
+
+impl From<&ConnectionConfig> for ConnectionLimits {
+    fn from(config: &ConnectionConfig) -> Self {
+        ConnectionLimits::default()
+            .with_max_pending_incoming(Some(config.max_pending_incoming))
+            .with_max_pending_outgoing(Some(config.max_pending_outgoing))
+            .with_max_established_incoming(Some(config.max_established_incoming))
+            .with_max_established_outgoing(Some(config.max_established_outgoing))
+            .with_max_established_per_peer(Some(config.max_established_per_peer))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub jitter: f64, // Fraction of the backoff added or removed at random
+    pub max_attempts: u32,
+    pub stable_after: Duration, // Connections lasting this long reset the backoff
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            jitter: 0.2,
+            max_attempts: 10,
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct KnownPeer {
+    addr: Multiaddr,
+    attempts: u32,
+    connected_since: Option<Instant>,
+    dialing: bool,
+}
+
+// Tracks known peers and decides when to re-dial them, the swarm runner schedules the dials.
+#[derive(Debug)]
+pub struct ReconnectManager {
+    config: ReconnectConfig,
+    peers: HashMap<PeerId, KnownPeer>,
+}
+
+impl ReconnectManager {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self { config, peers: HashMap::new() }
+    }
+
+    // Peers reached through an address this node dialed become known, others are only marked connected.
+    pub fn connected(&mut self, peer_id: PeerId, dialed_addr: Option<Multiaddr>) {
+        let now = Instant::now();
+        match (self.peers.get_mut(&peer_id), dialed_addr) {
+            (Some(peer), dialed_addr) => {
+                peer.connected_since.get_or_insert(now);
+                peer.dialing = false;
+                if let Some(addr) = dialed_addr {
+                    peer.addr = addr;
+                }
+            }
+            (None, Some(addr)) => {
+                self.peers.insert(
+                    peer_id,
+                    KnownPeer { addr, attempts: 0, connected_since: Some(now), dialing: false },
+                );
+            }
+            (None, None) => {}
+        }
+    }
+
+    // Called once the last connection to a peer closed, returns when to re-dial it.
+    pub fn disconnected(&mut self, peer_id: &PeerId) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        if let Some(connected_since) = peer.connected_since.take() {
+            if connected_since.elapsed() >= self.config.stable_after {
+                peer.attempts = 0;
+            }
+        }
+        self.next_attempt(peer_id)
+    }
+
+    // Called when a re-dial failed, returns when to try again.
+    pub fn dial_failed(&mut self, peer_id: &PeerId) -> Option<Duration> {
+        match self.peers.get_mut(peer_id) {
+            Some(peer) if peer.dialing && peer.connected_since.is_none() => {
+                peer.dialing = false;
+                self.next_attempt(peer_id)
+            }
+            _ => None,
+        }
+    }
+
+    // The backoff of a peer elapsed, returns the address to dial unless it reconnected meanwhile.
+    pub fn due(&mut self, peer_id: &PeerId) -> Option<Multiaddr> {
+        let peer = self.peers.get_mut(peer_id).filter(|peer| peer.connected_since.is_none())?;
+        peer.dialing = true;
+        Some(peer.addr.to_owned())
+    }
+
+    fn next_attempt(&mut self, peer_id: &PeerId) -> Option<Duration> {
+        let peer = self.peers.get_mut(peer_id)?;
+        if peer.attempts >= self.config.max_attempts {
+            self.peers.remove(peer_id);
+            return None;
+        }
+        let backoff = self
+            .config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(peer.attempts))
+            .min(self.config.max_backoff);
+        peer.attempts += 1;
+        let jitter = rand::thread_rng().gen_range(-self.config.jitter..=self.config.jitter);
+        Some(backoff.mul_f64(1.0 + jitter))
+    }
+}
+
+// Resolves to the peer once its backoff elapsed.
+pub(crate) fn redial_after(peer_id: PeerId, delay: Duration) -> BoxFuture<'static, PeerId> {
+    Box::pin(async move {
+        sleep(delay).await;
+        peer_id
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> ReconnectManager {
+        ReconnectManager::new(ReconnectConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.0,
+            max_attempts: 5,
+            stable_after: Duration::from_secs(60),
+        })
+    }
+
+    fn addr() -> Multiaddr {
+        "/ip4/127.0.0.1/tcp/5678".parse().unwrap()
+    }
+
+    #[test]
+    fn backoff_grows_until_giving_up() {
+        let mut manager = manager();
+        let peer_id = PeerId::random();
+        manager.connected(peer_id, Some(addr()));
+
+        let mut delays = vec![manager.disconnected(&peer_id).unwrap()];
+        while let Some(dialed) = manager.due(&peer_id) {
+            assert_eq!(dialed, addr());
+            match manager.dial_failed(&peer_id) {
+                Some(delay) => delays.push(delay),
+                None => break,
+            }
+        }
+        assert_eq!(delays, [1, 2, 4, 8, 10].map(Duration::from_secs));
+        assert!(manager.due(&peer_id).is_none());
+    }
+
+    #[test]
+    fn flapping_peer_keeps_backing_off() {
+        let mut manager = manager();
+        let peer_id = PeerId::random();
+        manager.connected(peer_id, Some(addr()));
+        assert_eq!(manager.disconnected(&peer_id), Some(Duration::from_secs(1)));
+        manager.due(&peer_id);
+        manager.connected(peer_id, None);
+        assert_eq!(manager.disconnected(&peer_id), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn unknown_peers_are_not_redialed() {
+        let mut manager = manager();
+        let peer_id = PeerId::random();
+        manager.connected(peer_id, None);
+        assert!(manager.disconnected(&peer_id).is_none());
+        assert!(manager.dial_failed(&peer_id).is_none());
+    }
+}
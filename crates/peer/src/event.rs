@@ -0,0 +1,69 @@
+use crate::query::QueryError;
+use crate::{DelegationMessage, GossipMessage, MarketMessage, NetworkingMessage};
+use futures::Stream;
+use libp2p::{kad, PeerId};
+use std::pin::Pin;
+use zetina_common::capability::{ExecutorCapabilities, JobRequirements};
+use zetina_common::job::{Job, JobBid, JobRejection};
+
+/*
+    Peer Events
+    Application level view of what happens on the network, yielded by the swarm runner.
+    Gossip messages are decoded and validated before they become events and carry the peer that authored them,
+    so the delegator and executor never deal with raw libp2p events or wire encoding.
+    DHT queries made through the Kademlia client resolve their own futures, the matching events are
+    yielded as well for anyone observing the network.
+*/
+#[derive(Debug)]
+pub enum PeerEvent {
+    Capabilities { source: PeerId, capabilities: ExecutorCapabilities },
+    Departed { source: PeerId, identity: PeerId },
+    CapabilitiesRequested { source: PeerId, identity: PeerId },
+    JobPublished { source: PeerId, job: Job },
+    JobAnnounced { source: PeerId, job_key: kad::RecordKey, requirements: JobRequirements },
+    BidReceived { source: PeerId, bid: JobBid },
+    Delegated { source: PeerId, bid: JobBid },
+    JobFinished { source: PeerId, job_key: kad::RecordKey, proof_key: kad::RecordKey },
+    JobRejected { source: PeerId, rejection: JobRejection },
+    JobReturned { source: PeerId, rejection: JobRejection },
+    RecordFetched { key: kad::RecordKey, value: Vec<u8> },
+    RecordStored { key: kad::RecordKey },
+    QueryFailed { key: kad::RecordKey, reason: QueryError },
+    PeerConnected(PeerId),
+    PeerDisconnected(PeerId),
+}
+
+pub type PeerEvents = Pin<Box<dyn Stream<Item = PeerEvent> + Send>>;
+
+impl PeerEvent {
+    pub(crate) fn from_gossip(source: PeerId, message: GossipMessage) -> Self {
+        match message {
+            GossipMessage::Networking(NetworkingMessage::Capabilities(capabilities)) => {
+                Self::Capabilities { source, capabilities }
+            }
+            GossipMessage::Networking(NetworkingMessage::Departure(identity)) => {
+                Self::Departed { source, identity }
+            }
+            GossipMessage::Networking(NetworkingMessage::CapabilitiesRequest(identity)) => {
+                Self::CapabilitiesRequested { source, identity }
+            }
+            GossipMessage::Market(MarketMessage::Job(job)) => Self::JobPublished { source, job },
+            GossipMessage::Market(MarketMessage::JobBidPropagation(job_key, requirements)) => {
+                Self::JobAnnounced { source, job_key, requirements }
+            }
+            GossipMessage::Market(MarketMessage::JobBid(bid)) => Self::BidReceived { source, bid },
+            GossipMessage::Delegation(DelegationMessage::Delegate(bid)) => {
+                Self::Delegated { source, bid }
+            }
+            GossipMessage::Delegation(DelegationMessage::Finished(proof_key, job_key)) => {
+                Self::JobFinished { source, job_key, proof_key }
+            }
+            GossipMessage::Delegation(DelegationMessage::Rejected(rejection)) => {
+                Self::JobRejected { source, rejection }
+            }
+            GossipMessage::Delegation(DelegationMessage::Returned(rejection)) => {
+                Self::JobReturned { source, rejection }
+            }
+        }
+    }
+}
@@ -1,32 +1,103 @@
+pub mod access;
+pub mod args;
+pub mod blob;
+pub mod connection;
+pub mod event;
+pub mod query;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod store;
+pub mod wire;
+
 use async_stream::stream;
-use futures::stream::Stream;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use libp2p::core::{multiaddr::Protocol, upgrade, ConnectedPoint};
 use libp2p::futures::StreamExt;
 use libp2p::gossipsub::{self, IdentTopic, PublishError, TopicHash};
 use libp2p::identity::Keypair;
-use libp2p::kad::store::{MemoryStore, MemoryStoreConfig};
 use libp2p::kad::{Config, Mode};
-use libp2p::swarm::{DialError, NetworkBehaviour, SwarmEvent};
-use libp2p::{kad, noise, tcp, yamux, Multiaddr, Swarm, SwarmBuilder};
+use libp2p::pnet::PnetConfig;
+use libp2p::swarm::{
+    behaviour::toggle::Toggle, dial_opts::DialOpts, DialError, ListenError, NetworkBehaviour,
+    SwarmEvent,
+};
+use libp2p::{
+    allow_block_list, autonat, connection_limits, dcutr, identify, kad, mdns, noise, relay,
+    request_response, tcp, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder, Transport,
+};
 use serde::{Deserialize, Serialize};
-use std::pin::Pin;
-use std::time::Duration;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
-use zetina_common::graceful_shutdown::shutdown_signal;
-use zetina_common::job::{Job, JobBid};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::interval;
+use tracing::{debug, error, info, warn};
+use zetina_common::capability::{ExecutorCapabilities, JobRequirements};
+use zetina_common::job::{Job, JobBid, JobRejection};
+
+use access::{AccessConfig, AccessStats};
+use blob::{
+    BlobAction, BlobCodec, BlobManager, BlobMessage, BlobStore, BlobStoreConfig, BLOB_PROTOCOL,
+};
+use connection::{redial_after, ConnectionConfig, ReconnectManager};
+use event::{PeerEvent, PeerEvents};
+use query::{PendingQuery, QueryConfig, QueryError};
+use store::{PeerStore, StoreConfig};
+use wire::{WireError, WireMessage, PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS};
 
 #[derive(NetworkBehaviour)]
 pub struct PeerBehaviour {
+    allowed_peers: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    connection_limits: connection_limits::Behaviour,
     gossipsub: gossipsub::Behaviour,
-    kademlia: kad::Behaviour<MemoryStore>,
+    kademlia: kad::Behaviour<PeerStore>,
+    mdns: mdns::tokio::Behaviour,
+    identify: identify::Behaviour,
+    autonat: autonat::Behaviour,
+    relay_client: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    dcutr: dcutr::Behaviour,
+    blob: request_response::Behaviour<BlobCodec>,
 }
 
+/*
+    NAT Configuration
+    Peers behind a NAT learn their reachability through AutoNAT.
+    Once a peer finds out it is private it listens through the configured relays, other peers then reach it
+    over a relayed circuit that DCUtR upgrades to a direct connection by hole punching.
+    Publicly reachable peers can act as relays for the rest of the network.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct NatConfig {
+    pub relay_multiaddrs: Vec<Multiaddr>, // Relays including their /p2p/ peer id
+    pub relay_server: bool,
+}
+
+pub const IDENTIFY_PROTOCOL_VERSION: &str = "/zetina/1.0.0";
+
+// Largest value stored in the DHT, records only reference blobs so they stay small.
+pub const MAX_RECORD_SIZE: usize = 64 * 1024;
+
+// How often expired records and the blob chunks of expired jobs are dropped.
+pub const RECORD_GC_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the routing table is refreshed with a Kademlia bootstrap and a random walk.
+pub const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+
 pub struct SwarmRunner {
     pub swarm: Swarm<PeerBehaviour>,
     pub listen_multiaddr: Multiaddr,
     pub dial_multiaddrs: Vec<Multiaddr>,
     pub p2p_keypair: Keypair,
-    pub p2p_multiaddr: Multiaddr,
+    pub p2p_multiaddr: Option<Multiaddr>,
+    pub nat_config: NatConfig,
+    pub blobs: BlobManager,
+    pub access_config: AccessConfig,
+    pub access_stats: Arc<AccessStats>,
+    pub reconnect: ReconnectManager,
+    pub query_config: QueryConfig,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -37,6 +108,8 @@ pub enum Topic {
 }
 
 impl Topic {
+    pub const ALL: [Topic; 3] = [Topic::Networking, Topic::Market, Topic::Delegation];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             Topic::Networking => "networking",
@@ -44,6 +117,45 @@ impl Topic {
             Topic::Delegation => "delegation",
         }
     }
+
+    // Topic names carry the protocol version, e.g. "zetina/market/1".
+    pub fn versioned_name(&self, version: u16) -> String {
+        format!("zetina/{}/{}", self.as_str(), version)
+    }
+
+    // Topics of every supported version and the legacy ones, a peer listens on all of them
+    // while it only publishes on the current one.
+    pub fn supported_topics() -> impl Iterator<Item = (Topic, IdentTopic)> {
+        Self::ALL
+            .into_iter()
+            .flat_map(|topic| {
+                SUPPORTED_PROTOCOL_VERSIONS
+                    .map(move |version| (topic, IdentTopic::new(topic.versioned_name(version))))
+            })
+            .chain(Self::legacy_topics())
+    }
+
+    // Unversioned topics of releases before the wire envelope, e.g. "market", carrying JSON messages.
+    pub fn legacy_topics() -> impl Iterator<Item = (Topic, IdentTopic)> {
+        Self::ALL.into_iter().map(|topic| (topic, IdentTopic::new(topic.as_str())))
+    }
+
+    pub fn is_legacy(hash: &TopicHash) -> bool {
+        Self::legacy_topics().any(|(_, ident)| ident.hash() == *hash)
+    }
+
+    pub fn from_hash(hash: &TopicHash) -> Option<Self> {
+        Self::supported_topics().find(|(_, ident)| ident.hash() == *hash).map(|(topic, _)| topic)
+    }
+
+    // Jobs and proofs travel through the DHT, gossip only carries keys, bids and small records.
+    pub fn max_message_size(&self) -> usize {
+        match self {
+            Topic::Networking => 16 * 1024,
+            Topic::Market => 64 * 1024,
+            Topic::Delegation => 16 * 1024,
+        }
+    }
 }
 
 impl From<Topic> for TopicHash {
@@ -54,7 +166,7 @@ impl From<Topic> for TopicHash {
 
 impl From<Topic> for IdentTopic {
     fn from(value: Topic) -> Self {
-        IdentTopic::new(value.as_str())
+        IdentTopic::new(value.versioned_name(PROTOCOL_VERSION))
     }
 }
 
@@ -66,19 +178,22 @@ pub struct GossipsubMessage {
 
 #[derive(Debug)]
 pub enum KademliaMessage {
-    GET(kad::RecordKey),
-    PUT((kad::RecordKey, Vec<u8>)),
+    GET((kad::RecordKey, oneshot::Sender<Result<Vec<u8>, QueryError>>)),
+    PUT((kad::RecordKey, Vec<u8>, Option<Instant>, oneshot::Sender<Result<(), QueryError>>)),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NetworkingMessage {
-    Multiaddr(Multiaddr),
+    Capabilities(ExecutorCapabilities),
+    Departure(PeerId),
+    // Asks the executor to advertise its capabilities now instead of at its next interval.
+    CapabilitiesRequest(PeerId),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MarketMessage {
     Job(Job),
-    JobBidPropagation(kad::RecordKey),
+    JobBidPropagation(kad::RecordKey, JobRequirements),
     JobBid(JobBid),
 }
 
@@ -86,53 +201,176 @@ pub enum MarketMessage {
 pub enum DelegationMessage {
     Delegate(JobBid),
     Finished(kad::RecordKey, kad::RecordKey),
+    Rejected(JobRejection),
+    Returned(JobRejection),
+}
+
+/*
+    Gossip Message
+    Typed view of a gossipsub message, decoded from the wire envelope with the message type of its topic.
+    Every received message is decoded before it is accepted and forwarded to the mesh,
+    messages on unknown topics, above the topic size limit or that fail to decode are rejected,
+    which counts against the score of the peer that sent them.
+    Messages of an unsupported protocol version are ignored without penalty.
+    Messages on the legacy unversioned topics are decoded from JSON instead of the envelope.
+*/
+#[derive(Debug)]
+pub enum GossipMessage {
+    Networking(NetworkingMessage),
+    Market(MarketMessage),
+    Delegation(DelegationMessage),
+}
+
+impl GossipMessage {
+    pub fn decode(hash: &TopicHash, data: &[u8]) -> Result<Self, WireError> {
+        let topic =
+            Topic::from_hash(hash).ok_or_else(|| WireError::UnknownTopic(hash.to_owned()))?;
+        let limit = topic.max_message_size();
+        let legacy = Topic::is_legacy(hash);
+        Ok(match topic {
+            Topic::Networking => Self::Networking(Self::decode_payload(data, limit, legacy)?),
+            Topic::Market => Self::Market(Self::decode_payload(data, limit, legacy)?),
+            Topic::Delegation => Self::Delegation(Self::decode_payload(data, limit, legacy)?),
+        })
+    }
+
+    fn decode_payload<T: WireMessage>(
+        data: &[u8],
+        limit: usize,
+        legacy: bool,
+    ) -> Result<T, WireError> {
+        if legacy {
+            wire::decode_legacy(data, limit)
+        } else {
+            wire::decode(data, limit)
+        }
+    }
 }
 
 impl SwarmRunner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         listen_multiaddr: Multiaddr,
         dial_multiaddrs: Vec<Multiaddr>,
         p2p_keypair: Keypair,
-        p2p_multiaddr: Multiaddr,
+        p2p_multiaddr: Option<Multiaddr>,
+        nat_config: NatConfig,
+        store_config: StoreConfig,
+        blob_store_config: BlobStoreConfig,
+        access_config: AccessConfig,
+        connection_config: ConnectionConfig,
+        query_config: QueryConfig,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let mut config = Config::default();
-        config.set_max_packet_size(1024 * 1024 * 100);
-        config.set_query_timeout(Duration::from_secs(60));
-        let mut swarm = SwarmBuilder::with_existing_identity(p2p_keypair.to_owned())
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default().port_reuse(true),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_quic()
-            .with_behaviour(|p2p_keypair| PeerBehaviour {
+        // Room for a record and the peers returned alongside it.
+        config.set_max_packet_size(2 * MAX_RECORD_SIZE);
+        config.set_query_timeout(query_config.timeout);
+        let mdns = mdns::tokio::Behaviour::new(
+            mdns::Config::default(),
+            p2p_keypair.public().to_peer_id(),
+        )?;
+        let store = PeerStore::open(p2p_keypair.public().to_peer_id(), store_config)?;
+        let blob_store = BlobStore::open(blob_store_config)?;
+        let behaviour =
+            |p2p_keypair: &Keypair, relay_client: relay::client::Behaviour| PeerBehaviour {
+                allowed_peers: Toggle::from(access_config.allow_list()),
+                blocked_peers: access_config.deny_list(),
+                connection_limits: connection_limits::Behaviour::new((&connection_config).into()),
                 kademlia: kad::Behaviour::with_config(
                     p2p_keypair.public().to_peer_id(),
-                    MemoryStore::with_config(
-                        p2p_keypair.public().to_peer_id(),
-                        MemoryStoreConfig {
-                            max_value_bytes: 1024 * 1024 * 100,
-                            ..Default::default()
-                        },
-                    ),
+                    store,
                     config,
                 ),
                 gossipsub: Self::init_gossip(p2p_keypair).unwrap(),
-            })?
-            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
-            .build();
+                mdns,
+                identify: identify::Behaviour::new(
+                    identify::Config::new(
+                        IDENTIFY_PROTOCOL_VERSION.to_string(),
+                        p2p_keypair.public(),
+                    )
+                    .with_push_listen_addr_updates(true),
+                ),
+                autonat: autonat::Behaviour::new(
+                    p2p_keypair.public().to_peer_id(),
+                    autonat::Config::default(),
+                ),
+                relay_client,
+                relay_server: Toggle::from(nat_config.relay_server.then(|| {
+                    relay::Behaviour::new(p2p_keypair.public().to_peer_id(), Default::default())
+                })),
+                dcutr: dcutr::Behaviour::new(p2p_keypair.public().to_peer_id()),
+                blob: request_response::Behaviour::new(
+                    [(BLOB_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                ),
+            };
+        let builder = SwarmBuilder::with_existing_identity(p2p_keypair.to_owned()).with_tokio();
+        let mut swarm = match access_config.psk {
+            // Every TCP connection goes through the pre-shared key handshake first, QUIC is left out.
+            Some(psk) => builder
+                .with_other_transport(|p2p_keypair| {
+                    Ok::<_, noise::Error>(
+                        tcp::tokio::Transport::new(tcp::Config::default().port_reuse(true))
+                            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+                            .upgrade(upgrade::Version::V1Lazy)
+                            .authenticate(noise::Config::new(p2p_keypair)?)
+                            .multiplex(yamux::Config::default()),
+                    )
+                })?
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(behaviour)?
+                .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+                .build(),
+            None => builder
+                .with_tcp(
+                    tcp::Config::default().port_reuse(true),
+                    noise::Config::new,
+                    yamux::Config::default,
+                )?
+                .with_quic()
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(behaviour)?
+                .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+                .build(),
+        };
 
-        swarm.behaviour_mut().gossipsub.subscribe(&IdentTopic::new(Topic::Networking.as_str()))?;
-        swarm.behaviour_mut().gossipsub.subscribe(&IdentTopic::new(Topic::Market.as_str()))?;
-        swarm.behaviour_mut().gossipsub.subscribe(&IdentTopic::new(Topic::Delegation.as_str()))?;
+        for (_, topic) in Topic::supported_topics() {
+            swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        }
         swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
         // swarm.listen_on("/ip4/0.0.0.0/udp/5678/quic-v1".parse()?)?;
         swarm.listen_on(listen_multiaddr.to_owned())?;
+        // Advertised to other peers through identify, without it AutoNAT finds the external address.
+        if let Some(p2p_multiaddr) = &p2p_multiaddr {
+            swarm.add_external_address(p2p_multiaddr.to_owned());
+        }
 
         dial_multiaddrs.iter().try_for_each(|addr| swarm.dial(addr.clone()))?;
 
-        Ok(SwarmRunner { swarm, listen_multiaddr, dial_multiaddrs, p2p_keypair, p2p_multiaddr })
+        // Relays double as AutoNAT servers, they are public by definition.
+        for relay_multiaddr in nat_config.relay_multiaddrs.iter() {
+            if let Some(Protocol::P2p(relay_peer_id)) = relay_multiaddr.iter().last() {
+                swarm
+                    .behaviour_mut()
+                    .autonat
+                    .add_server(relay_peer_id, Some(relay_multiaddr.to_owned()));
+            }
+            swarm.dial(relay_multiaddr.to_owned())?;
+        }
+
+        Ok(SwarmRunner {
+            swarm,
+            listen_multiaddr,
+            dial_multiaddrs,
+            p2p_multiaddr,
+            nat_config,
+            blobs: BlobManager::new(p2p_keypair.public().to_peer_id(), blob_store),
+            p2p_keypair,
+            access_config,
+            access_stats: Arc::new(AccessStats::default()),
+            reconnect: ReconnectManager::new(connection_config.reconnect),
+            query_config,
+        })
     }
 
     fn init_gossip(
@@ -144,20 +382,122 @@ impl SwarmRunner {
         let config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(10))
             .validation_mode(gossipsub::ValidationMode::Strict)
-            .max_transmit_size(usize::MAX)
+            .validate_messages()
+            .max_transmit_size(
+                Topic::ALL.iter().map(Topic::max_message_size).max().unwrap_or_default() + 1024,
+            )
             .build()?;
 
-        Ok(gossipsub::Behaviour::new(message_authenticity, config)?)
+        let mut behaviour = gossipsub::Behaviour::new(message_authenticity, config)?;
+        behaviour.with_peer_score(Self::peer_score_params(), Self::peer_score_thresholds())?;
+        Ok(behaviour)
+    }
+
+    // Invalid messages weigh heavily, so a peer spamming malformed or oversized messages gets graylisted quickly.
+    // Topics are low traffic, mesh delivery penalties are disabled not to punish honest but quiet peers.
+    fn peer_score_params() -> gossipsub::PeerScoreParams {
+        gossipsub::PeerScoreParams {
+            topics: Topic::supported_topics()
+                .map(|(_, topic)| {
+                    (
+                        topic.hash(),
+                        gossipsub::TopicScoreParams {
+                            topic_weight: 1.0,
+                            mesh_message_deliveries_weight: 0.0,
+                            mesh_failure_penalty_weight: 0.0,
+                            invalid_message_deliveries_weight: -100.0,
+                            invalid_message_deliveries_decay: 0.5,
+                            ..Default::default()
+                        },
+                    )
+                })
+                .collect(),
+            behaviour_penalty_weight: -10.0,
+            ..Default::default()
+        }
+    }
+
+    fn peer_score_thresholds() -> gossipsub::PeerScoreThresholds {
+        gossipsub::PeerScoreThresholds {
+            gossip_threshold: -10.0,
+            publish_threshold: -50.0,
+            graylist_threshold: -80.0,
+            ..Default::default()
+        }
+    }
+
+    fn handle_blob_actions(&mut self, actions: Vec<BlobAction>) {
+        for action in actions {
+            let behaviour = self.swarm.behaviour_mut();
+            match action {
+                BlobAction::FindProviders(hash) => {
+                    let query_id = behaviour.kademlia.get_providers(hash.into());
+                    self.blobs.lookup_started(query_id, hash);
+                }
+                BlobAction::Request(peer, hash) => {
+                    let request_id = behaviour.blob.send_request(&peer, hash);
+                    self.blobs.request_sent(request_id, peer, hash);
+                }
+                BlobAction::Provide(hash) => {
+                    if let Err(err) = behaviour.kademlia.start_providing(hash.into()) {
+                        error!("Failed to provide chunk {hash}: {err:?}");
+                    }
+                }
+                BlobAction::Unprovide(hash) => behaviour.kademlia.stop_providing(&hash.into()),
+            }
+        }
+    }
+
+    // Waits for the address the swarm listens on, the one picked by the OS when listening on port 0.
+    // Meant for a swarm that dials nobody, it runs before the swarm and drops every other event.
+    pub async fn listen_addr(&mut self) -> Multiaddr {
+        loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = self.swarm.select_next_some().await {
+                return address;
+            }
+        }
     }
 
     pub fn run(
         mut self,
         mut gossipsub_message: mpsc::Receiver<GossipsubMessage>,
         mut kademlia_message: mpsc::Receiver<KademliaMessage>,
-    ) -> Pin<Box<dyn Stream<Item = PeerBehaviourEvent> + Send>> {
+        mut blob_message: mpsc::Receiver<BlobMessage>,
+    ) -> PeerEvents {
         let stream = stream! {
+            let mut bootstrap_interval = interval(BOOTSTRAP_INTERVAL);
+            let mut bootstrapped = false;
+            let mut stored_blobs_provided = false;
+            let mut record_gc_interval = interval(RECORD_GC_INTERVAL);
+            let mut relayed = false;
+            let mut reconnect_scheduler = FuturesUnordered::<BoxFuture<'static, PeerId>>::new();
+            let mut pending_queries = HashMap::<kad::QueryId, PendingQuery>::new();
             loop {
                 tokio::select! {
+                    Some(peer_id) = reconnect_scheduler.next() => {
+                        if let Some(addr) = self.reconnect.due(&peer_id) {
+                            debug!("Re-dialing peer {peer_id} at {addr}");
+                            if let Err(err) = self.swarm.dial(DialOpts::peer_id(peer_id).addresses(vec![addr]).build()) {
+                                error!("Failed to re-dial peer {peer_id}: {err:?}");
+                                if let Some(delay) = self.reconnect.dial_failed(&peer_id) {
+                                    reconnect_scheduler.push(redial_after(peer_id, delay));
+                                }
+                            }
+                        }
+                    },
+                    _ = record_gc_interval.tick() => {
+                        self.swarm.behaviour_mut().kademlia.store_mut().gc();
+                        let actions = self.blobs.gc();
+                        self.handle_blob_actions(actions);
+                    },
+                    _ = bootstrap_interval.tick() => {
+                        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                        match kademlia.bootstrap() {
+                            Ok(_) => bootstrapped = true,
+                            Err(err) => debug!("Kademlia bootstrap skipped: {err:?}"),
+                        }
+                        kademlia.get_closest_peers(PeerId::random());
+                    },
                     Some(message) = gossipsub_message.recv() => {
                         debug!{"Sending gossipsub_message: topic {}, data {}", message.topic, hex::encode(&message.data)};
                         if let Err(e) = self.swarm
@@ -171,137 +511,281 @@ impl SwarmRunner {
                     Some(message) = kademlia_message.recv() => {
                         debug!{"Sending kademlia_message: {:?}", message};
                         match message {
-                            KademliaMessage::GET(key) => {
-                                self.swarm.behaviour_mut().kademlia.get_record(kad::RecordKey::new(&key));
+                            KademliaMessage::GET((key, reply)) => {
+                                let query_id = self.swarm.behaviour_mut().kademlia.get_record(key.to_owned());
+                                pending_queries.insert(query_id, PendingQuery::Get(key, reply));
                             },
-                            KademliaMessage::PUT((key, data)) => {
+                            KademliaMessage::PUT((key, data, expires, reply)) => {
                                 let record = kad::Record {
-                                    key: kad::RecordKey::new(&key),
+                                    key: key.to_owned(),
                                     value: data,
                                     publisher: None,
-                                    expires: None,
+                                    expires,
                                 };
-                                if let Err(e) = self.swarm.behaviour_mut().kademlia.put_record(record, kad::Quorum::One) {
-                                    error!("Kademlia error: {e:?}");
+                                match self.swarm.behaviour_mut().kademlia.put_record(record, self.query_config.quorum()) {
+                                    Ok(query_id) => {
+                                        pending_queries.insert(query_id, PendingQuery::Put(key, reply));
+                                    }
+                                    Err(err) => {
+                                        error!("Failed to store record {}: {err:?}", hex::encode(&key));
+                                        let reason = QueryError::from(err);
+                                        let key = PendingQuery::Put(key, reply).fail(reason.to_owned());
+                                        yield PeerEvent::QueryFailed { key, reason };
+                                    }
+                                }
+                            },
+                        }
+                    },
+                    Some(message) = blob_message.recv() => {
+                        match message {
+                            BlobMessage::GET((root, expires, responder)) => {
+                                debug!("Fetching blob {root}");
+                                let actions = self.blobs.get(root, expires, responder);
+                                self.handle_blob_actions(actions);
+                            },
+                            BlobMessage::PUT((data, expires, responder)) => {
+                                match self.blobs.put(&data, expires) {
+                                    Ok((root, actions)) => {
+                                        debug!("Stored blob {root} of {} bytes", data.len());
+                                        self.handle_blob_actions(actions);
+                                        let _ = responder.send(Ok(root));
+                                    }
+                                    Err(err) => {
+                                        let _ = responder.send(Err(err));
+                                    }
                                 }
                             },
                         }
                     },
                     event = self.swarm.select_next_some() => match event {
-                        SwarmEvent::Behaviour(PeerBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
-                            peer_id, topic
-                        })) => {
-                            if topic == Topic::Networking.into() {
-                                let mut publish = || {
-                                    self.swarm.behaviour_mut().gossipsub.publish(
-                                        Topic::Networking,
-                                        serde_json::to_vec(&NetworkingMessage::Multiaddr(self.p2p_multiaddr.to_owned()))?
-                                    )?;
-                                    Ok::<(), Error>(())
-                                };
-
-                                if let Err(error) = publish() {
-                                    error!("Dial error: {:?}", error);
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Blob(request_response::Event::Message { peer, message })) => {
+                            match message {
+                                request_response::Message::Request { request, channel, .. } => {
+                                    let response = self.blobs.serve(&request);
+                                    if self.swarm.behaviour_mut().blob.send_response(channel, response).is_err() {
+                                        debug!("Peer {peer} went away before chunk {request} was sent");
+                                    }
+                                }
+                                request_response::Message::Response { request_id, response } => {
+                                    let actions = self.blobs.response(request_id, response);
+                                    self.handle_blob_actions(actions);
                                 }
                             }
-
-                            yield PeerBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
-                                peer_id, topic
-                            });
-                        },
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Blob(request_response::Event::OutboundFailure { peer, request_id, error })) => {
+                            debug!("Chunk request to {peer} failed: {error}");
+                            let actions = self.blobs.request_failed(request_id);
+                            self.handle_blob_actions(actions);
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                            for (peer_id, addr) in peers.into_iter().filter(|(peer_id, _)| self.access_config.permits(peer_id)) {
+                                info!("mDNS discovered peer {peer_id} at {addr}");
+                                self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.to_owned());
+                                if !self.swarm.is_connected(&peer_id) {
+                                    if let Err(err) = self.swarm.dial(addr) {
+                                        error!("Dial error: {err:?}");
+                                    }
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
+                            for (peer_id, addr) in peers {
+                                debug!("mDNS record of peer {peer_id} at {addr} expired");
+                                self.swarm.behaviour_mut().kademlia.remove_address(&peer_id, &addr);
+                            }
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                            debug!("Identified peer {peer_id}: {} {:?}", info.protocol_version, info.listen_addrs);
+                            if info.protocols.iter().any(|protocol| *protocol == kad::PROTOCOL_NAME) {
+                                for addr in info.listen_addrs {
+                                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                                }
+                                // The first bootstrap at startup has no known peers yet, so run it as soon as the first one is identified.
+                                if !bootstrapped {
+                                    bootstrapped = self.swarm.behaviour_mut().kademlia.bootstrap().is_ok();
+                                }
+                            }
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                            info!("NAT status changed from {old:?} to {new:?}");
+                            if new == autonat::NatStatus::Private && !relayed {
+                                for relay_multiaddr in self.nat_config.relay_multiaddrs.iter() {
+                                    if let Err(err) = self.swarm.listen_on(relay_multiaddr.to_owned().with(Protocol::P2pCircuit)) {
+                                        error!("Failed to listen through relay {relay_multiaddr}: {err:?}");
+                                    }
+                                }
+                                relayed = true;
+                            }
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::RelayClient(relay::client::Event::ReservationReqAccepted { relay_peer_id, .. })) => {
+                            info!("Reservation accepted by relay {relay_peer_id}");
+                        }
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => {
+                            match result {
+                                Ok(_) => info!("Hole punched a direct connection to {remote_peer_id}"),
+                                Err(err) => error!("Failed to hole punch a connection to {remote_peer_id}: {err:?}"),
+                            }
+                        }
+                        SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Denied { cause }, .. } if access::is_blocked(&cause) => {
+                            self.access_stats.record_inbound();
+                            warn!("Blocked inbound connection from {send_back_addr}: {cause}");
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id, error: DialError::Denied { cause }, .. } if access::is_blocked(&cause) => {
+                            self.access_stats.record_outbound();
+                            warn!("Blocked outbound connection to {peer_id:?}: {cause}");
+                        }
+                        SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Denied { cause }, .. } => {
+                            debug!("Refused inbound connection from {send_back_addr}: {cause}");
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                            debug!("Failed to connect to peer {peer_id}: {error}");
+                            if let Some(delay) = self.reconnect.dial_failed(&peer_id) {
+                                info!("Re-dialing peer {peer_id} in {delay:?}");
+                                reconnect_scheduler.push(redial_after(peer_id, delay));
+                            }
+                        }
+                        SwarmEvent::ExternalAddrConfirmed { address } => {
+                            info!("External address confirmed: {address}");
+                        }
                         SwarmEvent::Behaviour(PeerBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                             propagation_source,
                             message_id,
                             message,
                         })) => {
-                            if message.topic == Topic::Networking.into() {
-                                match serde_json::from_slice::<NetworkingMessage>(&message.data) {
-                                    Ok(NetworkingMessage::Multiaddr(addr)) => {
-                                        if let Err(error) = self.swarm.dial(addr) {
-                                            error!{"Dial error: {:?}", error};
-                                        }
-                                    }
-                                    Err(error) => {
-                                        error!{"Deserialization error: {:?}", error};
-                                    }
+                            let (acceptance, decoded) = match GossipMessage::decode(&message.topic, &message.data) {
+                                Ok(decoded) => (gossipsub::MessageAcceptance::Accept, Some(decoded)),
+                                Err(err) if err.is_incompatible() => {
+                                    debug!("Ignored gossipsub message {message_id} from {propagation_source}: {err}");
+                                    (gossipsub::MessageAcceptance::Ignore, None)
                                 }
+                                Err(err) => {
+                                    warn!("Rejected gossipsub message {message_id} from {propagation_source}: {err}");
+                                    (gossipsub::MessageAcceptance::Reject, None)
+                                }
+                            };
+                            if let Err(err) = self.swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance) {
+                                error!("Failed to report gossipsub validation result: {err:?}");
                             }
 
-                            yield PeerBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-                                propagation_source,
-                                message_id,
-                                message,
-                            });
+                            // Strict validation guarantees every accepted message is signed by its author.
+                            if let (Some(decoded), Some(source)) = (decoded, message.source) {
+                                yield PeerEvent::from_gossip(source, decoded);
+                            }
                         }
                         SwarmEvent::ConnectionEstablished { peer_id, connection_id, num_established, endpoint, .. } => {
                             info!{"Connection established: peer_id {}, connection_id {}, num_established {}", peer_id, connection_id, num_established};
-                            self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, endpoint.get_remote_address().to_owned());
+                            // Listener side addresses are ephemeral ports, the listen addresses are learned through identify instead.
+                            match endpoint {
+                                ConnectedPoint::Dialer { address, .. } => {
+                                    self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address.to_owned());
+                                    self.reconnect.connected(peer_id, Some(address));
+                                }
+                                ConnectedPoint::Listener { .. } => self.reconnect.connected(peer_id, None),
+                            }
+                            if num_established.get() == 1 {
+                                yield PeerEvent::PeerConnected(peer_id);
+                            }
                         }
                         SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, endpoint, .. } => {
                             info!{"Connection closed: peer_id {}, connection_id {}, num_established {}", peer_id, connection_id, num_established};
                             if num_established == 0 {
-                                self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
                                 self.swarm.behaviour_mut().kademlia.remove_address(&peer_id, endpoint.get_remote_address());
-                                if let Err(err) = self.swarm.dial(endpoint.get_remote_address().to_owned()) {
-                                    error!("Failed to re-dial peer: {err:?}");
+                                match self.reconnect.disconnected(&peer_id) {
+                                    Some(delay) => {
+                                        info!("Re-dialing peer {peer_id} in {delay:?}");
+                                        reconnect_scheduler.push(redial_after(peer_id, delay));
+                                    }
+                                    None => debug!("Not re-dialing peer {peer_id}"),
                                 }
+                                yield PeerEvent::PeerDisconnected(peer_id);
                             }
                         }
-                        SwarmEvent::Behaviour(PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result, stats, step })) => {
+                        SwarmEvent::Behaviour(PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id, result, step, .. })) => {
                             match result {
-                                kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers, .. })) => {
-                                    for peer in providers {
-                                        info!("Peer {peer:?} provides key {}", hex::encode(&key));
+                                kad::QueryResult::GetProviders(result) => {
+                                    let mut actions = match result {
+                                        Ok(kad::GetProvidersOk::FoundProviders { key, providers }) => {
+                                            debug!("Found {} providers of key {}", providers.len(), hex::encode(&key));
+                                            self.blobs.providers_found(id, providers)
+                                        }
+                                        Ok(_) => vec![],
+                                        Err(err) => {
+                                            error!("Failed to get providers: {err:?}");
+                                            vec![]
+                                        }
+                                    };
+                                    if step.last() {
+                                        actions.extend(self.blobs.lookup_finished(id));
                                     }
+                                    self.handle_blob_actions(actions);
                                 }
-                                kad::QueryResult::GetProviders(Err(err)) => {
-                                    error!("Failed to get providers: {err:?}");
-                                }
-                                kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {peer, record}))) => {
+                                kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord { record, .. }))) => {
                                     info!("Successfully got record {}", hex::encode(&record.key));
-
-                                    yield PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id,
-                                        result: kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {peer, record}))),
-                                        stats, step })
+                                    if let Some(PendingQuery::Get(_, reply)) = pending_queries.remove(&id) {
+                                        let _ = reply.send(Ok(record.value.to_owned()));
+                                    }
+                                    yield PeerEvent::RecordFetched { key: record.key, value: record.value };
                                 }
-                                kad::QueryResult::GetRecord(Ok(_)) => {}
-                                kad::QueryResult::GetRecord(Err(err)) => {
-                                    error!("Failed to get record: {err:?}");
+                                // The query ends without a record only when none was found.
+                                kad::QueryResult::GetRecord(result) => {
+                                    if let Some(query) = pending_queries.remove(&id) {
+                                        let reason = result.map_or_else(QueryError::from, |_| QueryError::NotFound);
+                                        error!("Failed to get record {}: {reason}", hex::encode(query.key()));
+                                        let key = query.fail(reason.to_owned());
+                                        yield PeerEvent::QueryFailed { key, reason };
+                                    }
                                 }
                                 kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
                                     info!("Successfully put record {}", hex::encode(&key));
-
-                                    yield PeerBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed { id,
-                                        result: kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })),
-                                        stats, step })
+                                    if let Some(PendingQuery::Put(_, reply)) = pending_queries.remove(&id) {
+                                        let _ = reply.send(Ok(()));
+                                    }
+                                    yield PeerEvent::RecordStored { key };
                                 }
                                 kad::QueryResult::PutRecord(Err(err)) => {
-                                    error!("Failed to put record: {err:?}");
+                                    if let Some(query) = pending_queries.remove(&id) {
+                                        let reason = QueryError::from(err);
+                                        error!("Failed to put record {}: {reason}", hex::encode(query.key()));
+                                        let key = query.fail(reason.to_owned());
+                                        yield PeerEvent::QueryFailed { key, reason };
+                                    }
                                 }
                                 kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
-                                    info!("Successfully put provider record {}", hex::encode(&key));
+                                    debug!("Successfully put provider record {}", hex::encode(&key));
                                 }
                                 kad::QueryResult::StartProviding(Err(err)) => {
                                     error!("Failed to put provider record: {err:?}");
                                 }
+                                kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, num_remaining })) => {
+                                    debug!("Bootstrapped with peer {peer}, {num_remaining} buckets remaining");
+                                }
+                                kad::QueryResult::Bootstrap(Err(err)) => {
+                                    error!("Failed to bootstrap: {err:?}");
+                                }
+                                kad::QueryResult::GetClosestPeers(Ok(kad::GetClosestPeersOk { peers, .. })) => {
+                                    debug!("Random walk found {} peers", peers.len());
+                                }
                                 event => {
                                     debug!("Unhandled event: {:?}", event);
                                 }
                             }
                         }
                         SwarmEvent::Behaviour(event) => {
-                            yield event;
+                            debug!("Unhandled behaviour event: {:?}", event);
                         }
                         event => {
                             debug!("Unhandled event: {:?}", event);
                         }
                     },
-                    _ = shutdown_signal() => {
-                        break
-                    }
+                    // Runs until dropped, so a node still gossips while it drains.
                     else => break
                 }
+                // Chunks loaded from disk are announced once there are peers to announce them to.
+                if bootstrapped && !stored_blobs_provided {
+                    stored_blobs_provided = true;
+                    let actions = self.blobs.provide_stored();
+                    self.handle_blob_actions(actions);
+                }
             }
         };
         Box::pin(stream)
@@ -321,3 +805,63 @@ pub enum Error {
     #[error("Publish error")]
     Publish(#[from] PublishError),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_by_topic() {
+        let data = wire::encode(&DelegationMessage::Finished(
+            kad::RecordKey::new(&[1]),
+            kad::RecordKey::new(&[2]),
+        ))
+        .unwrap();
+        assert!(matches!(
+            GossipMessage::decode(&Topic::Delegation.into(), &data),
+            Ok(GossipMessage::Delegation(DelegationMessage::Finished(..)))
+        ));
+        assert!(matches!(
+            GossipMessage::decode(&Topic::Market.into(), &data),
+            Err(WireError::UnexpectedMessageType(_))
+        ));
+    }
+
+    #[test]
+    fn decode_legacy_json() {
+        let legacy = |topic: Topic| IdentTopic::new(topic.as_str()).hash();
+        let data = serde_json::to_vec(&DelegationMessage::Finished(
+            kad::RecordKey::new(&[1]),
+            kad::RecordKey::new(&[2]),
+        ))
+        .unwrap();
+        assert!(matches!(
+            GossipMessage::decode(&legacy(Topic::Delegation), &data),
+            Ok(GossipMessage::Delegation(DelegationMessage::Finished(proof_key, job_key)))
+                if proof_key.to_vec() == [1] && job_key.to_vec() == [2]
+        ));
+        // Legacy messages that no longer match the current types are ignored, not rejected.
+        let err = GossipMessage::decode(&legacy(Topic::Networking), br#"{"Multiaddr":null}"#)
+            .unwrap_err();
+        assert!(err.is_incompatible());
+        // Anything not shaped like a legacy message is rejected.
+        for data in [&b"not json"[..], br#"{"Unknown":1}"#, br#"{"Job":1,"JobBid":2}"#, b"[]"] {
+            let err = GossipMessage::decode(&legacy(Topic::Market), data).unwrap_err();
+            assert!(matches!(err, WireError::Json(_)));
+            assert!(!err.is_incompatible());
+        }
+    }
+
+    #[test]
+    fn reject_oversized_and_unknown() {
+        let data = vec![b' '; Topic::Networking.max_message_size() + 1];
+        assert!(matches!(
+            GossipMessage::decode(&Topic::Networking.into(), &data),
+            Err(WireError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            GossipMessage::decode(&IdentTopic::new("unknown").hash(), b"{}"),
+            Err(WireError::UnknownTopic(_))
+        ));
+    }
+}
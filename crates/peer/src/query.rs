@@ -0,0 +1,231 @@
+use crate::KademliaMessage;
+use libp2p::kad;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
+use tracing::warn;
+
+/*
+    Kademlia Client
+    Handle through which the delegator and executor read and write DHT records.
+    Every request carries a reply channel, the swarm runner keeps it under the id of the Kademlia query
+    and resolves it with the record, or with the reason the query failed.
+    Queries that failed because of the network, not found, short of quorum or timed out,
+    are retried with an exponential backoff before the failure is returned to the caller.
+*/
+#[derive(Debug, Clone)]
+pub struct KademliaClient {
+    tx: mpsc::Sender<KademliaMessage>,
+    config: QueryConfig,
+}
+
+impl KademliaClient {
+    pub fn new(tx: mpsc::Sender<KademliaMessage>, config: QueryConfig) -> Self {
+        Self { tx, config }
+    }
+
+    pub async fn get_record(&self, key: kad::RecordKey) -> Result<Vec<u8>, QueryError> {
+        self.retry(&key, || {
+            let message = |tx| KademliaMessage::GET((key.to_owned(), tx));
+            self.query(message)
+        })
+        .await
+    }
+
+    pub async fn put_record(
+        &self,
+        key: kad::RecordKey,
+        value: Vec<u8>,
+        expires: Option<Instant>,
+    ) -> Result<(), QueryError> {
+        self.retry(&key, || {
+            let message =
+                |tx| KademliaMessage::PUT((key.to_owned(), value.to_owned(), expires, tx));
+            self.query(message)
+        })
+        .await
+    }
+
+    async fn query<T>(
+        &self,
+        message: impl FnOnce(oneshot::Sender<Result<T, QueryError>>) -> KademliaMessage,
+    ) -> Result<T, QueryError> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(message(tx)).await.map_err(|_| QueryError::Closed)?;
+        rx.await.map_err(|_| QueryError::Closed)?
+    }
+
+    async fn retry<T, F, Fut>(&self, key: &kad::RecordKey, mut query: F) -> Result<T, QueryError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, QueryError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match query().await {
+                Err(err) if err.is_transient() && attempt < self.config.retries => {
+                    let backoff =
+                        self.config.retry_backoff.saturating_mul(2u32.saturating_pow(attempt));
+                    attempt += 1;
+                    warn!(
+                        "Query for record {} failed: {err}, retrying in {backoff:?} ({attempt}/{})",
+                        hex::encode(key),
+                        self.config.retries
+                    );
+                    sleep(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryConfig {
+    pub timeout: Duration,
+    pub put_quorum: NonZeroUsize, // Peers that must store a record for a put to succeed
+    pub retries: u32,
+    pub retry_backoff: Duration,
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            put_quorum: NonZeroUsize::MIN,
+            retries: 3,
+            retry_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl QueryConfig {
+    pub(crate) fn quorum(&self) -> kad::Quorum {
+        kad::Quorum::N(self.put_quorum)
+    }
+}
+
+// Query started on behalf of a client, waiting for its result.
+#[derive(Debug)]
+pub(crate) enum PendingQuery {
+    Get(kad::RecordKey, oneshot::Sender<Result<Vec<u8>, QueryError>>),
+    Put(kad::RecordKey, oneshot::Sender<Result<(), QueryError>>),
+}
+
+impl PendingQuery {
+    pub(crate) fn key(&self) -> &kad::RecordKey {
+        match self {
+            PendingQuery::Get(key, _) | PendingQuery::Put(key, _) => key,
+        }
+    }
+
+    // Resolves the query with an error, returning its key.
+    pub(crate) fn fail(self, error: QueryError) -> kad::RecordKey {
+        match self {
+            PendingQuery::Get(key, reply) => {
+                let _ = reply.send(Err(error));
+                key
+            }
+            PendingQuery::Put(key, reply) => {
+                let _ = reply.send(Err(error));
+                key
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    #[error("record not found")]
+    NotFound,
+
+    #[error("quorum not reached")]
+    QuorumFailed,
+
+    #[error("query timed out")]
+    Timeout,
+
+    #[error("record store error: {0}")]
+    Store(String),
+
+    #[error("swarm is not running")]
+    Closed,
+}
+
+impl QueryError {
+    // Failures that may go away once more peers are reachable or the record propagated.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, QueryError::NotFound | QueryError::QuorumFailed | QueryError::Timeout)
+    }
+}
+
+impl From<kad::GetRecordError> for QueryError {
+    fn from(value: kad::GetRecordError) -> Self {
+        match value {
+            kad::GetRecordError::NotFound { .. } => QueryError::NotFound,
+            kad::GetRecordError::QuorumFailed { .. } => QueryError::QuorumFailed,
+            kad::GetRecordError::Timeout { .. } => QueryError::Timeout,
+        }
+    }
+}
+
+impl From<kad::PutRecordError> for QueryError {
+    fn from(value: kad::PutRecordError) -> Self {
+        match value {
+            kad::PutRecordError::QuorumFailed { .. } => QueryError::QuorumFailed,
+            kad::PutRecordError::Timeout { .. } => QueryError::Timeout,
+        }
+    }
+}
+
+impl From<kad::store::Error> for QueryError {
+    fn from(value: kad::store::Error) -> Self {
+        QueryError::Store(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let config =
+            QueryConfig { retries: 2, retry_backoff: Duration::ZERO, ..Default::default() };
+        let client = KademliaClient::new(tx, config);
+        let swarm = tokio::spawn(async move {
+            let mut replies =
+                vec![Ok(b"record".to_vec()), Err(QueryError::Timeout), Err(QueryError::NotFound)];
+            while let Some(KademliaMessage::GET((_, reply))) = rx.recv().await {
+                let _ = reply.send(replies.pop().unwrap());
+            }
+        });
+        assert_eq!(client.get_record(kad::RecordKey::new(b"key")).await, Ok(b"record".to_vec()));
+        drop(client);
+        swarm.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let config =
+            QueryConfig { retries: 1, retry_backoff: Duration::ZERO, ..Default::default() };
+        let client = KademliaClient::new(tx, config);
+        let swarm = tokio::spawn(async move {
+            let mut attempts = 0;
+            while let Some(KademliaMessage::PUT((_, _, _, reply))) = rx.recv().await {
+                attempts += 1;
+                let _ = reply.send(Err(QueryError::QuorumFailed));
+            }
+            attempts
+        });
+        let result = client.put_record(kad::RecordKey::new(b"key"), vec![], None).await;
+        assert_eq!(result, Err(QueryError::QuorumFailed));
+        drop(client);
+        assert_eq!(swarm.await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::blob::{BlobError, BlobHash, BlobMessage};
+use crate::event::{PeerEvent, PeerEvents};
+use crate::query::QueryError;
+use crate::{GossipMessage, GossipsubMessage, KademliaMessage};
+use async_stream::stream;
+use libp2p::{gossipsub::TopicHash, kad, PeerId};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::debug;
+
+/*
+    Network Simulation
+    Stands in for the swarm runner in tests, every node gets the event stream and channels a swarm runner would give it,
+    but messages travel through an in-process network driven by a seeded random generator.
+    Each gossip delivery and each DHT query can be dropped, delayed and duplicated independently,
+    random delays reorder messages. A dropped query fails with a timeout once the longest delay elapsed.
+    Delays run on tokio time, on a runtime with paused time they elapse instantly.
+    The seed sets the odds of every fault but does not replay a run, the nodes draw from the generator in whatever
+    order tokio schedules their tasks, so scenarios assert on properties that hold for any run.
+    Records and blobs live in one shared store, blobs are never lost since the faults target the market protocol.
+*/
+
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+pub struct SimNode {
+    pub peer_id: PeerId,
+    pub events: PeerEvents,
+    pub gossipsub_tx: mpsc::Sender<GossipsubMessage>,
+    pub kademlia_tx: mpsc::Sender<KademliaMessage>,
+    pub blob_tx: mpsc::Sender<BlobMessage>,
+}
+
+struct SimState {
+    rng: StdRng,
+    faults: FaultConfig,
+    peers: Vec<(PeerId, mpsc::UnboundedSender<PeerEvent>)>,
+    records: HashMap<kad::RecordKey, Vec<u8>>,
+    blobs: HashMap<BlobHash, Vec<u8>>,
+}
+
+impl SimState {
+    // Delays of the copies of a message that arrive, none when it is dropped.
+    fn deliveries(&mut self) -> Vec<Duration> {
+        if self.rng.gen_bool(self.faults.drop_rate) {
+            return vec![];
+        }
+        let copies = if self.rng.gen_bool(self.faults.duplicate_rate) { 2 } else { 1 };
+        (0..copies).map(|_| self.delay()).collect()
+    }
+
+    fn delay(&mut self) -> Duration {
+        let FaultConfig { min_delay, max_delay, .. } = self.faults;
+        self.rng.gen_range(min_delay..=max_delay.max(min_delay))
+    }
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, faults: FaultConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                faults,
+                peers: vec![],
+                records: HashMap::new(),
+                blobs: HashMap::new(),
+            })),
+        }
+    }
+
+    // Adds a node connected to every node already in the network.
+    pub fn join(&self, peer_id: PeerId) -> SimNode {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel::<PeerEvent>();
+        let (gossipsub_tx, gossipsub_rx) = mpsc::channel::<GossipsubMessage>(100);
+        let (kademlia_tx, kademlia_rx) = mpsc::channel::<KademliaMessage>(100);
+        let (blob_tx, blob_rx) = mpsc::channel::<BlobMessage>(100);
+
+        {
+            let mut state = self.state.lock().unwrap();
+            for (peer, tx) in state.peers.iter() {
+                let _ = tx.send(PeerEvent::PeerConnected(peer_id));
+                let _ = events_tx.send(PeerEvent::PeerConnected(*peer));
+            }
+            state.peers.push((peer_id, events_tx.to_owned()));
+        }
+
+        tokio::spawn(self.to_owned().route_gossip(peer_id, gossipsub_rx));
+        tokio::spawn(self.to_owned().serve_kademlia(events_tx, kademlia_rx));
+        tokio::spawn(self.to_owned().serve_blobs(blob_rx));
+
+        SimNode {
+            peer_id,
+            events: Box::pin(stream! {
+                while let Some(event) = events_rx.recv().await {
+                    yield event;
+                }
+            }),
+            gossipsub_tx,
+            kademlia_tx,
+            blob_tx,
+        }
+    }
+
+    async fn route_gossip(
+        self,
+        source: PeerId,
+        mut gossipsub_rx: mpsc::Receiver<GossipsubMessage>,
+    ) {
+        while let Some(message) = gossipsub_rx.recv().await {
+            let topic = message.topic.hash();
+            if let Err(err) = GossipMessage::decode(&topic, &message.data) {
+                debug!("Dropped undecodable gossip from {source}: {err}");
+                continue;
+            }
+            let deliveries: Vec<_> = {
+                let mut state = self.state.lock().unwrap();
+                let recipients: Vec<_> = state
+                    .peers
+                    .iter()
+                    .filter(|(peer, _)| *peer != source)
+                    .map(|(_, tx)| tx.to_owned())
+                    .collect();
+                recipients
+                    .into_iter()
+                    .flat_map(|tx| {
+                        state.deliveries().into_iter().map(move |delay| (tx.to_owned(), delay))
+                    })
+                    .collect()
+            };
+            for (tx, delay) in deliveries {
+                tokio::spawn(deliver(source, topic.to_owned(), message.data.to_owned(), tx, delay));
+            }
+        }
+    }
+
+    async fn serve_kademlia(
+        self,
+        events_tx: mpsc::UnboundedSender<PeerEvent>,
+        mut kademlia_rx: mpsc::Receiver<KademliaMessage>,
+    ) {
+        while let Some(message) = kademlia_rx.recv().await {
+            // Queries are idempotent, a duplicated one is answered by its first copy.
+            let (delay, lost) = {
+                let mut state = self.state.lock().unwrap();
+                match state.deliveries().first() {
+                    Some(delay) => (*delay, false),
+                    None => (state.faults.max_delay, true),
+                }
+            };
+            tokio::spawn(self.to_owned().resolve(message, delay, lost, events_tx.to_owned()));
+        }
+    }
+
+    async fn resolve(
+        self,
+        message: KademliaMessage,
+        delay: Duration,
+        lost: bool,
+        events_tx: mpsc::UnboundedSender<PeerEvent>,
+    ) {
+        sleep(delay).await;
+        let event = match message {
+            KademliaMessage::GET((key, reply)) => {
+                let result = if lost {
+                    Err(QueryError::Timeout)
+                } else {
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .records
+                        .get(&key)
+                        .cloned()
+                        .ok_or(QueryError::NotFound)
+                };
+                let event = match &result {
+                    Ok(value) => PeerEvent::RecordFetched { key, value: value.to_owned() },
+                    Err(reason) => PeerEvent::QueryFailed { key, reason: reason.to_owned() },
+                };
+                let _ = reply.send(result);
+                event
+            }
+            KademliaMessage::PUT((key, value, _, reply)) => {
+                let result = if lost {
+                    Err(QueryError::Timeout)
+                } else {
+                    self.state.lock().unwrap().records.insert(key.to_owned(), value);
+                    Ok(())
+                };
+                let event = match &result {
+                    Ok(()) => PeerEvent::RecordStored { key },
+                    Err(reason) => PeerEvent::QueryFailed { key, reason: reason.to_owned() },
+                };
+                let _ = reply.send(result);
+                event
+            }
+        };
+        let _ = events_tx.send(event);
+    }
+
+    async fn serve_blobs(self, mut blob_rx: mpsc::Receiver<BlobMessage>) {
+        while let Some(message) = blob_rx.recv().await {
+            let mut state = self.state.lock().unwrap();
+            match message {
+                BlobMessage::PUT((data, _, reply)) => {
+                    let root = BlobHash::digest(&data);
+                    state.blobs.insert(root, data);
+                    let _ = reply.send(Ok(root));
+                }
+                BlobMessage::GET((root, _, reply)) => {
+                    let _ = reply
+                        .send(state.blobs.get(&root).cloned().ok_or(BlobError::Unavailable(root)));
+                }
+            }
+        }
+    }
+}
+
+async fn deliver(
+    source: PeerId,
+    topic: TopicHash,
+    data: Vec<u8>,
+    tx: mpsc::UnboundedSender<PeerEvent>,
+    delay: Duration,
+) {
+    sleep(delay).await;
+    if let Ok(message) = GossipMessage::decode(&topic, &data) {
+        let _ = tx.send(PeerEvent::from_gossip(source, message));
+    }
+}
@@ -0,0 +1,544 @@
+use libp2p::kad::store::{Error, MemoryStore, MemoryStoreConfig, RecordStore, Result};
+use libp2p::kad::{ProviderRecord, Record, RecordKey};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{hash_map, HashMap};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, thread};
+use tracing::{error, warn};
+
+/*
+    Record Store
+    Kademlia storage of the node, either kept in memory or persisted to disk.
+    The disk store is not disk backed, it holds every record in memory like the memory store and writes one file
+    per record behind the swarm, so infrastructure nodes keep holding job and proof records across restarts.
+    Only files named after their record are loaded and removed, anything else in the directory is left alone.
+    Quotas bound the number of records, the size of a single value and the total size of the values,
+    which is both the memory and the disk space the records take.
+    Records only reference blobs and take a few dozen bytes, so the number of records is the limit reached
+    in practice, the total size only binds when records close to the value size cap fill the store.
+    Expired records are garbage collected when a quota is hit and periodically by the swarm.
+    Provider records are short lived and always kept in memory.
+*/
+
+#[derive(Debug, Clone)]
+pub enum StoreConfig {
+    Memory(MemoryStoreConfig),
+    Disk(DiskStoreConfig),
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Memory(MemoryStoreConfig {
+            max_value_bytes: crate::MAX_RECORD_SIZE,
+            ..Default::default()
+        })
+    }
+}
+
+// Records kept by default, a record referencing a blob takes a few dozen bytes.
+pub const DEFAULT_MAX_RECORDS: usize = 16 * 1024;
+
+// Total size of the record values kept by default, reached before the record count only by large records.
+pub const DEFAULT_MAX_RECORD_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DiskStoreConfig {
+    pub path: PathBuf,
+    pub max_records: usize,
+    pub max_value_bytes: usize,
+    pub max_total_bytes: u64,
+}
+
+impl DiskStoreConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_records: DEFAULT_MAX_RECORDS,
+            max_value_bytes: crate::MAX_RECORD_SIZE,
+            max_total_bytes: DEFAULT_MAX_RECORD_BYTES,
+        }
+    }
+}
+
+pub enum PeerStore {
+    Memory(MemoryStore),
+    Disk(DiskStore),
+}
+
+impl PeerStore {
+    pub fn open(local_id: PeerId, config: StoreConfig) -> io::Result<Self> {
+        Ok(match config {
+            StoreConfig::Memory(config) => Self::Memory(MemoryStore::with_config(local_id, config)),
+            StoreConfig::Disk(config) => Self::Disk(DiskStore::open(local_id, config)?),
+        })
+    }
+
+    // Drops expired records, the memory store relies on Kademlia dropping them when they are read.
+    pub fn gc(&mut self) {
+        if let Self::Disk(store) = self {
+            store.gc();
+        }
+    }
+}
+
+pub enum PeerStoreRecords<'a> {
+    Memory(<MemoryStore as RecordStore>::RecordsIter<'a>),
+    Disk(DiskRecords<'a>),
+}
+
+impl<'a> Iterator for PeerStoreRecords<'a> {
+    type Item = Cow<'a, Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Memory(records) => records.next(),
+            Self::Disk(records) => records.next(),
+        }
+    }
+}
+
+impl RecordStore for PeerStore {
+    type RecordsIter<'a> = PeerStoreRecords<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        match self {
+            Self::Memory(store) => store.get(k),
+            Self::Disk(store) => store.get(k),
+        }
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        match self {
+            Self::Memory(store) => store.put(r),
+            Self::Disk(store) => store.put(r),
+        }
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        match self {
+            Self::Memory(store) => store.remove(k),
+            Self::Disk(store) => store.remove(k),
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        match self {
+            Self::Memory(store) => PeerStoreRecords::Memory(store.records()),
+            Self::Disk(store) => PeerStoreRecords::Disk(store.records()),
+        }
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        self.providers_store().add_provider(record)
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        match self {
+            Self::Memory(store) => store.providers(key),
+            Self::Disk(store) => store.providers.providers(key),
+        }
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        match self {
+            Self::Memory(store) => store.provided(),
+            Self::Disk(store) => store.providers.provided(),
+        }
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.providers_store().remove_provider(k, p)
+    }
+}
+
+impl PeerStore {
+    fn providers_store(&mut self) -> &mut MemoryStore {
+        match self {
+            Self::Memory(store) => store,
+            Self::Disk(store) => &mut store.providers,
+        }
+    }
+}
+
+// What is written to disk, expiries are stored as unix milliseconds since an `Instant` does not survive a restart.
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+const RECORD_SUFFIX: &str = ".record";
+const TMP_SUFFIX: &str = ".tmp";
+
+pub struct DiskStore {
+    config: DiskStoreConfig,
+    records: HashMap<RecordKey, Record>,
+    total_bytes: u64,
+    providers: MemoryStore,
+    writer: FileWriter,
+}
+
+impl DiskStore {
+    pub fn open(local_id: PeerId, config: DiskStoreConfig) -> io::Result<Self> {
+        let files = read_files(&config.path, RECORD_SUFFIX)?;
+        let mut store = Self {
+            writer: FileWriter::spawn(config.path.to_owned())?,
+            config,
+            records: HashMap::new(),
+            total_bytes: 0,
+            providers: MemoryStore::new(local_id),
+        };
+
+        for (path, data) in files {
+            match bincode::deserialize::<StoredRecord>(&data) {
+                Ok(stored) => {
+                    let record = Self::from_stored(stored);
+                    if record.is_expired(Instant::now()) || path != store.path(&record.key) {
+                        fs::remove_file(&path)?;
+                    } else {
+                        store.insert(record);
+                    }
+                }
+                Err(err) => {
+                    warn!("Removing unreadable record file {}: {err}", path.display());
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    pub fn gc(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<RecordKey> = self
+            .records
+            .iter()
+            .filter(|(_, record)| record.is_expired(now))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    fn path(&self, key: &RecordKey) -> PathBuf {
+        self.config.path.join(format!("{}{RECORD_SUFFIX}", hex::encode(key)))
+    }
+
+    fn insert(&mut self, record: Record) {
+        self.total_bytes += record.value.len() as u64;
+        if let Some(previous) = self.records.insert(record.key.to_owned(), record) {
+            self.total_bytes -= previous.value.len() as u64;
+        }
+    }
+
+    fn to_stored(record: &Record) -> StoredRecord {
+        StoredRecord {
+            key: record.key.to_vec(),
+            value: record.value.to_owned(),
+            publisher: record.publisher.map(|publisher| publisher.to_bytes()),
+            expires: record.expires.map(unix_millis),
+        }
+    }
+
+    fn from_stored(stored: StoredRecord) -> Record {
+        Record {
+            key: RecordKey::from(stored.key),
+            value: stored.value,
+            publisher: stored.publisher.and_then(|publisher| PeerId::from_bytes(&publisher).ok()),
+            expires: stored.expires.map(from_unix_millis),
+        }
+    }
+
+    fn fits(&self, key: &RecordKey, size: u64) -> bool {
+        let previous = self.records.get(key).map(|record| record.value.len() as u64);
+        (previous.is_some() || self.records.len() < self.config.max_records)
+            && self.total_bytes - previous.unwrap_or_default() + size <= self.config.max_total_bytes
+    }
+
+    pub fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.records.get(k).map(Cow::Borrowed)
+    }
+
+    pub fn put(&mut self, r: Record) -> Result<()> {
+        if r.value.len() > self.config.max_value_bytes {
+            return Err(Error::ValueTooLarge);
+        }
+
+        let size = r.value.len() as u64;
+        if !self.fits(&r.key, size) {
+            self.gc();
+            if !self.fits(&r.key, size) {
+                return Err(Error::MaxRecords);
+            }
+        }
+
+        match bincode::serialize(&Self::to_stored(&r)) {
+            Ok(data) => self.writer.write(self.path(&r.key), data),
+            // Still served from memory, it is only lost on restart.
+            Err(err) => error!("Failed to encode record {}: {err}", hex::encode(&r.key)),
+        }
+        self.insert(r);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, k: &RecordKey) {
+        if let Some(record) = self.records.remove(k) {
+            self.total_bytes -= record.value.len() as u64;
+            self.writer.remove(self.path(k));
+        }
+    }
+
+    pub fn records(&self) -> DiskRecords<'_> {
+        DiskRecords { records: self.records.values() }
+    }
+}
+
+pub struct DiskRecords<'a> {
+    records: hash_map::Values<'a, RecordKey, Record>,
+}
+
+impl<'a> Iterator for DiskRecords<'a> {
+    type Item = Cow<'a, Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.records.next().map(Cow::Borrowed)
+    }
+}
+
+// Converts an expiry to unix milliseconds and back, an `Instant` does not survive a restart.
+pub(crate) fn unix_millis(instant: Instant) -> u64 {
+    let remaining = instant.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining).duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+        as u64
+}
+
+pub(crate) fn from_unix_millis(millis: u64) -> Instant {
+    let remaining = (UNIX_EPOCH + Duration::from_millis(millis))
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now() + remaining
+}
+
+// Reads the files with the suffix in the directory, creating it if missing.
+pub(crate) fn read_files(dir: &Path, suffix: &str) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+    list_files(dir, suffix)?
+        .into_iter()
+        .map(|path| fs::read(&path).map(|data| (path, data)))
+        .collect()
+}
+
+// Lists the files with the suffix in the directory, creating it if missing.
+// Leftover temporary files of an interrupted write are removed, other files and directories are left alone.
+pub(crate) fn list_files(dir: &Path, suffix: &str) -> io::Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)?;
+    let tmp_suffix = format!("{suffix}{TMP_SUFFIX}");
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let file_type = entry.file_type()?;
+        let name = name.to_str().filter(|_| file_type.is_file());
+        if file_type.is_dir() {
+            continue;
+        } else if name.is_some_and(|name| name.ends_with(&tmp_suffix)) {
+            fs::remove_file(&path)?;
+        } else if name.is_some_and(|name| name.ends_with(suffix)) {
+            files.push(path);
+        } else {
+            warn!("Ignoring unknown entry {}", path.display());
+        }
+    }
+    Ok(files)
+}
+
+enum FileOp {
+    Write(PathBuf, Vec<u8>),
+    Remove(PathBuf),
+}
+
+/*
+    File Writer
+    Persists files on a thread of its own, so the swarm never waits on the disk.
+    A file is written next to its final path, synced and renamed before the directory is synced,
+    so a crash leaves either the previous or the new file behind, never a half written one.
+    Dropping the writer waits for the files queued before.
+*/
+pub(crate) struct FileWriter {
+    ops: Option<std_mpsc::Sender<FileOp>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FileWriter {
+    pub(crate) fn spawn(dir: PathBuf) -> io::Result<Self> {
+        let (ops, ops_rx) = std_mpsc::channel::<FileOp>();
+        let thread = thread::Builder::new()
+            .name("zetina-file-writer".to_string())
+            .spawn(move || ops_rx.into_iter().for_each(|op| apply(&dir, op)))?;
+        Ok(Self { ops: Some(ops), thread: Some(thread) })
+    }
+
+    pub(crate) fn write(&self, path: PathBuf, data: Vec<u8>) {
+        self.send(FileOp::Write(path, data));
+    }
+
+    pub(crate) fn remove(&self, path: PathBuf) {
+        self.send(FileOp::Remove(path));
+    }
+
+    fn send(&self, op: FileOp) {
+        if let Some(ops) = &self.ops {
+            let _ = ops.send(op);
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    fn drop(&mut self) {
+        self.ops.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn apply(dir: &Path, op: FileOp) {
+    let (path, applied) = match op {
+        FileOp::Write(path, data) => {
+            let written = write_file(dir, &path, &data);
+            (path, written)
+        }
+        FileOp::Remove(path) => {
+            let removed = fs::remove_file(&path).or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(err),
+            });
+            (path, removed)
+        }
+    };
+    if let Err(err) = applied {
+        error!("Failed to persist {}: {err}", path.display());
+    }
+}
+
+pub(crate) fn write_file(dir: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(TMP_SUFFIX);
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    sync_dir(dir)
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened as files on other platforms, renames are left to the file system.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(key: &[u8], value: Vec<u8>, expires: Option<Instant>) -> Record {
+        Record { key: RecordKey::new(&key), value, publisher: None, expires }
+    }
+
+    #[test]
+    fn records_survive_reopen() {
+        let dir = TempDir::new().unwrap();
+        let local_id = PeerId::random();
+        let config = DiskStoreConfig::new(dir.path().to_owned());
+
+        let mut store = DiskStore::open(local_id, config.to_owned()).unwrap();
+        store.put(record(&[1], vec![1, 2, 3], None)).unwrap();
+        store.put(record(&[2], vec![4], Some(Instant::now() + Duration::from_secs(600)))).unwrap();
+        drop(store);
+
+        let store = DiskStore::open(local_id, config).unwrap();
+        assert_eq!(store.get(&RecordKey::new(&[1])).unwrap().value, vec![1, 2, 3]);
+        assert!(store.get(&RecordKey::new(&[2])).unwrap().expires.is_some());
+        assert_eq!(store.records().count(), 2);
+        assert_eq!(store.total_bytes(), 4);
+    }
+
+    #[test]
+    fn expired_records_are_collected() {
+        let dir = TempDir::new().unwrap();
+        let mut store =
+            DiskStore::open(PeerId::random(), DiskStoreConfig::new(dir.path().to_owned())).unwrap();
+        store.put(record(&[1], vec![1], Some(Instant::now()))).unwrap();
+        store.gc();
+        assert!(store.get(&RecordKey::new(&[1])).is_none());
+        drop(store);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn foreign_entries_are_left_alone() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        fs::write(dir.path().join("notes.txt"), [1, 2, 3]).unwrap();
+        fs::write(dir.path().join("broken.record"), [1, 2, 3]).unwrap();
+        fs::write(dir.path().join("01.record.tmp"), [1, 2, 3]).unwrap();
+        let store =
+            DiskStore::open(PeerId::random(), DiskStoreConfig::new(dir.path().to_owned())).unwrap();
+        assert_eq!(store.records().count(), 0);
+        let mut left: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["nested", "notes.txt"]);
+    }
+
+    #[test]
+    fn default_total_size_is_reachable() {
+        let config = DiskStoreConfig::new(PathBuf::new());
+        let largest = config.max_records as u64 * config.max_value_bytes as u64;
+        assert!(config.max_total_bytes < largest);
+        assert!(config.max_total_bytes >= config.max_value_bytes as u64);
+    }
+
+    #[test]
+    fn quotas() {
+        let dir = TempDir::new().unwrap();
+        let mut store = DiskStore::open(
+            PeerId::random(),
+            DiskStoreConfig {
+                max_records: 1,
+                max_value_bytes: 4,
+                max_total_bytes: 4,
+                ..DiskStoreConfig::new(dir.path().to_owned())
+            },
+        )
+        .unwrap();
+        assert!(matches!(store.put(record(&[1], vec![0; 5], None)), Err(Error::ValueTooLarge)));
+        store.put(record(&[1], vec![0; 4], None)).unwrap();
+        assert!(matches!(store.put(record(&[2], vec![0], None)), Err(Error::MaxRecords)));
+        // Replacing a record only counts its new size.
+        store.put(record(&[1], vec![0; 2], None)).unwrap();
+        assert_eq!(store.total_bytes(), 2);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{DelegationMessage, MarketMessage, NetworkingMessage};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::ops::RangeInclusive;
+use thiserror::Error;
+use zetina_common::{job::Job, job_witness::JobWitness};
+
+/*
+    Wire Protocol
+    Every gossipsub message and every DHT record is wrapped in a versioned envelope:
+    a big endian u16 protocol version, a u8 message type and the bincode encoded payload.
+    The envelope layout itself never changes, so a peer can always tell which version a message was written with.
+    Messages of versions outside the supported range are ignored rather than rejected,
+    so during an upgrade peers running another release are not penalised and the network keeps working
+    for everything both sides understand.
+    Releases before the envelope published plain JSON on unversioned topics, those messages are still decoded
+    into the current types and ignored when they no longer match. Only JSON shaped like a message of those releases
+    is ignored, anything else on a legacy topic is rejected like any other malformed message.
+*/
+
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Oldest version this release still decodes and subscribes to, raised once a version is retired.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const SUPPORTED_PROTOCOL_VERSIONS: RangeInclusive<u16> =
+    MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+
+const HEADER_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Networking = 0,
+    Market = 1,
+    Delegation = 2,
+    Job = 3,
+    JobWitness = 4,
+    BlobRoot = 5,
+    BlobManifest = 6,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = WireError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageType::Networking),
+            1 => Ok(MessageType::Market),
+            2 => Ok(MessageType::Delegation),
+            3 => Ok(MessageType::Job),
+            4 => Ok(MessageType::JobWitness),
+            5 => Ok(MessageType::BlobRoot),
+            6 => Ok(MessageType::BlobManifest),
+            _ => Err(WireError::UnknownMessageType(value)),
+        }
+    }
+}
+
+pub trait WireMessage: Serialize + DeserializeOwned {
+    const MESSAGE_TYPE: MessageType;
+    // Variants the message had in the releases before the envelope, published as JSON on the legacy topics.
+    const LEGACY_VARIANTS: &'static [&'static str] = &[];
+}
+
+impl WireMessage for NetworkingMessage {
+    const MESSAGE_TYPE: MessageType = MessageType::Networking;
+    const LEGACY_VARIANTS: &'static [&'static str] = &["Multiaddr"];
+}
+
+impl WireMessage for MarketMessage {
+    const MESSAGE_TYPE: MessageType = MessageType::Market;
+    const LEGACY_VARIANTS: &'static [&'static str] = &["Job", "JobBidPropagation", "JobBid"];
+}
+
+impl WireMessage for DelegationMessage {
+    const MESSAGE_TYPE: MessageType = MessageType::Delegation;
+    const LEGACY_VARIANTS: &'static [&'static str] = &["Delegate", "Finished"];
+}
+
+impl WireMessage for Job {
+    const MESSAGE_TYPE: MessageType = MessageType::Job;
+}
+
+impl WireMessage for JobWitness {
+    const MESSAGE_TYPE: MessageType = MessageType::JobWitness;
+}
+
+pub fn encode<T: WireMessage>(message: &T) -> Result<Vec<u8>, WireError> {
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    data.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    data.push(T::MESSAGE_TYPE as u8);
+    bincode::DefaultOptions::new().serialize_into(&mut data, message)?;
+    Ok(data)
+}
+
+// Decodes a message of at most `limit` bytes, the limit also bounds allocations made while decoding.
+pub fn decode<T: WireMessage>(data: &[u8], limit: usize) -> Result<T, WireError> {
+    if data.len() > limit {
+        return Err(WireError::TooLarge { size: data.len(), limit });
+    }
+
+    let (header, payload) = data.split_at_checked(HEADER_SIZE).ok_or(WireError::Truncated)?;
+    let version = u16::from_be_bytes([header[0], header[1]]);
+    if !SUPPORTED_PROTOCOL_VERSIONS.contains(&version) {
+        return Err(WireError::UnsupportedVersion(version));
+    }
+
+    let message_type = MessageType::try_from(header[2])?;
+    if message_type != T::MESSAGE_TYPE {
+        return Err(WireError::UnexpectedMessageType(message_type));
+    }
+
+    Ok(bincode::DefaultOptions::new().with_limit(limit as u64).deserialize(payload)?)
+}
+
+// Decodes a JSON message of a release before the envelope, received on an unversioned topic.
+// A message of a legacy variant that does not match the current types is incompatible, anything else is malformed.
+pub fn decode_legacy<T: WireMessage>(data: &[u8], limit: usize) -> Result<T, WireError> {
+    if data.len() > limit {
+        return Err(WireError::TooLarge { size: data.len(), limit });
+    }
+    let value: serde_json::Value = serde_json::from_slice(data).map_err(WireError::Json)?;
+    let known = legacy_variant(&value)
+        .is_some_and(|variant| T::LEGACY_VARIANTS.iter().any(|legacy| *legacy == variant));
+    serde_json::from_value(value).map_err(|err| match known {
+        true => WireError::Legacy(err),
+        false => WireError::Json(err),
+    })
+}
+
+// Enums are encoded externally tagged, as the variant name or an object with the variant name as its only key.
+fn legacy_variant(value: &serde_json::Value) -> Option<&str> {
+    match value {
+        serde_json::Value::String(variant) => Some(variant),
+        serde_json::Value::Object(map) if map.len() == 1 => map.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum WireError {
+    #[error("unknown topic {0}")]
+    UnknownTopic(libp2p::gossipsub::TopicHash),
+
+    #[error("message of {size} bytes exceeds the limit of {limit} bytes")]
+    TooLarge { size: usize, limit: usize },
+
+    #[error("message is shorter than the envelope header")]
+    Truncated,
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedVersion(u16),
+
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+
+    #[error("unexpected message type {0:?}")]
+    UnexpectedMessageType(MessageType),
+
+    #[error("bincode error: {0}")]
+    Bincode(#[from] bincode::Error),
+
+    #[error("incompatible legacy message: {0}")]
+    Legacy(serde_json::Error),
+
+    #[error("malformed legacy message: {0}")]
+    Json(serde_json::Error),
+}
+
+impl WireError {
+    // Messages of another protocol version, known legacy ones included, are not the sender's fault.
+    pub fn is_incompatible(&self) -> bool {
+        matches!(self, WireError::UnsupportedVersion(_) | WireError::Legacy(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::kad;
+
+    #[test]
+    fn roundtrip() {
+        let message =
+            DelegationMessage::Finished(kad::RecordKey::new(&[1]), kad::RecordKey::new(&[2]));
+        let data = encode(&message).unwrap();
+        assert_eq!(&data[..HEADER_SIZE], &[0, 1, MessageType::Delegation as u8]);
+        assert!(matches!(
+            decode::<DelegationMessage>(&data, data.len()).unwrap(),
+            DelegationMessage::Finished(..)
+        ));
+    }
+
+    #[test]
+    fn incompatible_version() {
+        let mut data = encode(&MarketMessage::JobBidPropagation(
+            kad::RecordKey::new(&[1]),
+            Default::default(),
+        ))
+        .unwrap();
+        data[..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let err = decode::<MarketMessage>(&data, data.len()).unwrap_err();
+        assert!(err.is_incompatible());
+    }
+
+    #[test]
+    fn unexpected_message_type() {
+        let data = encode(&DelegationMessage::Finished(
+            kad::RecordKey::new(&[1]),
+            kad::RecordKey::new(&[2]),
+        ))
+        .unwrap();
+        assert!(matches!(
+            decode::<MarketMessage>(&data, data.len()),
+            Err(WireError::UnexpectedMessageType(MessageType::Delegation))
+        ));
+    }
+}
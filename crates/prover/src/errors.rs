@@ -1,22 +1,48 @@
+use std::{io, time::Duration};
 use thiserror::Error;
+use zetina_common::process::{ChildError, ChildFailure};
 
 #[derive(Error, Debug)]
 pub enum ProverControllerError {
     #[error("task not found")]
     TaskNotFound,
 
-    #[error("task not found")]
+    #[error("task aborted")]
     TaskTerminated,
 
-    #[error("io")]
-    Io(#[from] std::io::Error),
+    #[error("proving timed out after {0:?}: {1}")]
+    Timeout(Duration, ChildFailure),
+
+    #[error("proving exceeded its {0}: {1}")]
+    ResourceExceeded(String, ChildFailure),
+
+    #[error("proving failed: {0}")]
+    Failed(ChildFailure),
+
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
 
-    #[error("serde")]
+    #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
 
-    #[error("proof parsing error")]
+    #[error("proof parsing error: {0}")]
     ProofParseError(String),
 
     #[error("could not get number of steps")]
     NumberOfStepsUnavailable,
+
+    #[error("{0} not found on PATH")]
+    ProverNotFound(&'static str),
+}
+
+impl From<ChildError> for ProverControllerError {
+    fn from(err: ChildError) -> Self {
+        match err {
+            ChildError::Io(err) => Self::Io(err),
+            ChildError::Aborted => Self::TaskTerminated,
+            ChildError::Timeout(timeout, failure) => Self::Timeout(timeout, failure),
+            ChildError::ResourceExceeded(limit, failure) => Self::ResourceExceeded(limit, failure),
+            ChildError::Failed(failure) => Self::Failed(failure),
+        }
+    }
 }
@@ -6,87 +6,96 @@ use crate::{errors::ProverControllerError, traits::ProverController};
 use async_process::Stdio;
 use futures::Future;
 use serde_json::Value;
-use std::{
-    fs,
-    io::{Read, Write},
-    pin::Pin,
-};
-use tempfile::NamedTempFile;
-use tokio::{process::Command, select, sync::mpsc};
+use sha2::{Digest, Sha256};
+use std::{env, fs, pin::Pin, time::Duration};
+use tokio::{process::Command, sync::mpsc};
 use tracing::debug;
-use zetina_common::{job_trace::JobTrace, job_witness::JobWitness, process::Process};
+use zetina_common::{
+    job_trace::JobTrace,
+    job_witness::JobWitness,
+    process::{supervise_child, Process, ProcessLimits},
+};
 
 pub mod tests;
 pub mod types;
 
-pub struct StoneProver {}
+pub struct StoneProver {
+    limits: ProcessLimits,
+}
 
 impl StoneProver {
+    pub const BACKEND: &'static str = "stone";
+    pub const BINARY: &'static str = "cpu_air_prover";
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
     pub fn new() -> Self {
-        Self {}
+        Self {
+            limits: ProcessLimits { timeout: Some(Self::DEFAULT_TIMEOUT), ..Default::default() },
+        }
+    }
+
+    pub fn with_limits(mut self, limits: ProcessLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Identifies the installed prover build by the hash of its binary, stone reports no version.
+    pub fn version() -> Result<String, ProverControllerError> {
+        let path = env::var_os("PATH")
+            .iter()
+            .flat_map(env::split_paths)
+            .map(|dir| dir.join(Self::BINARY))
+            .find(|path| path.is_file())
+            .ok_or(ProverControllerError::ProverNotFound(Self::BINARY))?;
+        let digest = Sha256::digest(fs::read(path)?);
+        Ok(format!("sha256:{}", hex::encode(&digest[..8])))
     }
 }
 
 impl ProverController for StoneProver {
     fn run(
         &self,
-        job_trace: JobTrace,
+        mut job_trace: JobTrace,
     ) -> Result<Process<Result<JobWitness, ProverControllerError>>, ProverControllerError> {
         let (terminate_tx, mut terminate_rx) = mpsc::channel::<()>(10);
+        let timeout_tx = terminate_tx.to_owned();
         let future: Pin<
             Box<dyn Future<Output = Result<JobWitness, ProverControllerError>> + Send + '_>,
         > = Box::pin(async move {
-            let mut out_file = NamedTempFile::new()?;
-
-            let mut cpu_air_prover_config = NamedTempFile::new()?;
-            let mut cpu_air_params = NamedTempFile::new()?;
+            // Written next to the trace, the workspace goes away with the trace once proving ends.
+            let out_file = job_trace.workspace.file("proof.json");
+            let cpu_air_prover_config = job_trace.workspace.file("cpu_air_prover_config.json");
+            let cpu_air_params = job_trace.workspace.file("cpu_air_params.json");
 
             let n_steps: u64 = serde_json::from_str::<Value>(
-                fs::read_to_string(job_trace.air_public_input.path())?.as_str(),
+                fs::read_to_string(job_trace.air_public_input())?.as_str(),
             )?["n_steps"]
                 .as_u64()
                 .ok_or(ProverControllerError::NumberOfStepsUnavailable)?;
 
-            cpu_air_prover_config
-                .write_all(&serde_json::to_string(&config(n_steps))?.into_bytes())?;
-            cpu_air_params.write_all(&serde_json::to_string(&params(n_steps))?.into_bytes())?;
+            fs::write(&cpu_air_prover_config, serde_json::to_string(&config(n_steps))?)?;
+            fs::write(&cpu_air_params, serde_json::to_string(&params(n_steps))?)?;
 
-            let mut task = Command::new("cpu_air_prover")
+            let mut command = Command::new(Self::BINARY);
+            command
                 .arg("--out_file")
-                .arg(out_file.path())
+                .arg(&out_file)
                 .arg("--private_input_file")
-                .arg(job_trace.air_private_input.path())
+                .arg(job_trace.air_private_input())
                 .arg("--public_input_file")
-                .arg(job_trace.air_public_input.path())
+                .arg(job_trace.air_public_input())
                 .arg("--prover_config_file")
-                .arg(cpu_air_prover_config.path())
+                .arg(&cpu_air_prover_config)
                 .arg("--parameter_file")
-                .arg(cpu_air_params.path())
+                .arg(&cpu_air_params)
                 .arg("--generate_annotations")
-                .stdout(Stdio::null())
-                .spawn()?;
-
-            debug!("task {} spawned", hex::encode(&job_trace.job_key));
-
-            loop {
-                select! {
-                    output = task.wait() => {
-                        debug!("{:?}", output);
-                        if !output?.success() {
-                            return Err(ProverControllerError::TaskTerminated);
-                        }
-                        let output = task.wait_with_output().await?;
-                        debug!("{:?}", output);
-                        break;
-                    }
-                    Some(()) = terminate_rx.recv() => {
-                        task.start_kill()?;
-                    }
-                }
-            }
-
-            let mut proof = Vec::new();
-            out_file.read_to_end(&mut proof)?;
+                .stdout(Stdio::null());
+
+            debug!("spawning task {}", hex::encode(&job_trace.job_key));
+            supervise_child(command, &self.limits, timeout_tx, &mut terminate_rx).await?;
+
+            let proof = fs::read(&out_file)?;
+            job_trace.workspace.succeed();
 
             Ok(JobWitness { job_key: job_trace.job_key.to_owned(), proof })
         });
@@ -1,7 +1,6 @@
 use libp2p::kad;
-use std::{env, fs, io::Write, path::PathBuf};
-use tempfile::NamedTempFile;
-use zetina_common::job_trace::JobTrace;
+use std::{env, fs, path::PathBuf};
+use zetina_common::{job_trace::JobTrace, workspace::WorkspaceConfig};
 
 pub struct TestFixture {
     pub job_trace: JobTrace,
@@ -16,25 +15,12 @@ pub fn fixture() -> TestFixture {
     let memory_path = ws_root.join("crates/tests/cairo/memory");
     let trace_path = ws_root.join("crates/tests/cairo/trace");
 
-    let mut air_public_input = NamedTempFile::new().unwrap();
-    air_public_input.write_all(&fs::read(air_public_input_path).unwrap()).unwrap();
+    let workspace = WorkspaceConfig::default().create("fixture").unwrap();
+    let job_trace = JobTrace::new(kad::RecordKey::new(&[0]), workspace);
+    fs::copy(air_public_input_path, job_trace.air_public_input()).unwrap();
+    fs::copy(air_private_input_path, job_trace.air_private_input()).unwrap();
+    fs::copy(memory_path, job_trace.memory()).unwrap();
+    fs::copy(trace_path, job_trace.trace()).unwrap();
 
-    let mut air_private_input = NamedTempFile::new().unwrap();
-    air_private_input.write_all(&fs::read(air_private_input_path).unwrap()).unwrap();
-
-    let mut memory = NamedTempFile::new().unwrap();
-    memory.write_all(&fs::read(memory_path).unwrap()).unwrap();
-
-    let mut trace = NamedTempFile::new().unwrap();
-    trace.write_all(&fs::read(trace_path).unwrap()).unwrap();
-
-    TestFixture {
-        job_trace: JobTrace::new(
-            kad::RecordKey::new(&[0]),
-            air_public_input,
-            air_private_input,
-            memory,
-            trace,
-        ),
-    }
+    TestFixture { job_trace }
 }
@@ -1,7 +1,10 @@
 use crate::{
+    errors::ProverControllerError,
     stone_prover::{tests::models::fixture, StoneProver},
     traits::ProverController,
 };
+use std::time::Duration;
+use zetina_common::process::ProcessLimits;
 
 #[tokio::test]
 async fn run_single_job_trace() {
@@ -20,3 +23,13 @@ async fn abort_single_job_trace() {
     job.abort().await.unwrap();
     job.await.unwrap_err();
 }
+
+#[tokio::test]
+async fn timed_out_single_job_trace() {
+    let fixture = fixture();
+
+    let limits = ProcessLimits { timeout: Some(Duration::from_millis(1)), ..Default::default() };
+    let prover = StoneProver::new().with_limits(limits);
+    let err = prover.run(fixture.job_trace).unwrap().await.unwrap_err();
+    assert!(matches!(err, ProverControllerError::Timeout(..)));
+}
@@ -4,15 +4,22 @@ use async_process::Stdio;
 use futures::Future;
 use libp2p::kad;
 use starknet::signers::VerifyingKey;
+use std::{fs, path::PathBuf};
 use std::{
     hash::{DefaultHasher, Hash, Hasher},
     pin::Pin,
+    time::Duration,
 };
-use std::{io::Write, path::PathBuf};
-use tempfile::NamedTempFile;
-use tokio::{process::Command, select, sync::mpsc};
+use tokio::{process::Command, sync::mpsc};
 use tracing::debug;
-use zetina_common::{hash, job::Job, job_trace::JobTrace, layout::Layout, process::Process};
+use zetina_common::{
+    hash,
+    job::Job,
+    job_trace::JobTrace,
+    layout::Layout,
+    process::{supervise_child, Process, ProcessLimits},
+    workspace::WorkspaceConfig,
+};
 
 pub mod tests;
 pub mod types;
@@ -20,11 +27,26 @@ pub mod types;
 pub struct CairoRunner {
     program_path: PathBuf,
     verifying_key: VerifyingKey,
+    limits: ProcessLimits,
+    workspaces: WorkspaceConfig,
 }
 
 impl CairoRunner {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
     pub fn new(program_path: PathBuf, verifying_key: VerifyingKey) -> Self {
-        Self { program_path, verifying_key }
+        let limits = ProcessLimits { timeout: Some(Self::DEFAULT_TIMEOUT), ..Default::default() };
+        Self { program_path, verifying_key, limits, workspaces: WorkspaceConfig::default() }
+    }
+
+    pub fn with_limits(mut self, limits: ProcessLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_workspaces(mut self, workspaces: WorkspaceConfig) -> Self {
+        self.workspaces = workspaces;
+        self
     }
 }
 
@@ -34,69 +56,49 @@ impl RunnerController for CairoRunner {
         job: Job,
     ) -> Result<Process<Result<JobTrace, RunnerControllerError>>, RunnerControllerError> {
         let (terminate_tx, mut terminate_rx) = mpsc::channel::<()>(10);
+        let timeout_tx = terminate_tx.to_owned();
         let future: Pin<
             Box<dyn Future<Output = Result<JobTrace, RunnerControllerError>> + Send + '_>,
         > = Box::pin(async move {
             let job_key = kad::RecordKey::new(&hash!(job).to_be_bytes());
             let layout: &str = Layout::Starknet.into();
 
-            let mut cairo_pie = NamedTempFile::new()?;
-            cairo_pie.write_all(&job.job_data.cairo_pie_compressed)?;
-
             let input = SimpleBootloaderInput {
                 public_key: self.verifying_key.scalar(),
                 job,
                 single_page: true,
             };
 
-            let mut program_input = NamedTempFile::new()?;
-            program_input.write_all(&serde_json::to_string(&input)?.into_bytes())?;
+            // The trace takes over the workspace, it is removed once the job is proven or failed.
+            let workspace = self.workspaces.create(&hex::encode(&job_key))?;
+            let job_trace = JobTrace::new(job_key, workspace);
 
-            // outputs
-            let air_public_input = NamedTempFile::new()?;
-            let air_private_input = NamedTempFile::new()?;
-            let trace = NamedTempFile::new()?;
-            let memory = NamedTempFile::new()?;
+            let program_input = job_trace.workspace.file("program_input.json");
+            fs::write(&program_input, serde_json::to_string(&input)?)?;
 
-            let mut task = Command::new("cairo-run")
+            let mut command = Command::new("cairo-run");
+            command
                 .arg("--program")
                 .arg(self.program_path.as_path())
                 .arg("--layout")
                 .arg(layout)
                 .arg("--program_input")
-                .arg(program_input.path())
+                .arg(&program_input)
                 .arg("--air_public_input")
-                .arg(air_public_input.path())
+                .arg(job_trace.air_public_input())
                 .arg("--air_private_input")
-                .arg(air_private_input.path())
+                .arg(job_trace.air_private_input())
                 .arg("--trace_file")
-                .arg(trace.path())
+                .arg(job_trace.trace())
                 .arg("--memory_file")
-                .arg(memory.path())
+                .arg(job_trace.memory())
                 .arg("--proof_mode")
                 .arg("--print_output")
-                .stdout(Stdio::null())
-                .spawn()?;
-
-            debug!("task {} spawned", hex::encode(&job_key));
+                .stdout(Stdio::null());
 
-            loop {
-                select! {
-                    output = task.wait() => {
-                        debug!("{:?}", output);
-                        if !output?.success() {
-                            return Err(RunnerControllerError::TaskTerminated);
-                        }
-                        let output = task.wait_with_output().await?;
-                        debug!("{:?}", output);
-                        break;
-                    }
-                    Some(()) = terminate_rx.recv() => {
-                        task.start_kill()?;
-                    }
-                }
-            }
-            Ok(JobTrace::new(job_key, air_public_input, air_private_input, memory, trace))
+            debug!("spawning task {}", hex::encode(&job_trace.job_key));
+            supervise_child(command, &self.limits, timeout_tx, &mut terminate_rx).await?;
+            Ok(job_trace)
         });
 
         Ok(Process::new(future, terminate_tx))
@@ -1,4 +1,4 @@
-use zetina_common::job::{Job, JobData};
+use zetina_common::job::{Job, JobData, DEFAULT_JOB_TTL};
 
 use starknet::signers::SigningKey;
 use std::{env, fs, path::PathBuf};
@@ -17,9 +17,10 @@ pub fn fixture() -> TestFixture {
 
     TestFixture {
         job: Job::try_from_job_data(
-            JobData::new(fs::read(cairo_pie_path).unwrap()),
+            JobData::with_ttl(fs::read(cairo_pie_path).unwrap(), DEFAULT_JOB_TTL),
             &SigningKey::from_random(),
-        ),
+        )
+        .unwrap(),
         program_path,
     }
 }
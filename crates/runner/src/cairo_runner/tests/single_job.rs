@@ -1,8 +1,11 @@
 use crate::{
     cairo_runner::{tests::models::fixture, CairoRunner},
+    errors::RunnerControllerError,
     traits::RunnerController,
 };
 use starknet::signers::SigningKey;
+use std::time::Duration;
+use zetina_common::process::ProcessLimits;
 
 #[tokio::test]
 async fn run_single_job() {
@@ -19,3 +22,15 @@ async fn abort_single_jobs() {
     job.abort().await.unwrap();
     job.await.unwrap_err();
 }
+
+#[tokio::test]
+async fn timed_out_single_job() {
+    let fixture = fixture();
+    let limits = ProcessLimits { timeout: Some(Duration::from_millis(1)), ..Default::default() };
+    let runner = CairoRunner::new(fixture.program_path, SigningKey::from_random().verifying_key())
+        .with_limits(limits);
+    let err = runner.run(fixture.job).unwrap().await.unwrap_err();
+    assert!(
+        matches!(err, RunnerControllerError::Timeout(timeout, _) if timeout == Duration::from_millis(1))
+    );
+}
@@ -1,19 +1,48 @@
+use std::{io, time::Duration};
 use thiserror::Error;
+use zetina_common::{
+    process::{ChildError, ChildFailure},
+    workspace::WorkspaceError,
+};
 
 #[derive(Error, Debug)]
 pub enum RunnerControllerError {
     #[error("task not found")]
     TaskNotFound,
 
-    #[error("task not found")]
+    #[error("task aborted")]
     TaskTerminated,
 
-    #[error("io")]
-    Io(#[from] std::io::Error),
+    #[error("run timed out after {0:?}: {1}")]
+    Timeout(Duration, ChildFailure),
+
+    #[error("run exceeded its {0}: {1}")]
+    ResourceExceeded(String, ChildFailure),
+
+    #[error("run failed: {0}")]
+    Failed(ChildFailure),
+
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
 
-    #[error("serde")]
+    #[error("workspace error: {0}")]
+    Workspace(#[from] WorkspaceError),
+
+    #[error("serde error: {0}")]
     Serde(#[from] serde_json::Error),
 
-    #[error("proof parsing error")]
+    #[error("proof parsing error: {0}")]
     ProofParseError(String),
 }
+
+impl From<ChildError> for RunnerControllerError {
+    fn from(err: ChildError) -> Self {
+        match err {
+            ChildError::Io(err) => Self::Io(err),
+            ChildError::Aborted => Self::TaskTerminated,
+            ChildError::Timeout(timeout, failure) => Self::Timeout(timeout, failure),
+            ChildError::ResourceExceeded(limit, failure) => Self::ResourceExceeded(limit, failure),
+            ChildError::Failed(failure) => Self::Failed(failure),
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use futures::{stream, StreamExt};
+use libp2p::{identity::Keypair, kad, pnet::PreSharedKey, Multiaddr, PeerId};
+use starknet::signers::SigningKey;
+use std::{
+    collections::HashSet,
+    env, fs,
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::{interval, sleep, timeout, Instant},
+};
+use zetina_common::{
+    capability::ExecutorCapabilities,
+    hash,
+    job::{Job, JobData},
+    job_trace::JobTrace,
+    job_witness::JobWitness,
+    layout::Layout,
+    process::Process,
+    workspace::WorkspaceConfig,
+};
+use zetina_delegator::delegator::{Delegator, DelegatorEvent};
+use zetina_executor::{
+    admin::{AdminClient, ExecutorSettings},
+    bidding::BidPolicy,
+    executor::Executor,
+    validation::{JobValidator, ValidationConfig},
+};
+use zetina_peer::{
+    access::AccessConfig,
+    blob::{BlobClient, BlobMessage, BlobStoreConfig},
+    connection::ConnectionConfig,
+    event::{PeerEvent, PeerEvents},
+    query::{KademliaClient, QueryConfig},
+    sim::{SimNetwork, SimNode},
+    store::StoreConfig,
+    wire::{self, WireMessage},
+    GossipsubMessage, KademliaMessage, NatConfig, NetworkingMessage, SwarmRunner, Topic,
+};
+use zetina_prover::{errors::ProverControllerError, traits::ProverController};
+use zetina_runner::{errors::RunnerControllerError, traits::RunnerController};
+
+/*
+    Test Network
+    Runs delegators and executors in one process, each with its own swarm listening on loopback TCP.
+    Executors run jobs with a mock runner and prover, so scenarios exercise the market protocol
+    without the bootloader or the Stone prover.
+    Every network gets a fresh pre-shared key, networks of tests running in parallel cannot see each other
+    even when they discover each other over mDNS.
+    Nodes are split into groups, each group bootstraps from its first node and denies the peers of other groups,
+    which partitions the network. Every group has a probe node, the network is handed over once each probe
+    heard from the executors of its group. The probe of the first group observes the gossip of its group
+    and can publish arbitrary messages.
+    The same nodes can run on a simulated network instead, where faults are injected and time is virtual.
+*/
+
+// Longest wait for the gossip of a fresh network to flow.
+pub const SETTLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long a draining executor waits for its running jobs.
+pub const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub delegators: usize,
+    pub executors: usize,
+}
+
+pub struct TestNetwork {
+    pub delegators: Vec<DelegatorNode>,
+    pub executors: Vec<ExecutorNode>,
+    pub probe: ProbeNode,
+    // Probes of the other groups, only used to tell when their gossip flows.
+    _probes: Vec<ProbeNode>,
+}
+
+impl TestNetwork {
+    pub async fn start(delegators: usize, executors: usize, run_delay: Duration) -> Self {
+        Self::partitioned(&[Group { delegators, executors }], run_delay).await
+    }
+
+    pub async fn partitioned(groups: &[Group], run_delay: Duration) -> Self {
+        let psk = PreSharedKey::new(rand::random());
+        let plans: Vec<Vec<NodePlan>> = groups
+            .iter()
+            .map(|group| {
+                (0..group.delegators + group.executors + 1).map(|_| NodePlan::new()).collect()
+            })
+            .collect();
+
+        let (mut delegators, mut executors, mut probes) = (vec![], vec![], vec![]);
+        for (index, (group, group_plans)) in groups.iter().zip(plans.iter()).enumerate() {
+            let denied_peers: Vec<PeerId> = plans
+                .iter()
+                .enumerate()
+                .filter(|(other, _)| *other != index)
+                .flat_map(|(_, other_plans)| other_plans.iter().map(|plan| plan.peer_id))
+                .collect();
+            let access_config =
+                AccessConfig { psk: Some(psk), allowed_peers: vec![], denied_peers };
+
+            // The group bootstraps from its first node, the OS picks its port.
+            let mut bootstrap = group_plans[0].runner(vec![], access_config.to_owned());
+            let bootstrap_addr = bootstrap.listen_addr().await;
+            let runners = std::iter::once(bootstrap).chain(group_plans[1..].iter().map(|plan| {
+                plan.runner(vec![bootstrap_addr.to_owned()], access_config.to_owned())
+            }));
+
+            let mut group_executors = vec![];
+            for (position, (plan, runner)) in group_plans.iter().zip(runners).enumerate() {
+                let swarm = SwarmHandles::from(runner);
+                if position < group.delegators {
+                    delegators.push(DelegatorNode::start(swarm));
+                } else if position < group.delegators + group.executors {
+                    executors.push(ExecutorNode::start(plan.peer_id, swarm, run_delay));
+                    group_executors.push(plan.peer_id);
+                } else {
+                    let mut probe = ProbeNode::start(swarm);
+                    probe.settle(&group_executors).await;
+                    probes.push(probe);
+                }
+            }
+        }
+
+        let probe = probes.remove(0);
+        Self { delegators, executors, probe, _probes: probes }
+    }
+
+    // Same market on a simulated network, nodes are wired up right away so there is nothing to wait for.
+    pub fn simulated(
+        sim: &SimNetwork,
+        delegators: usize,
+        executors: usize,
+        run_delay: Duration,
+    ) -> Self {
+        let join = || {
+            let peer_id = PeerId::random();
+            (peer_id, SwarmHandles::from(sim.join(peer_id)))
+        };
+        Self {
+            delegators: (0..delegators).map(|_| DelegatorNode::start(join().1)).collect(),
+            executors: (0..executors)
+                .map(|_| {
+                    let (peer_id, swarm) = join();
+                    ExecutorNode::start(peer_id, swarm, run_delay)
+                })
+                .collect(),
+            probe: ProbeNode::start(join().1),
+            _probes: vec![],
+        }
+    }
+
+    // Jobs run by every executor of the network.
+    pub fn runs(&self) -> usize {
+        self.executors.iter().map(ExecutorNode::runs).sum()
+    }
+}
+
+// The fibonacci PIE shipped with the tests, due within the given time.
+pub fn job_data(ttl: Duration) -> JobData {
+    let path =
+        PathBuf::from(env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR env not present"))
+            .join("cairo/fibonacci_pie.zip");
+    JobData::with_ttl(fs::read(path).unwrap(), ttl)
+}
+
+// Quick retries, test networks are small and fast.
+fn query_config() -> QueryConfig {
+    QueryConfig { retry_backoff: Duration::from_millis(200), ..Default::default() }
+}
+
+// Identities are known up front so groups can deny each other before any swarm runs.
+struct NodePlan {
+    keypair: Keypair,
+    peer_id: PeerId,
+}
+
+impl NodePlan {
+    fn new() -> Self {
+        let keypair = Keypair::generate_ecdsa();
+        let peer_id = keypair.public().to_peer_id();
+        Self { keypair, peer_id }
+    }
+
+    fn runner(&self, dial: Vec<Multiaddr>, access_config: AccessConfig) -> SwarmRunner {
+        SwarmRunner::new(
+            "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            dial,
+            self.keypair.to_owned(),
+            None,
+            NatConfig::default(),
+            StoreConfig::default(),
+            BlobStoreConfig::default(),
+            access_config,
+            ConnectionConfig::default(),
+            query_config(),
+        )
+        .unwrap()
+    }
+}
+
+struct SwarmHandles {
+    events: PeerEvents,
+    gossipsub_tx: mpsc::Sender<GossipsubMessage>,
+    kademlia: KademliaClient,
+    blobs: BlobClient,
+}
+
+impl From<SwarmRunner> for SwarmHandles {
+    fn from(swarm_runner: SwarmRunner) -> Self {
+        let (gossipsub_tx, gossipsub_rx) = mpsc::channel::<GossipsubMessage>(100);
+        let (kademlia_tx, kademlia_rx) = mpsc::channel::<KademliaMessage>(100);
+        let (blob_tx, blob_rx) = mpsc::channel::<BlobMessage>(100);
+        Self {
+            events: swarm_runner.run(gossipsub_rx, kademlia_rx, blob_rx),
+            gossipsub_tx,
+            kademlia: KademliaClient::new(kademlia_tx, query_config()),
+            blobs: BlobClient::new(blob_tx),
+        }
+    }
+}
+
+impl From<SimNode> for SwarmHandles {
+    fn from(node: SimNode) -> Self {
+        Self {
+            events: node.events,
+            gossipsub_tx: node.gossipsub_tx,
+            kademlia: KademliaClient::new(node.kademlia_tx, query_config()),
+            blobs: BlobClient::new(node.blob_tx),
+        }
+    }
+}
+
+pub struct DelegatorNode {
+    delegate_tx: mpsc::Sender<JobData>,
+    events_rx: broadcast::Receiver<(kad::RecordKey, DelegatorEvent)>,
+    _delegator: Delegator,
+}
+
+impl DelegatorNode {
+    fn start(swarm: SwarmHandles) -> Self {
+        let (delegate_tx, delegate_rx) = mpsc::channel::<JobData>(100);
+        let (events_tx, events_rx) = broadcast::channel::<(kad::RecordKey, DelegatorEvent)>(100);
+        let delegator = Delegator::new(
+            swarm.events,
+            swarm.gossipsub_tx,
+            swarm.kademlia,
+            swarm.blobs,
+            delegate_rx,
+            events_tx,
+            SigningKey::from_random(),
+        );
+        Self { delegate_tx, events_rx, _delegator: delegator }
+    }
+
+    // Submits a job and returns the key the delegator publishes it under.
+    pub async fn delegate(&self, job_data: JobData) -> kad::RecordKey {
+        // A job hashes like its data, the signature does not change the key.
+        let job_key = kad::RecordKey::new(&hash!(job_data).to_be_bytes());
+        self.delegate_tx.send(job_data).await.unwrap();
+        job_key
+    }
+
+    // Collects the events of a job until one matches, panics when none does in time.
+    pub async fn events_until(
+        &mut self,
+        job_key: &kad::RecordKey,
+        within: Duration,
+        done: impl Fn(&DelegatorEvent) -> bool,
+    ) -> Vec<DelegatorEvent> {
+        let deadline = Instant::now() + within;
+        let mut events = vec![];
+        loop {
+            let event = match timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                self.events_rx.recv(),
+            )
+            .await
+            {
+                Ok(Ok((key, event))) if &key == job_key => event,
+                Ok(Ok(_)) | Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => panic!("delegator stopped"),
+                Err(_) => {
+                    panic!("no matching event for job {} in {events:?}", hex::encode(job_key))
+                }
+            };
+            let matched = done(&event);
+            events.push(event);
+            if matched {
+                return events;
+            }
+        }
+    }
+
+    // Collects the events of every job for a while.
+    pub async fn all_events_for(
+        &mut self,
+        within: Duration,
+    ) -> Vec<(kad::RecordKey, DelegatorEvent)> {
+        let deadline = Instant::now() + within;
+        let mut events = vec![];
+        while let Ok(result) =
+            timeout(deadline.saturating_duration_since(Instant::now()), self.events_rx.recv()).await
+        {
+            match result {
+                Ok(event) => events.push(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        events
+    }
+
+    // Collects the events of a job for a while.
+    pub async fn events_for(
+        &mut self,
+        job_key: &kad::RecordKey,
+        within: Duration,
+    ) -> Vec<DelegatorEvent> {
+        let deadline = Instant::now() + within;
+        let mut events = vec![];
+        while let Ok(result) =
+            timeout(deadline.saturating_duration_since(Instant::now()), self.events_rx.recv()).await
+        {
+            match result {
+                Ok((key, event)) if &key == job_key => events.push(event),
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        events
+    }
+}
+
+pub struct ExecutorNode {
+    pub peer_id: PeerId,
+    runs: Arc<AtomicUsize>,
+    pub admin: AdminClient,
+    executor: Option<Executor>,
+    kill_tx: Option<oneshot::Sender<()>>,
+}
+
+impl ExecutorNode {
+    fn start(peer_id: PeerId, swarm: SwarmHandles, run_delay: Duration) -> Self {
+        let validation_config =
+            ValidationConfig { proving_overhead: Duration::from_secs(1), ..Default::default() };
+        let capabilities = ExecutorCapabilities {
+            identity: peer_id,
+            layouts: vec![Layout::Starknet],
+            max_steps: validation_config.max_steps,
+            max_memory_cells: validation_config.max_memory_cells,
+            prover_backend: "mock".to_string(),
+            prover_version: "0".to_string(),
+            bootloader_program_hash: Default::default(),
+            queue_depth: 0,
+        };
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (admin_tx, admin_rx) = mpsc::channel(10);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        let executor = Executor::new(
+            peer_id,
+            killable(swarm.events, kill_rx),
+            swarm.gossipsub_tx,
+            swarm.kademlia,
+            swarm.blobs,
+            MockRunner { delay: run_delay, runs: runs.to_owned() },
+            MockProver,
+            None,
+            None,
+            BidPolicy::default(),
+            Arc::new(JobValidator::new(validation_config)),
+            capabilities,
+            ExecutorSettings::default(),
+            admin_rx,
+            DRAIN_GRACE_PERIOD,
+        );
+        Self {
+            peer_id,
+            runs,
+            admin: AdminClient::new(admin_tx),
+            executor: Some(executor),
+            kill_tx: Some(kill_tx),
+        }
+    }
+
+    // Drains the executor and waits until it stopped.
+    pub async fn drain(&mut self) {
+        self.admin.drain().await.unwrap();
+        if let Some(executor) = self.executor.take() {
+            executor.join().await;
+        }
+    }
+
+    // Cuts the executor off the network in the middle of whatever it is doing, its swarm shuts down
+    // and its peers see it go like a killed process.
+    pub fn crash(&mut self) {
+        if let Some(kill_tx) = self.kill_tx.take() {
+            let _ = kill_tx.send(());
+        }
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::SeqCst)
+    }
+}
+
+// Peer that only watches the gossip of its group and publishes whatever a scenario asks it to.
+pub struct ProbeNode {
+    gossipsub_tx: mpsc::Sender<GossipsubMessage>,
+    events_rx: mpsc::UnboundedReceiver<PeerEvent>,
+}
+
+impl ProbeNode {
+    fn start(mut swarm: SwarmHandles) -> Self {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        // The swarm only makes progress while its events are polled, even if nobody reads them.
+        tokio::spawn(async move {
+            while let Some(event) = swarm.events.next().await {
+                let _ = events_tx.send(event);
+            }
+        });
+        Self { gossipsub_tx: swarm.gossipsub_tx, events_rx }
+    }
+
+    // Asks the executors for their capabilities until all of them answered, by then gossip flows
+    // between the probe and each of them.
+    async fn settle(&mut self, executors: &[PeerId]) {
+        let mut pending: HashSet<PeerId> = executors.iter().copied().collect();
+        let mut request_interval = interval(Duration::from_secs(1));
+        timeout(SETTLE_TIMEOUT, async {
+            while !pending.is_empty() {
+                tokio::select! {
+                    _ = request_interval.tick() => {
+                        for identity in pending.iter() {
+                            let request = NetworkingMessage::CapabilitiesRequest(*identity);
+                            self.publish(Topic::Networking, &request).await;
+                        }
+                    }
+                    event = self.events_rx.recv() => {
+                        let event = event.expect("probe stopped");
+                        if let PeerEvent::Capabilities { capabilities, .. } = event {
+                            pending.remove(&capabilities.identity);
+                        }
+                    }
+                }
+            }
+        })
+        .await
+        .expect("executors did not advertise their capabilities");
+    }
+
+    pub async fn publish<T: WireMessage>(&self, topic: Topic, message: &T) {
+        let data = wire::encode(message).unwrap();
+        self.gossipsub_tx.send(GossipsubMessage { topic: topic.into(), data }).await.unwrap();
+    }
+
+    // Waits for the first event the filter accepts, panics when none arrives in time.
+    pub async fn wait_for<T>(
+        &mut self,
+        within: Duration,
+        filter: impl Fn(PeerEvent) -> Option<T>,
+    ) -> T {
+        timeout(within, async {
+            loop {
+                if let Some(found) = filter(self.events_rx.recv().await.expect("probe stopped")) {
+                    return found;
+                }
+            }
+        })
+        .await
+        .expect("probe did not observe the expected event")
+    }
+}
+
+// Ends the events when the kill switch fires or is dropped, the swarm behind them is dropped with its connections.
+fn killable(events: PeerEvents, kill_rx: oneshot::Receiver<()>) -> PeerEvents {
+    Box::pin(stream::unfold((events, kill_rx), |(mut events, mut kill_rx)| async move {
+        tokio::select! {
+            event = events.next() => event.map(|event| (event, (events, kill_rx))),
+            _ = &mut kill_rx => None,
+        }
+    }))
+}
+
+// Stands in for the Cairo runner, returns an empty trace after a delay and counts the jobs it ran.
+struct MockRunner {
+    delay: Duration,
+    runs: Arc<AtomicUsize>,
+}
+
+impl RunnerController for MockRunner {
+    fn run(
+        &self,
+        job: Job,
+    ) -> Result<Process<Result<JobTrace, RunnerControllerError>>, RunnerControllerError> {
+        let (terminate_tx, mut terminate_rx) = mpsc::channel::<()>(10);
+        self.runs.fetch_add(1, Ordering::SeqCst);
+        let delay = self.delay;
+        let future: Pin<Box<dyn Future<Output = Result<JobTrace, RunnerControllerError>> + Send>> =
+            Box::pin(async move {
+                let job_key = kad::RecordKey::new(&hash!(job).to_be_bytes());
+                tokio::select! {
+                    _ = sleep(delay) => {
+                        let workspace = WorkspaceConfig::default().create("mock")?;
+                        Ok(JobTrace::new(job_key, workspace))
+                    }
+                    _ = terminate_rx.recv() => Err(RunnerControllerError::TaskTerminated),
+                }
+            });
+        Ok(Process::new(future, terminate_tx))
+    }
+}
+
+// Stands in for the Stone prover, the proof of a job is its key so every job gets a distinct proof.
+struct MockProver;
+
+impl ProverController for MockProver {
+    fn run(
+        &self,
+        job_trace: JobTrace,
+    ) -> Result<Process<Result<JobWitness, ProverControllerError>>, ProverControllerError> {
+        let (terminate_tx, _) = mpsc::channel::<()>(10);
+        let future: Pin<
+            Box<dyn Future<Output = Result<JobWitness, ProverControllerError>> + Send>,
+        > = Box::pin(async move {
+            Ok(JobWitness {
+                proof: job_trace.job_key.to_vec(),
+                job_key: job_trace.job_key.to_owned(),
+            })
+        });
+        Ok(Process::new(future, terminate_tx))
+    }
+}
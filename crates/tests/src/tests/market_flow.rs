@@ -0,0 +1,235 @@
+use super::harness::{job_data, Group, TestNetwork};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use zetina_delegator::delegator::DelegatorEvent;
+use zetina_executor::job_state::ABORT_REASON;
+use zetina_peer::{event::PeerEvent, DelegationMessage, Topic};
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long the last gossip of a stopped executor takes to reach the delegator at most.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn finished(event: &DelegatorEvent) -> bool {
+    matches!(event, DelegatorEvent::Finished(_))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn single_job() {
+    let mut network = TestNetwork::start(1, 1, Duration::ZERO).await;
+    let executor = network.executors[0].peer_id;
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+    let events = network.delegators[0].events_until(&job_key, TIMEOUT, finished).await;
+
+    assert!(matches!(events.first(), Some(DelegatorEvent::Propagated)));
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DelegatorEvent::Delegated(peer) if *peer == executor)));
+    assert!(
+        matches!(events.last(), Some(DelegatorEvent::Finished(proof)) if *proof == job_key.to_vec())
+    );
+    assert_eq!(network.runs(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn competing_bids() {
+    let mut network = TestNetwork::start(1, 3, Duration::ZERO).await;
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+    let events = network.delegators[0].events_until(&job_key, TIMEOUT, finished).await;
+
+    let bidders: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DelegatorEvent::BidReceived(peer) => Some(*peer),
+            _ => None,
+        })
+        .collect();
+    assert!(bidders.len() > 1, "expected competing bids, got {bidders:?}");
+    let delegated: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DelegatorEvent::Delegated(peer) => Some(*peer),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(delegated.len(), 1);
+    assert!(bidders.contains(&delegated[0]));
+    assert_eq!(network.runs(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn executor_crash_mid_job() {
+    let mut network = TestNetwork::start(1, 2, Duration::from_secs(10)).await;
+
+    // The job expires while the executor it was delegated to is down.
+    let ttl = Duration::from_secs(20);
+    let job_key = network.delegators[0].delegate(job_data(ttl)).await;
+    let events = network.delegators[0]
+        .events_until(&job_key, TIMEOUT, |event| matches!(event, DelegatorEvent::Delegated(_)))
+        .await;
+    let Some(DelegatorEvent::Delegated(crashed)) = events.last() else { unreachable!() };
+    let crashed =
+        network.executors.iter().position(|executor| executor.peer_id == *crashed).unwrap();
+    network.executors[crashed].crash();
+
+    let events = network.delegators[0]
+        .events_until(&job_key, TIMEOUT, |event| matches!(event, DelegatorEvent::Expired))
+        .await;
+    assert!(!events.iter().any(finished));
+
+    // The rest of the network keeps serving jobs.
+    let survivor = network.executors[1 - crashed].peer_id;
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+    let events = network.delegators[0].events_until(&job_key, TIMEOUT, finished).await;
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DelegatorEvent::Delegated(peer) if *peer == survivor)));
+    assert_eq!(network.executors[1 - crashed].runs(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn network_partition() {
+    let groups = [Group { delegators: 1, executors: 1 }, Group { delegators: 1, executors: 1 }];
+    let mut network = TestNetwork::partitioned(&groups, Duration::ZERO).await;
+
+    let job_keys = [
+        network.delegators[0].delegate(job_data(TIMEOUT)).await,
+        network.delegators[1].delegate(job_data(TIMEOUT + Duration::from_secs(1))).await,
+    ];
+    for (index, job_key) in job_keys.iter().enumerate() {
+        // Each job can only reach the executor on the side of its delegator.
+        let executor = network.executors[index].peer_id;
+        let events = network.delegators[index].events_until(job_key, TIMEOUT, finished).await;
+        let delegated: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                DelegatorEvent::BidReceived(peer) | DelegatorEvent::Delegated(peer) => Some(*peer),
+                _ => None,
+            })
+            .collect();
+        assert!(
+            delegated.iter().all(|peer| *peer == executor),
+            "{delegated:?} crossed the partition"
+        );
+        assert_eq!(network.executors[index].runs(), 1);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duplicate_messages() {
+    let mut network = TestNetwork::start(1, 1, Duration::from_secs(2)).await;
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+
+    // Replay the delegation and the completion of the job as if they were delivered twice.
+    let bid = network
+        .probe
+        .wait_for(TIMEOUT, |event| match event {
+            PeerEvent::Delegated { bid, .. } if bid.job_key == job_key => Some(bid),
+            _ => None,
+        })
+        .await;
+    network.probe.publish(Topic::Delegation, &DelegationMessage::Delegate(bid.to_owned())).await;
+    network.probe.publish(Topic::Delegation, &DelegationMessage::Delegate(bid)).await;
+
+    let proof_key = network
+        .probe
+        .wait_for(TIMEOUT, |event| match event {
+            PeerEvent::JobFinished { job_key: key, proof_key, .. } if key == job_key => {
+                Some(proof_key)
+            }
+            _ => None,
+        })
+        .await;
+    let message = DelegationMessage::Finished(proof_key, job_key.to_owned());
+    network.probe.publish(Topic::Delegation, &message).await;
+    network.probe.publish(Topic::Delegation, &message).await;
+
+    network.delegators[0].events_until(&job_key, TIMEOUT, finished).await;
+    let events = network.delegators[0].events_for(&job_key, Duration::from_secs(15)).await;
+    assert!(!events.iter().any(finished), "job finished twice: {events:?}");
+    assert_eq!(network.runs(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn draining_executor_finishes_running_job() {
+    let mut network = TestNetwork::start(1, 1, Duration::from_secs(2)).await;
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+    network.delegators[0]
+        .events_until(&job_key, TIMEOUT, |event| matches!(event, DelegatorEvent::Delegated(_)))
+        .await;
+
+    // The job runs for less than the grace period, so it is finished before the executor exits.
+    timeout(TIMEOUT, network.executors[0].drain()).await.expect("executor did not drain");
+    // The proof is announced before the executor stops, it reaches the delegator afterwards.
+    let events = network.delegators[0].events_until(&job_key, FLUSH_TIMEOUT, finished).await;
+    assert!(!events.iter().any(|event| matches!(event, DelegatorEvent::Returned(..))));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn draining_executor_hands_back_job() {
+    let mut network = TestNetwork::start(1, 2, Duration::from_secs(20)).await;
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT * 2)).await;
+    let events = network.delegators[0]
+        .events_until(&job_key, TIMEOUT, |event| matches!(event, DelegatorEvent::Delegated(_)))
+        .await;
+    let Some(DelegatorEvent::Delegated(drained)) = events.last() else { unreachable!() };
+    let drained =
+        network.executors.iter().position(|executor| executor.peer_id == *drained).unwrap();
+    let (drained_peer, survivor) =
+        (network.executors[drained].peer_id, network.executors[1 - drained].peer_id);
+    network.executors[drained].drain().await;
+
+    // The job outlasts the grace period, it is handed back before the executor stops.
+    let returned = |event: &DelegatorEvent| match event {
+        DelegatorEvent::Returned(peer, _) => *peer == drained_peer,
+        _ => false,
+    };
+    network.delegators[0].events_until(&job_key, FLUSH_TIMEOUT, returned).await;
+
+    // Then it is delegated to the other executor.
+    let events = network.delegators[0].events_until(&job_key, TIMEOUT * 2, finished).await;
+    assert!(events
+        .iter()
+        .any(|event| matches!(event, DelegatorEvent::Delegated(peer) if *peer == survivor)));
+    assert_eq!(network.executors[1 - drained].runs(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn operator_aborts_job() {
+    let mut network = TestNetwork::start(1, 1, Duration::from_secs(20)).await;
+    let admin = network.executors[0].admin.to_owned();
+
+    let job_key = network.delegators[0].delegate(job_data(TIMEOUT)).await;
+    let jobs = timeout(TIMEOUT, async {
+        loop {
+            let jobs = admin.jobs().await.unwrap();
+            if !jobs.is_empty() {
+                break jobs;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(jobs[0].job_key, hex::encode(&job_key));
+    assert!(jobs[0].step > 0 && jobs[0].step <= jobs[0].steps);
+
+    assert!(admin.abort(job_key.to_owned()).await.unwrap());
+    let events = network.delegators[0]
+        .events_until(&job_key, TIMEOUT, |event| matches!(event, DelegatorEvent::Rejected(..)))
+        .await;
+    assert!(
+        matches!(events.last(), Some(DelegatorEvent::Rejected(_, reason)) if reason == ABORT_REASON)
+    );
+    assert!(admin.jobs().await.unwrap().is_empty());
+    assert!(!admin.abort(job_key.to_owned()).await.unwrap());
+
+    let errors = admin.errors().await.unwrap();
+    assert_eq!(errors[0].job_key, Some(hex::encode(&job_key)));
+    assert_eq!(errors[0].message, ABORT_REASON);
+}
@@ -1,4 +1,10 @@
 #[cfg(all(test, feature = "full_test"))]
 mod compiler_runner_flow;
+#[cfg(test)]
+mod harness;
+#[cfg(test)]
+mod market_flow;
 #[cfg(all(test, feature = "full_test"))]
 mod runner_prover_flow;
+#[cfg(test)]
+mod sim_flow;
@@ -0,0 +1,82 @@
+use super::harness::{job_data, TestNetwork};
+use libp2p::kad;
+use proptest::prelude::*;
+use std::time::Duration;
+use zetina_delegator::delegator::DelegatorEvent;
+use zetina_peer::sim::{FaultConfig, SimNetwork};
+
+const TTL: Duration = Duration::from_secs(120);
+const JOBS: u64 = 3;
+
+// Kinds of the events that end a job.
+const SETTLED: [&str; 4] = ["Finished", "Failed", "Rejected", "Expired"];
+
+// Name of the event variant, payloads carry random peer ids and keys that differ between runs.
+fn kind(event: &DelegatorEvent) -> String {
+    format!("{event:?}").split('(').next().unwrap().to_string()
+}
+
+// Runs a market on a simulated network until every deadline passed, returns the event kinds of each job.
+fn simulate(seed: u64, faults: FaultConfig) -> Vec<Vec<String>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let sim = SimNetwork::new(seed, faults);
+        let mut network = TestNetwork::simulated(&sim, 1, 2, Duration::from_secs(5));
+        let mut job_keys: Vec<kad::RecordKey> = vec![];
+        for index in 0..JOBS {
+            let job_data = job_data(TTL + Duration::from_secs(index));
+            job_keys.push(network.delegators[0].delegate(job_data).await);
+        }
+
+        let events = network.delegators[0].all_events_for(TTL + Duration::from_secs(60)).await;
+        job_keys
+            .iter()
+            .map(|job_key| {
+                events
+                    .iter()
+                    .filter(|(key, _)| key == job_key)
+                    .map(|(_, event)| kind(event))
+                    .collect()
+            })
+            .collect()
+    })
+}
+
+fn faults(drop_rate: f64, duplicate_rate: f64, max_delay: u64) -> FaultConfig {
+    FaultConfig {
+        drop_rate,
+        duplicate_rate,
+        min_delay: Duration::ZERO,
+        max_delay: Duration::from_millis(max_delay),
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    // Every job the delegator accepted ends exactly once, finished or failed, whatever the network does.
+    #[test]
+    fn every_job_settles_once(
+        seed in any::<u64>(),
+        drop_rate in 0.0..0.3,
+        duplicate_rate in 0.0..0.3,
+        max_delay in 0..3000u64,
+    ) {
+        let jobs = simulate(seed, faults(drop_rate, duplicate_rate, max_delay));
+        for (index, events) in jobs.iter().enumerate() {
+            let settled = events.iter().filter(|kind| SETTLED.contains(&kind.as_str())).count();
+            prop_assert_eq!(settled, 1, "job {} settled {} times: {:?}", index, settled, events);
+        }
+    }
+}
+
+#[test]
+fn reliable_network_finishes_every_job() {
+    for events in simulate(0, FaultConfig::default()) {
+        assert_eq!(events.last().map(String::as_str), Some("Finished"), "{events:?}");
+    }
+}
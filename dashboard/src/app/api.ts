@@ -31,7 +31,9 @@ export const JobEventsResponse = z.object({
     .literal("Finished")
     .or(z.literal("Delegated"))
     .or(z.literal("BidReceived"))
-    .or(z.literal("Propagated")),
+    .or(z.literal("Propagated"))
+    .or(z.literal("Rejected"))
+    .or(z.literal("Expired")),
   data: z.any(),
 });
 export type JobEventsResponse = z.infer<typeof JobEventsResponse>;
@@ -210,6 +210,16 @@ export default function Home() {
                 addLog(`Job ${data.job_key} delegated to peer ${peer_id}`);
                 setActiveStep(4);
               }
+              if (job_event.type == "Rejected") {
+                addLog(`Job ${data.job_key} rejected by ${job_event.data}`);
+                setIsProcessing(null);
+                subscriber?.close();
+              }
+              if (job_event.type == "Expired") {
+                addLog(`Job ${data.job_key} expired without a proof`);
+                setIsProcessing(null);
+                subscriber?.close();
+              }
               if (job_event.type == "Finished") {
                 let proof = Proof.parse(job_event.data);
                 addLog(`Job ${data.job_key} proof received`);